@@ -0,0 +1,110 @@
+use std::{error::Error, path::Path, sync::mpsc};
+
+use mlua::IntoLua;
+use package::Definitions;
+
+use crate::{
+    drawable::LuaDrawable,
+    node::{LuaNode, Node},
+    scene::LuaScene,
+    setup, Game,
+};
+
+/// How the file is written, it goes at its top.
+const TOOL: &str = "client --definitions";
+
+const PACKAGE_INFO: &str = "{ name: string, version: string, state: string, updates: number, \
+    overruns: number, skipped: number, worst: number, error: string? }";
+
+/// Everything a client package sees, next to what every package gets.
+pub fn definitions() -> Definitions {
+    let mut defs = Definitions::new();
+
+    defs.class("Drawable");
+
+    defs.class("Node")
+        .method("setDrawable", "drawable: Drawable", "()")
+        .method("add", "child: Node", "()")
+        .method("translate", "offset: { number }", "()")
+        .method("rotateX", "angle: number", "()")
+        .method("rotateY", "angle: number", "()")
+        .method("rotateZ", "angle: number", "()")
+        .method("scale", "factors: { number }", "()");
+
+    defs.class("Scene")
+        .field("root", "Node")
+        .method("load", "file: string", "Drawable");
+
+    defs.class("Game")
+        .field("isServer", "boolean")
+        .field("world", "Node")
+        .field("after", "(seconds: number, f: () -> ()) -> number")
+        .field("every", "(seconds: number, f: () -> ()) -> number")
+        .field("cancel", "(id: number) -> ()")
+        .method("setScene", "scene: Scene", "()")
+        .method("setTargetFPS", "fps: number", "()")
+        .method("connect", "host: string, port: number", "()")
+        .method(
+            "setInterpolation",
+            "delay: number, maxExtrapolation: number?",
+            "()",
+        )
+        .method("packages", "", &format!("{{ {} }}", PACKAGE_INFO))
+        .method("restartPackage", "name: string", "boolean")
+        .method("stopPackage", "name: string", "boolean")
+        .method("send", "name: string, data: any", "()");
+
+    defs.value("Game", "Game");
+    defs.library("Scene")
+        .function("new", "name: string", "Scene");
+    defs.library("Node").function("new", "", "Node");
+
+    defs.callback("OnMessage", "(data: any) -> ()");
+
+    defs
+}
+
+pub fn write(file: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(file, definitions().to_luau(TOOL))?;
+    println!("EINKRAD: wrote definitions to {}", file.display());
+    Ok(())
+}
+
+/// Whether `file` is up to date and the definitions still match the
+/// globals packages really get. Tells what differs.
+pub fn check(file: &Path) -> Result<bool, Box<dyn Error>> {
+    let defs = definitions();
+    let lua = package::api(&|c| {
+        let game = Game {
+            tx: mpsc::channel().0,
+            is_server: false,
+            world: Node::new(),
+        };
+        setup(c, game, &Default::default())
+    })?;
+    let root = LuaNode { inner: Node::new() };
+    let samples = vec![
+        ("Node", root.clone().into_lua(&lua)?),
+        ("Scene", LuaScene { id: 0, root }.into_lua(&lua)?),
+    ];
+    let mut drift = defs.check(&lua, samples)?;
+    drift.extend(defs.check_class::<LuaDrawable>("Drawable"));
+    drift.extend(defs.check_class::<LuaNode>("Node"));
+    drift.extend(defs.check_class::<LuaScene>("Scene"));
+    drift.extend(defs.check_class::<Game>("Game"));
+
+    let written = std::fs::read_to_string(file).unwrap_or_default();
+    if written != defs.to_luau(TOOL) {
+        drift.push(format!(
+            "{} is out of date, run {} {}",
+            file.display(),
+            TOOL,
+            file.display()
+        ));
+    }
+
+    for d in drift.iter() {
+        println!("EINKRAD: {}", d);
+    }
+    Ok(drift.is_empty())
+}
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, VecDeque};
+
+use common::{matrix, quaternion};
+
+#[derive(Clone, Copy)]
+struct Sample {
+    time: f64,
+    translation: [f32; 3],
+    rotation: [f32; 4],
+    scale: [f32; 3],
+}
+
+impl Sample {
+    fn new(time: f64, transform: &[f32; 16]) -> Self {
+        let (translation, rotation, scale) = matrix::decompose(transform);
+        Self {
+            time,
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    fn matrix(&self) -> [f32; 16] {
+        matrix::compose(&self.translation, &self.rotation, &self.scale)
+    }
+
+    fn lerp(&self, o: &Sample, t: f32) -> [f32; 16] {
+        let mut translation = [0.0; 3];
+        let mut scale = [0.0; 3];
+        for i in 0..3 {
+            translation[i] = self.translation[i] + (o.translation[i] - self.translation[i]) * t;
+            scale[i] = self.scale[i] + (o.scale[i] - self.scale[i]) * t;
+        }
+
+        let mut rotation = self.rotation;
+        let angle = quaternion::angle(&self.rotation, &o.rotation);
+        quaternion::slerp(&mut rotation, &o.rotation, angle * t);
+
+        matrix::compose(&translation, &rotation, &scale)
+    }
+}
+
+/// Buffers timestamped transforms per node and plays them back `delay`
+/// seconds behind the server, so nodes move smoothly between snapshots.
+pub struct Interpolator {
+    /// How far playback runs behind the estimated server time, in seconds.
+    pub delay: f64,
+    /// How long a node keeps moving past its last sample when packets are
+    /// late, in seconds. Zero holds the last sample.
+    pub max_extrapolation: f64,
+    /// Server time of the newest snapshot.
+    latest: f64,
+    buffers: HashMap<u32, VecDeque<Sample>>,
+}
+
+impl Interpolator {
+    pub fn new(delay: f64, max_extrapolation: f64) -> Self {
+        Self {
+            delay,
+            max_extrapolation,
+            latest: f64::NEG_INFINITY,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Adds a sample of a snapshot, call `advance` once all of them are in.
+    pub fn push(&mut self, id: u32, time: f64, transform: &[f32; 16]) {
+        let buffer = self.buffers.entry(id).or_default();
+
+        // snapshots only carry changes, a node missing from the previous
+        // one stood still until then
+        if let Some(last) = buffer.back().copied() {
+            if last.time < self.latest && self.latest < time {
+                buffer.push_back(Sample {
+                    time: self.latest,
+                    ..last
+                });
+            }
+        }
+
+        if buffer.back().is_some_and(|last| last.time >= time) {
+            return;
+        }
+        buffer.push_back(Sample::new(time, transform));
+    }
+
+    /// Marks the snapshot at `time` as complete.
+    pub fn advance(&mut self, time: f64) {
+        self.latest = self.latest.max(time);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.buffers.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = f64::NEG_INFINITY;
+        self.buffers.clear();
+    }
+
+    /// Transform of the node at `server_time - delay`. Samples that can
+    /// not be needed anymore are dropped.
+    pub fn sample(&mut self, id: u32, server_time: f64) -> Option<[f32; 16]> {
+        let render_time = server_time - self.delay;
+        let buffer = self.buffers.get_mut(&id)?;
+
+        while buffer.len() > 2 && buffer[1].time <= render_time {
+            buffer.pop_front();
+        }
+
+        let first = *buffer.front()?;
+        let Some(second) = buffer.get(1).copied() else {
+            return Some(first.matrix());
+        };
+
+        if render_time <= first.time {
+            return Some(first.matrix());
+        }
+
+        let span = second.time - first.time;
+        let mut t = (render_time - first.time) / span;
+
+        if t > 1.0 && second.time < self.latest {
+            // newer snapshots did not move it, the node stopped
+            return Some(second.matrix());
+        }
+
+        if t > 1.0 {
+            // late packets, keep going the way the node went
+            let late = (render_time - second.time).min(self.max_extrapolation);
+            t = 1.0 + late.max(0.0) / span;
+            let mut translation = [0.0; 3];
+            for (i, v) in translation.iter_mut().enumerate() {
+                *v = first.translation[i]
+                    + (second.translation[i] - first.translation[i]) * t as f32;
+            }
+            return Some(matrix::compose(
+                &translation,
+                &second.rotation,
+                &second.scale,
+            ));
+        }
+
+        Some(first.lerp(&second, t as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> [f32; 16] {
+        matrix::compose(&[x, 0.0, 0.0], &[0.0, 0.0, 0.0, 1.0], &[1.0; 3])
+    }
+
+    fn x(transform: Option<[f32; 16]>) -> f32 {
+        matrix::decompose(&transform.unwrap()).0[0]
+    }
+
+    /// A node that went from 0 to 10 between the snapshots at 1 and 2.
+    fn moved(max_extrapolation: f64) -> Interpolator {
+        let mut interpolator = Interpolator::new(0.1, max_extrapolation);
+        interpolator.push(1, 1.0, &at(0.0));
+        interpolator.advance(1.0);
+        interpolator.push(1, 2.0, &at(10.0));
+        interpolator.advance(2.0);
+        interpolator
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn samples_are_interpolated_behind_the_server() {
+        let mut interpolator = moved(0.25);
+        assert!(close(x(interpolator.sample(1, 1.0)), 0.0));
+        assert!(close(x(interpolator.sample(1, 1.6)), 5.0));
+        assert!(close(x(interpolator.sample(1, 2.1)), 10.0));
+        assert!(interpolator.sample(2, 1.6).is_none());
+    }
+
+    #[test]
+    fn extrapolation_is_clamped() {
+        let mut interpolator = moved(0.25);
+        assert!(close(x(interpolator.sample(1, 2.2)), 11.0));
+        assert!(close(x(interpolator.sample(1, 3.0)), 12.5));
+
+        // without extrapolation the node holds its last sample
+        let mut interpolator = moved(0.0);
+        assert!(close(x(interpolator.sample(1, 3.0)), 10.0));
+    }
+
+    #[test]
+    fn nodes_left_out_of_snapshots_stood_still() {
+        let mut interpolator = moved(0.25);
+        interpolator.advance(3.0);
+        interpolator.advance(4.0);
+        // newer snapshots did not move it, so it does not drift on
+        assert!(close(x(interpolator.sample(1, 3.6)), 10.0));
+
+        // when it moves again it starts from where it stood at the last
+        // snapshot, not from the sample long before
+        interpolator.push(1, 5.0, &at(20.0));
+        interpolator.advance(5.0);
+        assert!(close(x(interpolator.sample(1, 4.1)), 10.0));
+        assert!(close(x(interpolator.sample(1, 4.6)), 15.0));
+    }
+
+    #[test]
+    fn removed_nodes_are_gone() {
+        let mut interpolator = moved(0.25);
+        interpolator.remove(1);
+        assert!(interpolator.sample(1, 1.6).is_none());
+    }
+}
@@ -1,25 +1,36 @@
 use std::{
     collections::HashMap,
     error::Error,
-    path::PathBuf,
-    sync::mpsc::{self, Sender},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
 };
 
-use message::ServiceMessage;
-use mlua::AnyUserData;
+use common::{message::Message, version::Version};
+use message::{PackageInfo, ServiceMessage, ServiceReply};
+use mlua::{AnyUserData, MultiValue, UserDataRef};
+use network::{Network, NetworkMessage};
 use node::{LuaNode, Node};
-use package::Package;
+use package::{Bus, Capability, Package, PackageState, Sandbox, TestOptions};
 use raylib_ffi::{
     enums::ConfigFlags, BeginDrawing, ClearBackground, CloseWindow, Color, DrawFPS, EndDrawing,
-    InitWindow, SetConfigFlags, SetTargetFPS, WindowShouldClose,
+    GetFrameTime, InitWindow, SetConfigFlags, SetTargetFPS, WindowShouldClose,
 };
+use replica::{Replica, MAX_EXTRAPOLATION};
 use scene::{lua_scene_new, LuaScene, Scene};
 
+mod definitions;
 mod drawable;
+mod interpolation;
 mod light;
 mod message;
+mod network;
 mod node;
+mod replica;
 mod scene;
+mod testing;
 
 #[macro_export]
 macro_rules! rl_str {
@@ -31,20 +42,131 @@ macro_rules! rl_str {
 enum GameMessage {
     SetLevel(u32),
     SetTargetFPS(u32),
+    Connect(String),
+    Send(Message),
+    SetInterpolation(f64, f64),
+}
+
+/// Nodes a package created.
+type Tracked = Arc<Mutex<Vec<Weak<RwLock<Node>>>>>;
+
+/// What a package made on the client, so a reload can clean up after it.
+struct Created {
+    scenes: Vec<u32>,
+    nodes: Tracked,
+}
+
+impl Created {
+    /// Removes the scenes and nodes, the drawables go with their scene.
+    fn clean_up(&mut self, scenes: &mut HashMap<u32, Scene>, active_scene: &mut u32) {
+        for id in self.scenes.drain(..) {
+            if let Some(scene) = scenes.remove(&id) {
+                // nodes that are not ours, like the replica, only hang below it
+                let children: Vec<_> = scene
+                    .root
+                    .read()
+                    .unwrap()
+                    .children
+                    .values()
+                    .cloned()
+                    .collect();
+                for child in children {
+                    Node::detach(&child);
+                }
+            }
+            if *active_scene == id {
+                *active_scene = 0;
+            }
+        }
+
+        let nodes: Vec<_> = self.nodes.lock().unwrap().drain(..).collect();
+        for node in nodes.iter().filter_map(Weak::upgrade) {
+            Node::destroy(&node);
+        }
+    }
 }
 
 struct Game {
     tx: Sender<GameMessage>,
     is_server: bool,
+    world: Arc<RwLock<Node>>,
+}
+
+fn lua_game_packages(lua: &mlua::Lua, _game: UserDataRef<Game>) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Packages)?;
+    package::request(lua, ServiceMessage::Packages, |lua, answer| {
+        let ServiceReply::PackageList(list) = answer else {
+            return Err(mlua::Error::runtime("could not list packages"));
+        };
+
+        let packages = lua.create_table()?;
+        for info in list {
+            let p = lua.create_table()?;
+            p.set("name", info.name)?;
+            p.set("version", info.version)?;
+            p.set("state", info.state.name())?;
+            p.set("updates", info.stats.updates)?;
+            p.set("overruns", info.stats.overruns)?;
+            p.set("skipped", info.stats.skipped)?;
+            p.set("worst", info.stats.worst.as_secs_f64())?;
+            if let PackageState::Crashed(error) = info.state {
+                p.set("error", error)?;
+            }
+            packages.push(p)?;
+        }
+        Ok(packages)
+    })
+}
+
+fn lua_game_restart_package(
+    lua: &mlua::Lua,
+    (_game, name): (UserDataRef<Game>, String),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Packages)?;
+    let msg = ServiceMessage::RestartPackage(name);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::Done(done) => Ok(done),
+        _ => Err(mlua::Error::runtime("could not restart package")),
+    })
+}
+
+fn lua_game_stop_package(
+    lua: &mlua::Lua,
+    (_game, name): (UserDataRef<Game>, String),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Packages)?;
+    let msg = ServiceMessage::StopPackage(name);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::Done(done) => Ok(done),
+        _ => Err(mlua::Error::runtime("could not stop package")),
+    })
 }
 
 impl mlua::UserData for Game {
     fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
         fields.add_field_method_get("isServer", |_lua, me| Ok(me.is_server));
+        fields.add_field_method_get("world", |lua, me| {
+            package::allow(lua, Capability::Scene)?;
+            Ok(LuaNode {
+                inner: me.world.clone(),
+            })
+        });
+
+        // these ask the render loop, so they are awaitable functions
+        fields.add_field_function_get("packages", |lua, _| {
+            package::awaitable_method(lua, "GamePackages", lua_game_packages)
+        });
+        fields.add_field_function_get("restartPackage", |lua, _| {
+            package::awaitable_method(lua, "GameRestartPackage", lua_game_restart_package)
+        });
+        fields.add_field_function_get("stopPackage", |lua, _| {
+            package::awaitable_method(lua, "GameStopPackage", lua_game_stop_package)
+        });
     }
 
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("setScene", |_lua, me, scene: AnyUserData| {
+        methods.add_method("setScene", |lua, me, scene: AnyUserData| {
+            package::allow(lua, Capability::Scene)?;
             let id = scene.borrow_scoped(|s: &LuaScene| s.id)?;
             me.tx.send(GameMessage::SetLevel(id)).unwrap();
             Ok(())
@@ -54,50 +176,163 @@ impl mlua::UserData for Game {
             me.tx.send(GameMessage::SetTargetFPS(fps)).unwrap();
             Ok(())
         });
+
+        methods.add_method("connect", |lua, me, (host, port): (String, u16)| {
+            package::allow(lua, Capability::Network)?;
+            me.tx
+                .send(GameMessage::Connect(format!("{}:{}", host, port)))
+                .unwrap();
+            Ok(())
+        });
+
+        methods.add_method(
+            "setInterpolation",
+            |_lua, me, (delay, max_extrapolation): (f64, Option<f64>)| {
+                me.tx
+                    .send(GameMessage::SetInterpolation(
+                        delay,
+                        max_extrapolation.unwrap_or(MAX_EXTRAPOLATION),
+                    ))
+                    .unwrap();
+                Ok(())
+            },
+        );
+
+        methods.add_function("after", |lua, (seconds, f): (f64, mlua::Function)| {
+            package::after(lua, seconds, f)
+        });
+
+        methods.add_function("every", |lua, (seconds, f): (f64, mlua::Function)| {
+            package::every(lua, seconds, f)
+        });
+
+        methods.add_function("cancel", |lua, id: u32| package::cancel(lua, id));
+
+        methods.add_method("send", |lua, me, (name, data): (String, mlua::Value)| {
+            package::allow(lua, Capability::Network)?;
+            let data = package::to_value(data)?;
+            me.tx
+                .send(GameMessage::Send(Message::Package { name, data }))
+                .unwrap();
+            Ok(())
+        });
     }
 }
 
+/// Sets up the globals of a client package, `definitions` describes them.
+/// Nodes the package creates end up in `tracked`.
+fn setup(c: &mlua::Lua, game: Game, tracked: &Tracked) -> Result<(), Box<dyn Error>> {
+    let globals = c.globals();
+
+    globals.set("Game", game)?;
+
+    let scene = c.create_table()?;
+    let func = package::awaitable(c, c.create_function(lua_scene_new)?)?;
+    scene.set("new", func)?;
+    globals.set("Scene", scene)?;
+
+    let node = c.create_table()?;
+    let tracked = tracked.clone();
+    let func = c.create_function(move |lua, _: ()| {
+        package::allow(lua, Capability::Scene)?;
+        let inner = Node::new();
+        tracked.lock().unwrap().push(Arc::downgrade(&inner));
+        Ok(LuaNode { inner })
+    })?;
+    node.set("new", func)?;
+    globals.set("Node", node)?;
+
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    if let Some(file) = std::env::args().skip_while(|a| a != "--definitions").nth(1) {
+        return definitions::write(file.as_ref());
+    }
+    if let Some(file) = std::env::args()
+        .skip_while(|a| a != "--check-definitions")
+        .nth(1)
+    {
+        if !definitions::check(file.as_ref())? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let sandbox = std::env::args().any(|a| a == "--sandbox");
+    if let Some(dir) = std::env::args().skip_while(|a| a != "--test").nth(1) {
+        let mut options = TestOptions::default();
+        if let Some(ticks) = std::env::args().skip_while(|a| a != "--ticks").nth(1) {
+            options.ticks = ticks.parse()?;
+        }
+        let report = std::env::args().skip_while(|a| a != "--report").nth(1);
+        if !testing::run(
+            dir.as_ref(),
+            &options,
+            sandbox,
+            report.as_deref().map(Path::new),
+        )? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let mut scenes: HashMap<u32, Scene> = HashMap::new();
     let data: PathBuf = "data".into();
     let mut active_scene = 0;
     let (gtx, grx) = mpsc::channel();
+    let (ntx, nrx) = mpsc::channel();
+    let mut generation = 0;
+    let mut network = std::env::args()
+        .skip_while(|a| a != "--server")
+        .nth(1)
+        .map(|addr| Network::connect(addr, generation, ntx.clone()));
 
+    let mut replica = Replica::new();
     let mut plugins = Vec::new();
+    let mut bus = Bus::new();
+    let mut created: HashMap<String, Created> = HashMap::new();
+    let mut restarts: Vec<String> = Vec::new();
+    let mut stops: Vec<String> = Vec::new();
+    let watch = std::env::args().any(|a| a == "--watch");
 
-    for entry in data.read_dir()? {
-        let entry = entry?;
-        if entry.metadata()?.is_dir() {
-            let gtx = gtx.clone();
-            match Package::<ServiceMessage>::load(entry.path(), move |c| {
-                let globals = c.globals();
-
-                globals.set(
-                    "Game",
-                    Game {
-                        tx: gtx.clone(),
-                        is_server: false,
-                    },
-                )?;
-
-                let scene = c.create_table()?;
-                let func = c.create_function(lua_scene_new)?;
-                scene.set("new", func)?;
-                globals.set("Scene", scene)?;
-
-                let node = c.create_table()?;
-                let func = c.create_function(|_lua, _: ()| Ok(LuaNode { inner: Node::new() }))?;
-                node.set("new", func)?;
-                globals.set("Node", node)?;
+    let (manifests, errors) = package::load_order(&data)?;
+    for e in errors {
+        println!("EINKRAD: {e}");
+    }
 
-                Ok(())
-            }) {
-                Ok(pk) => {
-                    plugins.push(pk);
-                }
-                Err(e) => {
-                    println!("EINKRAD: {e}");
+    let storage = package::data_dir("client").map(|d| d.join("storage"));
+    for mut manifest in manifests {
+        if sandbox {
+            manifest.sandbox.get_or_insert_with(Sandbox::default);
+        }
+        manifest.storage = storage.clone();
+        let gtx = gtx.clone();
+        let world = replica.root.clone();
+        let nodes = Arc::new(Mutex::new(Vec::new()));
+        let tracked = nodes.clone();
+        match Package::<ServiceMessage>::load(manifest, move |c| {
+            let game = Game {
+                tx: gtx.clone(),
+                is_server: false,
+                world: world.clone(),
+            };
+            setup(c, game, &tracked)
+        }) {
+            Ok(mut pk) => {
+                if watch {
+                    pk.watch();
                 }
+                created.insert(
+                    pk.name.clone(),
+                    Created {
+                        scenes: Vec::new(),
+                        nodes,
+                    },
+                );
+                plugins.push(pk);
+            }
+            Err(e) => {
+                println!("EINKRAD: {e}");
             }
         }
     }
@@ -110,31 +345,135 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("EINKRAD: --- START ---");
         while !WindowShouldClose() {
             for pk in plugins.iter() {
-                while let Ok(msg) = pk.service_rx.try_recv() {
-                    match msg {
+                while let Ok(request) = pk.service_rx.try_recv() {
+                    let reply = match request.msg {
                         ServiceMessage::CreateScene(name) => {
                             println!("EINKRAD: create scene {}", name);
                             let s = Scene::new(name);
                             let id = s.id;
                             let root = s.root.clone();
                             scenes.insert(s.id, s);
-                            pk.service_tx
-                                .send(ServiceMessage::CreatedScene(id, root))
-                                .unwrap();
+                            if let Some(c) = created.get_mut(&pk.name) {
+                                c.scenes.push(id);
+                            }
+                            Ok(ServiceReply::CreatedScene(id, root))
                         }
                         ServiceMessage::LoadDrawable(scene_id, file) => {
                             println!("EINKRAD: load drawable {} {}", scene_id, file);
-                            if let Some(scene) = scenes.get_mut(&scene_id) {
-                                let did = scene.load(file);
-                                pk.service_tx
-                                    .send(ServiceMessage::LoadedDrawable(did.0, did.1))
-                                    .unwrap();
+                            match scenes.get_mut(&scene_id) {
+                                Some(scene) => {
+                                    let did = scene.load(file);
+                                    Ok(ServiceReply::LoadedDrawable(did.0, did.1))
+                                }
+                                None => Err(format!("there is no scene {}", scene_id)),
+                            }
+                        }
+                        ServiceMessage::Packages => {
+                            let list = plugins
+                                .iter()
+                                .map(|p| PackageInfo {
+                                    name: p.name.clone(),
+                                    version: p.manifest.version.to_string(),
+                                    state: p.state(),
+                                    stats: p.stats(),
+                                })
+                                .collect();
+                            Ok(ServiceReply::PackageList(list))
+                        }
+                        ServiceMessage::RestartPackage(name) => {
+                            // a running one has to be stopped first
+                            let ended = plugins
+                                .iter()
+                                .any(|p| p.name == name && p.state().has_ended());
+                            if ended {
+                                restarts.push(name);
+                            }
+                            Ok(ServiceReply::Done(ended))
+                        }
+                        ServiceMessage::StopPackage(name) => {
+                            let found = plugins.iter().any(|p| p.name == name);
+                            if found {
+                                stops.push(name);
                             }
+                            Ok(ServiceReply::Done(found))
+                        }
+                    };
+                    pk.reply(request.id, reply);
+                }
+            }
+            bus.route(&plugins);
+
+            for pk in plugins.iter_mut().filter(|p| stops.contains(&p.name)) {
+                println!("EINKRAD: stop {}", pk.name);
+                pk.stop();
+                bus.forget(&pk.name);
+                if let Some(c) = created.get_mut(&pk.name) {
+                    c.clean_up(&mut scenes, &mut active_scene);
+                }
+            }
+            stops.clear();
+
+            for pk in plugins.iter_mut() {
+                let reload = pk.changed();
+                if !reload && !restarts.contains(&pk.name) {
+                    continue;
+                }
+                println!("EINKRAD: restart {}", pk.name);
+                let state = pk.unload();
+                bus.forget(&pk.name);
+
+                if let Some(c) = created.get_mut(&pk.name) {
+                    c.clean_up(&mut scenes, &mut active_scene);
+                }
+
+                if let Err(e) = pk.restart(reload.then_some(state)) {
+                    println!("EINKRAD: {e}");
+                }
+            }
+            restarts.clear();
+
+            while let Ok((from, msg)) = nrx.try_recv() {
+                // a connection that was replaced must not end the current one
+                if network.as_ref().is_none_or(|n| n.generation != from) {
+                    continue;
+                }
+                match msg {
+                    NetworkMessage::Connected(addr) => {
+                        println!("EINKRAD: connected to {}", addr);
+                    }
+                    NetworkMessage::Received(Message::Greeting {
+                        version,
+                        capabilities,
+                    }) => {
+                        let compatibility = Version::default().compatibility(&version);
+                        println!(
+                            "EINKRAD: server {} {:?} {:?}",
+                            version, compatibility, capabilities
+                        );
+                    }
+                    NetworkMessage::Received(Message::Reject(reason)) => {
+                        println!("EINKRAD: rejected by server {:?}", reason);
+                        network = None;
+                        replica.clear();
+                    }
+                    NetworkMessage::Received(Message::Package { name, data }) => {
+                        if let Some(pk) = plugins.iter().find(|pk| pk.name == name) {
+                            let _ = pk.msg_tx.send(data);
                         }
-                        ServiceMessage::CreatedScene(..) | ServiceMessage::LoadedDrawable(..) => {
-                            println!("EINKRAD: we should not get this");
+                    }
+                    NetworkMessage::Received(Message::Snapshot(snapshot)) => {
+                        if let Some(net) = network.as_mut() {
+                            replica.receive(&snapshot, net);
                         }
                     }
+                    NetworkMessage::Received(msg) => {
+                        replica.apply(msg);
+                    }
+                    NetworkMessage::Disconnected(e) => {
+                        println!("EINKRAD: disconnected {}", e);
+                        network = None;
+                        replica.clear();
+                    }
                 }
             }
 
@@ -146,9 +485,32 @@ fn main() -> Result<(), Box<dyn Error>> {
                     GameMessage::SetTargetFPS(fps) => {
                         SetTargetFPS(fps as _);
                     }
+                    GameMessage::Connect(addr) => {
+                        // the nodes of the previous server are gone with it
+                        if network.is_some() {
+                            replica.clear();
+                        }
+                        generation += 1;
+                        network = Some(Network::connect(addr, generation, ntx.clone()));
+                    }
+                    GameMessage::SetInterpolation(delay, max_extrapolation) => {
+                        replica.interpolator.delay = delay;
+                        replica.interpolator.max_extrapolation = max_extrapolation;
+                    }
+                    GameMessage::Send(msg) => match &network {
+                        Some(net) => net.send(msg),
+                        None => println!("EINKRAD: not connected"),
+                    },
                 }
             }
 
+            replica.update();
+
+            let dt = GetFrameTime() as f64;
+            for pk in plugins.iter() {
+                pk.frame(dt);
+            }
+
             BeginDrawing();
             ClearBackground(Color {
                 r: 255,
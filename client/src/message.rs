@@ -1,11 +1,36 @@
 use std::sync::{Arc, RwLock};
 
+use package::{PackageState, Service, UpdateStats};
+
 use crate::{drawable::DrawableInstances, node::Node};
 
+#[derive(Clone)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    pub state: PackageState,
+    pub stats: UpdateStats,
+}
+
+/// What a package asks the render loop.
 #[derive(Clone)]
 pub enum ServiceMessage {
     CreateScene(String),
-    CreatedScene(u32, Arc<RwLock<Node>>),
     LoadDrawable(u32, String),
+    Packages,
+    /// Starts a crashed or stopped package afresh.
+    RestartPackage(String),
+    StopPackage(String),
+}
+
+#[derive(Clone)]
+pub enum ServiceReply {
+    CreatedScene(u32, Arc<RwLock<Node>>),
     LoadedDrawable(u32, DrawableInstances),
+    PackageList(Vec<PackageInfo>),
+    Done(bool),
+}
+
+impl Service for ServiceMessage {
+    type Reply = ServiceReply;
 }
@@ -0,0 +1,115 @@
+use std::{
+    net::TcpStream,
+    sync::mpsc::{self, Sender},
+};
+
+use common::{
+    message::{read_message, write_message, Message},
+    snapshot,
+    transport::Transport,
+    version::Version,
+};
+
+pub enum NetworkMessage {
+    Connected(String),
+    Received(Message),
+    Disconnected(String),
+}
+
+/// Connection to a server, driven by a background thread.
+pub struct Network {
+    pub addr: String,
+    /// Tags the events of this connection, those of one it replaced can
+    /// still be on their way.
+    pub generation: u32,
+    tx: Sender<Message>,
+}
+
+impl Network {
+    /// Connects in the background, everything that arrives ends up in
+    /// `events` together with `generation`.
+    pub fn connect(addr: String, generation: u32, events: Sender<(u32, NetworkMessage)>) -> Self {
+        let (tx, rx) = mpsc::channel::<Message>();
+        let thread_addr = addr.clone();
+
+        std::thread::spawn(move || {
+            let events = Tagged(generation, events);
+            let mut writer = match TcpStream::connect(&thread_addr) {
+                Ok(s) => s,
+                Err(e) => {
+                    let _ = events.send(NetworkMessage::Disconnected(e.to_string()));
+                    return;
+                }
+            };
+            let mut reader = match writer.try_clone() {
+                Ok(s) => s,
+                Err(e) => {
+                    let _ = events.send(NetworkMessage::Disconnected(e.to_string()));
+                    return;
+                }
+            };
+
+            let greeting = Message::Greeting {
+                version: Version::default(),
+                capabilities: vec![snapshot::CAPABILITY.into()],
+            };
+            if let Err(e) = write_message(&mut writer, &greeting) {
+                let _ = events.send(NetworkMessage::Disconnected(e.to_string()));
+                return;
+            }
+            let _ = events.send(NetworkMessage::Connected(thread_addr));
+
+            std::thread::spawn(move || {
+                while let Ok(msg) = rx.recv() {
+                    if write_message(&mut writer, &msg).is_err() {
+                        break;
+                    }
+                }
+                let _ = writer.shutdown(std::net::Shutdown::Both);
+            });
+
+            loop {
+                match read_message(&mut reader) {
+                    Ok(msg) => {
+                        if events.send(NetworkMessage::Received(msg)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = events.send(NetworkMessage::Disconnected(e.to_string()));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            generation,
+            tx,
+        }
+    }
+
+    pub fn send(&self, msg: Message) {
+        if self.tx.send(msg).is_err() {
+            println!("EINKRAD: not connected to {}", self.addr);
+        }
+    }
+}
+
+/// Sends the events of one connection.
+struct Tagged(u32, Sender<(u32, NetworkMessage)>);
+
+impl Tagged {
+    fn send(&self, msg: NetworkMessage) -> Result<(), ()> {
+        self.1.send((self.0, msg)).map_err(|_| ())
+    }
+}
+
+impl Transport for Network {
+    fn send(&mut self, msg: Message) -> std::io::Result<()> {
+        self.tx
+            .send(msg)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+}
@@ -40,6 +40,48 @@ impl Node {
     pub fn apply_transform(&mut self) {
         common::matrix::mul_assign(&mut self.transform_world, &self.transform);
     }
+
+    pub fn is_attached(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    /// Moves `child` below `parent`, removing it from its previous parent.
+    pub fn attach(parent: &Arc<RwLock<Node>>, child: &Arc<RwLock<Node>>) {
+        let mut c = child.write().unwrap();
+
+        if let Some(op) = &c.parent {
+            op.write().unwrap().children.remove(&c.id);
+        }
+
+        c.parent = Some(parent.clone());
+
+        parent.write().unwrap().children.insert(c.id, child.clone());
+    }
+
+    pub fn detach(child: &Arc<RwLock<Node>>) {
+        let mut c = child.write().unwrap();
+
+        if let Some(op) = c.parent.take() {
+            op.write().unwrap().children.remove(&c.id);
+        }
+    }
+
+    /// Detaches the node and releases the drawable instances of its whole subtree.
+    pub fn destroy(node: &Arc<RwLock<Node>>) {
+        Node::detach(node);
+
+        let mut stack = vec![node.clone()];
+        while let Some(n) = stack.pop() {
+            let mut n = n.write().unwrap();
+            n.parent = None;
+            if let Some(drw) = n.drawable.take() {
+                if drw.instances.write().unwrap().remove(&n.id).is_some() {
+                    drw.matrices.write().unwrap().pop();
+                }
+            }
+            stack.extend(n.children.drain().map(|(_, c)| c));
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -82,19 +124,7 @@ impl mlua::UserData for LuaNode {
 
         methods.add_method("add", |_lua, me, child: AnyUserData| {
             let child = child.borrow_scoped(|child: &LuaNode| child.inner.clone())?;
-            let mut c = child.write().unwrap();
-
-            if let Some(op) = &c.parent {
-                op.write().unwrap().children.remove(&c.id);
-            }
-
-            c.parent = Some(me.inner.clone());
-
-            me.inner
-                .write()
-                .unwrap()
-                .children
-                .insert(c.id, child.clone());
+            Node::attach(&me.inner, &child);
             Ok(())
         });
 
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+
+use common::{
+    message::Message,
+    snapshot::{self, Snapshot, SnapshotReceiver},
+    transport::Transport,
+};
+
+use crate::{interpolation::Interpolator, node::Node};
+
+/// Local mirror of the server scene. Top level server nodes hang below
+/// `root`, which packages add to their own scenes through `Game.world`.
+pub struct Replica {
+    pub root: Arc<RwLock<Node>>,
+    nodes: HashMap<u32, Arc<RwLock<Node>>>,
+    receiver: SnapshotReceiver,
+    pub interpolator: Interpolator,
+    started: Instant,
+    /// Estimated server time minus local time, in seconds.
+    clock_offset: Option<f64>,
+}
+
+/// Snapshots the client keeps around as possible baselines.
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// Default playback delay, two server ticks.
+const INTERPOLATION_DELAY: f64 = 0.1;
+
+/// Default time a node keeps moving when snapshots are late.
+pub const MAX_EXTRAPOLATION: f64 = 0.25;
+
+impl Replica {
+    pub fn new() -> Self {
+        Self {
+            root: Node::new(),
+            nodes: HashMap::new(),
+            receiver: SnapshotReceiver::new(SNAPSHOT_HISTORY),
+            interpolator: Interpolator::new(INTERPOLATION_DELAY, MAX_EXTRAPOLATION),
+            started: Instant::now(),
+            clock_offset: None,
+        }
+    }
+
+    fn parent(&self, parent: Option<u32>) -> Arc<RwLock<Node>> {
+        parent
+            .and_then(|p| self.nodes.get(&p))
+            .unwrap_or(&self.root)
+            .clone()
+    }
+
+    /// Applies a scene message from the server, everything else is ignored.
+    pub fn apply(&mut self, msg: Message) {
+        match msg {
+            Message::CreateNode {
+                id,
+                parent,
+                transform,
+            } => {
+                // a node may be announced twice, by the greeting and by the
+                // changes of the same tick, it keeps its children then
+                let parent = self.parent(parent);
+                let node = self.nodes.entry(id).or_insert_with(Node::new);
+                node.write().unwrap().transform = transform;
+                Node::attach(&parent, node);
+            }
+            Message::ReparentNode { id, parent } => {
+                if let Some(node) = self.nodes.get(&id) {
+                    Node::attach(&self.parent(parent), node);
+                }
+            }
+            Message::TransformNode { id, transform } => {
+                if let Some(node) = self.nodes.get(&id) {
+                    node.write().unwrap().transform = transform;
+                }
+            }
+            Message::DestroyNode { id } => {
+                if let Some(node) = self.nodes.remove(&id) {
+                    Node::destroy(&node);
+                }
+                // descendants went with it on the server
+                self.nodes.retain(|_, n| n.read().unwrap().is_attached());
+            }
+            _ => {}
+        }
+    }
+
+    /// Applies a delta snapshot and acknowledges it through `transport`.
+    pub fn receive<T: Transport>(&mut self, snapshot: &Snapshot, transport: &mut T) {
+        match self.receiver.handle(snapshot, transport) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                println!("EINKRAD: ack snapshot {}: {}", snapshot.tick, e);
+            }
+        }
+
+        let time = snapshot.tick as f64 * snapshot::TICK.as_secs_f64();
+        let offset = time - self.started.elapsed().as_secs_f64();
+        self.clock_offset = Some(match self.clock_offset {
+            Some(o) => o + (offset - o) * 0.1,
+            None => offset,
+        });
+
+        let Some((_, state)) = self.receiver.latest() else {
+            return;
+        };
+
+        // `removed` only covers nodes of the baseline, a node created and
+        // removed while a snapshot was lost never shows up there
+        let gone: Vec<u32> = self
+            .nodes
+            .keys()
+            .filter(|id| !state.contains_key(id))
+            .copied()
+            .collect();
+        for id in gone {
+            if let Some(node) = self.nodes.remove(&id) {
+                Node::destroy(&node);
+            }
+            self.interpolator.remove(id);
+        }
+
+        // create first, a new node may be the parent of another new node
+        for d in snapshot.changed.iter() {
+            self.nodes.entry(d.id).or_insert_with(|| {
+                let node = Node::new();
+                node.write().unwrap().transform = state[&d.id].transform;
+                Node::attach(&self.root, &node);
+                node
+            });
+        }
+
+        for d in snapshot.changed.iter() {
+            let (Some(node), Some(n)) = (self.nodes.get(&d.id), state.get(&d.id)) else {
+                continue;
+            };
+            if d.parent.is_some() {
+                Node::attach(&self.parent(n.parent), node);
+            }
+            if d.transform.is_some() {
+                self.interpolator.push(d.id, time, &n.transform);
+            }
+        }
+        self.interpolator.advance(time);
+    }
+
+    /// Moves the replicated nodes to where they were `delay` seconds ago on
+    /// the server, called once per frame.
+    pub fn update(&mut self) {
+        let Some(offset) = self.clock_offset else {
+            return;
+        };
+        let server_time = self.started.elapsed().as_secs_f64() + offset;
+
+        for (id, node) in self.nodes.iter() {
+            if let Some(m) = self.interpolator.sample(*id, server_time) {
+                node.write().unwrap().transform = m;
+            }
+        }
+    }
+
+    /// Drops everything, used when the connection goes away.
+    pub fn clear(&mut self) {
+        for (_, node) in self.nodes.drain() {
+            Node::destroy(&node);
+        }
+        self.receiver = SnapshotReceiver::new(SNAPSHOT_HISTORY);
+        self.interpolator.clear();
+        self.clock_offset = None;
+    }
+}
@@ -8,8 +8,8 @@ use std::{
     },
 };
 
-use mlua::{AnyUserData, UserData};
-use package::App;
+use mlua::{MultiValue, UserData, UserDataRef};
+use package::Capability;
 use raylib_ffi::{
     enums::{CameraProjection, ShaderLocationIndex, ShaderUniformDataType},
     BeginMode3D, Camera, DrawSphereEx, EndMode3D, GetShaderLocation, GetShaderLocationAttrib,
@@ -19,7 +19,7 @@ use raylib_ffi::{
 use crate::{
     drawable::{Drawable, DrawableInstances, LuaDrawable},
     light::Light,
-    message::ServiceMessage,
+    message::{ServiceMessage, ServiceReply},
     node::{LuaNode, Node},
     rl_str,
 };
@@ -177,21 +177,36 @@ impl Scene {
     }
 }
 
-pub fn lua_scene_new(lua: &mlua::Lua, name: String) -> mlua::Result<LuaScene> {
-    let answer = lua
-        .named_registry_value::<AnyUserData>("App")?
-        .borrow_scoped(|app: &App<ServiceMessage>| {
-            app.sync_send(ServiceMessage::CreateScene(name))
-        })?;
-
-    if let ServiceMessage::CreatedScene(id, root) = answer {
-        Ok(LuaScene {
-            id,
-            root: LuaNode { inner: root },
-        })
-    } else {
-        Err(mlua::Error::runtime("could not create scene"))
-    }
+pub fn lua_scene_new(lua: &mlua::Lua, name: String) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Scene)?;
+    package::request(lua, ServiceMessage::CreateScene(name), |_lua, answer| {
+        if let ServiceReply::CreatedScene(id, root) = answer {
+            Ok(LuaScene {
+                id,
+                root: LuaNode { inner: root },
+            })
+        } else {
+            Err(mlua::Error::runtime("could not create scene"))
+        }
+    })
+}
+
+fn lua_scene_load(
+    lua: &mlua::Lua,
+    (scene, file): (UserDataRef<LuaScene>, String),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Scene)?;
+    let file = package::check_path(lua, &file)?
+        .to_string_lossy()
+        .into_owned();
+    let msg = ServiceMessage::LoadDrawable(scene.id, file);
+    package::request(lua, msg, |_lua, answer| {
+        if let ServiceReply::LoadedDrawable(id, instances) = answer {
+            Ok(LuaDrawable { id, instances })
+        } else {
+            Err(mlua::Error::runtime("could not load drawable"))
+        }
+    })
 }
 
 pub struct LuaScene {
@@ -202,21 +217,8 @@ pub struct LuaScene {
 impl UserData for LuaScene {
     fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
         fields.add_field_method_get("root", |_lua, me| Ok(me.root.clone()));
-    }
-
-    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("load", |lua, me, file: String| {
-            let answer = lua
-                .named_registry_value::<AnyUserData>("App")?
-                .borrow_scoped(|app: &App<ServiceMessage>| {
-                    app.sync_send(ServiceMessage::LoadDrawable(me.id, file))
-                })?;
-
-            if let ServiceMessage::LoadedDrawable(id, instances) = answer {
-                Ok(LuaDrawable { id, instances })
-            } else {
-                Err(mlua::Error::runtime("could not load drawable"))
-            }
+        fields.add_field_function_get("load", |lua, _| {
+            package::awaitable_method(lua, "SceneLoad", lua_scene_load)
         });
     }
 }
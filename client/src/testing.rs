@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::Path,
+    sync::{mpsc, Arc, Mutex, RwLock},
+};
+
+use package::{Manifest, PackageState, Sandbox, TestOptions, UpdateStats};
+
+use crate::{
+    drawable::DrawableInstances,
+    message::{PackageInfo, ServiceMessage, ServiceReply},
+    node::Node,
+    setup, Game,
+};
+
+/// Answers a package like the render loop does, without a window. Scenes
+/// are only their root node and drawables have no model.
+struct MockHost {
+    name: String,
+    version: String,
+    scenes: HashMap<u32, Arc<RwLock<Node>>>,
+    last_id: u32,
+}
+
+impl MockHost {
+    fn serve(&mut self, msg: ServiceMessage) -> Result<ServiceReply, String> {
+        match msg {
+            ServiceMessage::CreateScene(_) => {
+                self.last_id += 1;
+                let root = Node::new();
+                self.scenes.insert(self.last_id, root.clone());
+                Ok(ServiceReply::CreatedScene(self.last_id, root))
+            }
+            ServiceMessage::LoadDrawable(scene_id, _) => {
+                if !self.scenes.contains_key(&scene_id) {
+                    return Err(format!("there is no scene {}", scene_id));
+                }
+                self.last_id += 1;
+                let instances = DrawableInstances {
+                    matrices: Arc::new(RwLock::new(Vec::new())),
+                    instances: Arc::new(RwLock::new(HashMap::new())),
+                };
+                Ok(ServiceReply::LoadedDrawable(self.last_id, instances))
+            }
+            ServiceMessage::Packages => Ok(ServiceReply::PackageList(vec![PackageInfo {
+                name: self.name.clone(),
+                version: self.version.clone(),
+                state: PackageState::Running,
+                stats: UpdateStats::default(),
+            }])),
+            // there are no other packages to restart or stop
+            ServiceMessage::RestartPackage(_) | ServiceMessage::StopPackage(_) => {
+                Ok(ServiceReply::Done(false))
+            }
+        }
+    }
+}
+
+/// Runs the tests of the package in `dir` without opening a window. The
+/// JSON report goes to `report` if given. Whether all tests passed.
+pub fn run(
+    dir: &Path,
+    options: &TestOptions,
+    sandbox: bool,
+    report: Option<&Path>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut manifest = Manifest::read(dir)?;
+    if sandbox {
+        manifest.sandbox.get_or_insert_with(Sandbox::default);
+    }
+
+    // what the package tells the game is dropped once the tests are done
+    let (gtx, _grx) = mpsc::channel();
+    let world = Node::new();
+    let tracked = Arc::new(Mutex::new(Vec::new()));
+    let game_setup = move |c: &mlua::Lua| {
+        let game = Game {
+            tx: gtx.clone(),
+            is_server: false,
+            world: world.clone(),
+        };
+        setup(c, game, &tracked)
+    };
+
+    let mut host = MockHost {
+        name: manifest.name.clone(),
+        version: manifest.version.to_string(),
+        scenes: HashMap::new(),
+        last_id: 0,
+    };
+    // nothing on the client moves by itself
+    let result = package::test::<ServiceMessage, _, _>(
+        &manifest,
+        &game_setup,
+        options,
+        move |msg| host.serve(msg),
+        |_| Vec::new(),
+    );
+
+    println!("{}", result);
+    if let Some(file) = report {
+        std::fs::write(file, result.to_json() + "\n")?;
+    }
+    Ok(result.passed())
+}
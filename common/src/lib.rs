@@ -1,5 +1,8 @@
 pub mod matrix;
 pub mod message;
 pub mod quaternion;
+pub mod snapshot;
+pub mod transport;
+pub mod value;
 pub mod vector;
 pub mod version;
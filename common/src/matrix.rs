@@ -510,3 +510,106 @@ pub fn get_angle(m: &[f32; 16]) -> [f32; 3] {
 
     [pitch, yaw, roll]
 }
+
+/// Splits an affine transform into translation, rotation quaternion and scale.
+pub fn decompose(m: &[f32; 16]) -> ([f32; 3], [f32; 4], [f32; 3]) {
+    let translation = [m[12], m[13], m[14]];
+    let scale = [
+        (m[0] * m[0] + m[1] * m[1] + m[2] * m[2]).sqrt(),
+        (m[4] * m[4] + m[5] * m[5] + m[6] * m[6]).sqrt(),
+        (m[8] * m[8] + m[9] * m[9] + m[10] * m[10]).sqrt(),
+    ];
+
+    let mut r = [0.0; 16];
+    identity(&mut r);
+    for c in 0..3 {
+        if scale[c] != 0.0 {
+            for i in 0..3 {
+                r[c * 4 + i] = m[c * 4 + i] / scale[c];
+            }
+        }
+    }
+
+    (translation, get_rotation(&mut r), scale)
+}
+
+/// Inverse of `decompose`.
+pub fn compose(t: &[f32; 3], q: &[f32; 4], s: &[f32; 3]) -> [f32; 16] {
+    let mut m = [0.0; 16];
+    crate::quaternion::set_matrix(&mut m, q);
+
+    for c in 0..3 {
+        for i in 0..3 {
+            m[c * 4 + i] *= s[c];
+        }
+    }
+    m[12] = t[0];
+    m[13] = t[1];
+    m[14] = t[2];
+
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quaternion;
+
+    fn assert_near(a: &[f32], b: &[f32]) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-4, "{:?} is not {:?}", a, b);
+        }
+    }
+
+    fn rotation(axis: [f32; 3], a: f32) -> [f32; 4] {
+        let s = (a / 2.0).sin();
+        let mut q = [axis[0] * s, axis[1] * s, axis[2] * s, (a / 2.0).cos()];
+        quaternion::normalize(&mut q);
+        q
+    }
+
+    #[test]
+    fn compose_then_decompose_gives_the_parts() {
+        let cases = [
+            (
+                [0.0, 0.0, 0.0],
+                rotation([0.0, 0.0, 1.0], 0.0),
+                [1.0, 1.0, 1.0],
+            ),
+            (
+                [1.0, -2.0, 3.0],
+                rotation([0.0, 1.0, 0.0], 1.0),
+                [2.0, 2.0, 2.0],
+            ),
+            (
+                [5.0, 0.5, -1.0],
+                rotation([1.0, 1.0, 0.0], 2.5),
+                [0.5, 3.0, 1.5],
+            ),
+            (
+                [0.0, 9.0, 0.0],
+                rotation([1.0, 0.0, 0.0], PI),
+                [1.0, 2.0, 1.0],
+            ),
+        ];
+
+        for (t, q, s) in cases {
+            let (t2, q2, s2) = decompose(&compose(&t, &q, &s));
+            assert_near(&t2, &t);
+            assert_near(&s2, &s);
+            assert!(quaternion::angle(&q2, &q) < 1e-3, "{:?} is not {:?}", q2, q);
+        }
+    }
+
+    #[test]
+    fn decompose_then_compose_gives_the_matrix() {
+        let mut m = [0.0; 16];
+        identity(&mut m);
+        translate(&mut m, &[3.0, -1.0, 2.0]);
+        rotate_by_quaternion(&mut m, &rotation([0.0, 1.0, 1.0], 0.7));
+        scale(&mut m, &[1.5, 0.5, 2.0]);
+
+        let (t, q, s) = decompose(&m);
+        assert_near(&compose(&t, &q, &s), &m);
+    }
+}
@@ -1,8 +1,171 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io::{Read, Write},
+};
+
 use borsh::{BorshDeserialize, BorshSerialize};
 
-use crate::version::Version;
+use crate::{snapshot::Snapshot, value::Value, version::Version};
+
+/// Upper bound for the length prefix of a single message frame.
+pub const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Reject {
+    /// Major or minor version differ.
+    VersionMismatch { server: String, client: String },
+    /// The peer sent something else before its greeting.
+    NotGreeted,
+}
 
-#[derive(BorshSerialize, BorshDeserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
 pub enum Message {
-    Greeting { version: Version },
+    /// First message in both directions. The client offers its capabilities,
+    /// the server answers with the ones it accepted.
+    Greeting {
+        version: Version,
+        capabilities: Vec<String>,
+    },
+    /// Sent by the server right before it closes the connection.
+    Reject(Reject),
+    /// Payload for the package called `name` on the other side.
+    Package {
+        name: String,
+        data: Value,
+    },
+    /// A node was added to the server scene, `parent` is `None` for top level nodes.
+    CreateNode {
+        id: u32,
+        parent: Option<u32>,
+        transform: [f32; 16],
+    },
+    ReparentNode {
+        id: u32,
+        parent: Option<u32>,
+    },
+    TransformNode {
+        id: u32,
+        transform: [f32; 16],
+    },
+    /// Removes the node and everything below it.
+    DestroyNode {
+        id: u32,
+    },
+    /// Scene state as a delta, replaces the node events for clients with the
+    /// snapshot capability.
+    Snapshot(Snapshot),
+    /// The client applied the snapshot of this tick.
+    Ack {
+        tick: u32,
+    },
+}
+
+/// A frame over `MAX_FRAME_SIZE`, wrapped in an `io::Error` so that both
+/// sides can tell it from other errors.
+#[derive(Debug)]
+pub struct FrameTooLarge(pub u32);
+
+impl Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame of {} bytes exceeds the limit", self.0)
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+pub(crate) fn too_large(size: u32) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, FrameTooLarge(size))
+}
+
+/// Length of the frame that starts with `prefix`, a u32 little endian.
+pub fn frame_len(prefix: [u8; 4]) -> std::io::Result<usize> {
+    let size = u32::from_le_bytes(prefix);
+    if size > MAX_FRAME_SIZE {
+        return Err(too_large(size));
+    }
+    Ok(size as usize)
+}
+
+/// `msg` as a whole frame: its length, then the Borsh data.
+pub fn encode(msg: &Message) -> std::io::Result<Vec<u8>> {
+    let data = borsh::to_vec(msg)?;
+    let size = u32::try_from(data.len()).unwrap_or(u32::MAX);
+    if size > MAX_FRAME_SIZE {
+        return Err(too_large(size));
+    }
+
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&size.to_le_bytes());
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// The message in the data of a frame, without its length.
+pub fn decode(data: &[u8]) -> std::io::Result<Message> {
+    Message::try_from_slice(data)
+}
+
+/// Reads one frame, the blocking counterpart of the server framing.
+pub fn read_message<R: Read>(r: &mut R) -> std::io::Result<Message> {
+    let mut prefix = [0u8; 4];
+    r.read_exact(&mut prefix)?;
+    let mut data = vec![0u8; frame_len(prefix)?];
+    r.read_exact(&mut data)?;
+    decode(&data)
+}
+
+pub fn write_message<W: Write>(w: &mut W, msg: &Message) -> std::io::Result<()> {
+    w.write_all(&encode(msg)?)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(size: usize) -> Message {
+        Message::Package {
+            name: "chat".into(),
+            data: Value::String("x".repeat(size)),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &package(10)).unwrap();
+        write_message(&mut buf, &Message::Ack { tick: 7 }).unwrap();
+
+        let mut r = buf.as_slice();
+        let Message::Package { name, data } = read_message(&mut r).unwrap() else {
+            panic!("expected a package message");
+        };
+        assert_eq!(name, "chat");
+        assert_eq!(data, Value::String("x".repeat(10)));
+        assert!(matches!(
+            read_message(&mut r).unwrap(),
+            Message::Ack { tick: 7 }
+        ));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_on_both_ends() {
+        let e = write_message(&mut Vec::new(), &package(MAX_FRAME_SIZE as usize)).unwrap_err();
+        assert!(e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<FrameTooLarge>()
+            .is_some());
+
+        let prefix = (MAX_FRAME_SIZE + 1).to_le_bytes();
+        let Err(e) = read_message(&mut prefix.as_slice()) else {
+            panic!("read an oversized frame");
+        };
+        assert!(matches!(
+            e.get_ref().unwrap().downcast_ref::<FrameTooLarge>(),
+            Some(FrameTooLarge(size)) if *size == MAX_FRAME_SIZE + 1
+        ));
+    }
 }
@@ -18,81 +18,140 @@ pub fn normalize(q: &mut [f32; 4]) {
 }
 
 
+/// Writes the rotation of `q` into the upper 3x3 of `m`, clears the rest.
 pub fn set_matrix(m: &mut [f32; 16], q: &[f32; 4]) {
-//     let x2  = x + x;
-//     let y2  = y + y;
-//     let z2  = z + z;
-//     let xx2 = x * x2;
-//     let xy2 = x * y2;
-//     let xz2 = x * z2;
-//     let yy2 = y * y2;
-//     let yz2 = y * z2;
-//     let zz2 = z * z2;
-//     let sx2 = s * x2;
-//     let sy2 = s * y2;
-//     let sz2 = s * z2;
-
-// m[0] = 1.0 - (yy2 + zz2);
-//     m[1] = xy2 + sz2;
-//     m[2] = xz2 - sy2;
-//     m[3] = 0.0; // column 0;
-//     m[4] = xy2 - sz2;
-//     m[5] = 1.0 - (xx2 + zz2);
-//     m[6] = yz2 + sx2;
-//     m[7] = 0.0; // column 1;
-//     m[8] = xz2 + sy2;
-//     m[9] = yz2 - sx2;
-//     m[10] = 1.0 - (xx2 + yy2);
-//     m[11] = 0.0; // column 2;
-//     m[12] = 0.0;
-//     m[13] = 0.0          ;
-//     m[14] = 0.0;
-//     m[15] = 1.0 // column 3;
+    let x2 = q[0] + q[0];
+    let y2 = q[1] + q[1];
+    let z2 = q[2] + q[2];
+    let xx2 = q[0] * x2;
+    let xy2 = q[0] * y2;
+    let xz2 = q[0] * z2;
+    let yy2 = q[1] * y2;
+    let yz2 = q[1] * z2;
+    let zz2 = q[2] * z2;
+    let sx2 = q[3] * x2;
+    let sy2 = q[3] * y2;
+    let sz2 = q[3] * z2;
+
+    m[0] = 1.0 - (yy2 + zz2);
+    m[1] = xy2 + sz2;
+    m[2] = xz2 - sy2;
+    m[3] = 0.0; // column 0
+    m[4] = xy2 - sz2;
+    m[5] = 1.0 - (xx2 + zz2);
+    m[6] = yz2 + sx2;
+    m[7] = 0.0; // column 1
+    m[8] = xz2 + sy2;
+    m[9] = yz2 - sx2;
+    m[10] = 1.0 - (xx2 + yy2);
+    m[11] = 0.0; // column 2
+    m[12] = 0.0;
+    m[13] = 0.0;
+    m[14] = 0.0;
+    m[15] = 1.0; // column 3
+}
+
+/// Angle between two orientations along the shorter path, in the units `slerp` expects.
+pub fn angle(q1: &[f32; 4], q2: &[f32; 4]) -> f32 {
+    let d = q1[0] * q2[0] + q1[1] * q2[1] + q1[2] * q2[2] + q1[3] * q2[3];
+    d.abs().min(1.0).acos()
 }
 
+/// Rotates `q1` towards `q2`, but at most by `max_angle`.
 pub fn slerp(q1: &mut [f32; 4], q2: &[f32; 4], max_angle: f32) {
-    
     if max_angle < 0.001 {
-		// No rotation allowed. Prevent dividing by 0 later.
+        // No rotation allowed. Prevent dividing by 0 later.
         return;
     }
 
-    let mut cosTheta = 0.0;
+    let mut cos_theta = 0.0;
     for i in 0..4 {
-        cosTheta += q1[i] * q2[i];
+        cos_theta += q1[i] * q2[i];
     }
 
-	// q1 and q2 are already equal.
-	// Force q2 just to be sure
-    if cosTheta > 0.9999 {
-        return;
+    // Avoid taking the long path around the sphere
+    if cos_theta < 0.0 {
+        for v in q1.iter_mut() {
+            *v = -*v;
+        }
+        cos_theta = -cos_theta;
     }
 
-	// Avoid taking the long path around the sphere
-    if cosTheta < 0.0 {
-        for i in 0..4 {
-            q1[i] = q1[i] * -1.0;
-        }
-        cosTheta *= -1.0;
+    // q1 and q2 are already equal.
+    // Force q2 just to be sure
+    if cos_theta > 0.9999 {
+        *q1 = *q2;
+        return;
     }
 
-    let mut angle = cosTheta.acos();
+    let angle = cos_theta.acos();
 
-	// If there is only a 2&deg; difference, and we are allowed 5&deg;,
-	// ten we arrive:
-    if angle < max_angle {
-        return ;
+    // If there is only a 2&deg; difference, and we are allowed 5&deg;,
+    // then we arrive:
+    if angle <= max_angle {
+        *q1 = *q2;
+        return;
     }
 
     let ft = max_angle / angle;
-    angle = max_angle;
-
-    // res = [0] * 4
-    let a = ((1.0 - ft) * angle).cos();
+    let a = ((1.0 - ft) * angle).sin();
     let b = (ft * angle).sin();
     let _sin = angle.sin();
     for r in 0..4 {
         q1[r] = (a * q1[r] + b * q2[r]) / _sin;
     }
     normalize(q1);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rotation by `a` radians around z.
+    fn around_z(a: f32) -> [f32; 4] {
+        [0.0, 0.0, (a / 2.0).sin(), (a / 2.0).cos()]
+    }
+
+    fn assert_same_rotation(a: &[f32; 4], b: &[f32; 4]) {
+        assert!(angle(a, b) < 1e-3, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn slerp_stays_without_an_angle() {
+        let mut q = around_z(0.0);
+        slerp(&mut q, &around_z(1.0), 0.0);
+        assert_eq!(q, around_z(0.0));
+    }
+
+    #[test]
+    fn slerp_arrives_when_allowed_enough() {
+        let to = around_z(1.0);
+        let mut q = around_z(0.0);
+        slerp(&mut q, &to, angle(&around_z(0.0), &to));
+        assert_eq!(q, to);
+
+        let mut q = around_z(0.0);
+        slerp(&mut q, &to, 10.0);
+        assert_eq!(q, to);
+    }
+
+    #[test]
+    fn slerp_halfway_is_the_middle_rotation() {
+        let from = around_z(0.0);
+        let to = around_z(std::f32::consts::FRAC_PI_2);
+        let mut q = from;
+        slerp(&mut q, &to, angle(&from, &to) / 2.0);
+
+        assert_same_rotation(&q, &around_z(std::f32::consts::FRAC_PI_4));
+        assert!((angle(&q, &from) - angle(&q, &to)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn slerp_takes_the_short_way() {
+        // the negated quaternion is the same rotation
+        let to = around_z(std::f32::consts::FRAC_PI_2).map(|v| -v);
+        let mut q = around_z(0.0);
+        slerp(&mut q, &to, angle(&around_z(0.0), &to) / 2.0);
+        assert_same_rotation(&q, &around_z(std::f32::consts::FRAC_PI_4));
+    }
+}
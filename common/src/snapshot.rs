@@ -0,0 +1,348 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    message::{self, Message, MAX_FRAME_SIZE},
+    transport::Transport,
+};
+
+/// Capability a client offers when it wants snapshots instead of scene events.
+pub const CAPABILITY: &str = "delta-snapshots";
+
+/// Time between two server ticks, the server time of a snapshot is `tick * TICK`.
+pub const TICK: Duration = Duration::from_millis(50);
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Debug)]
+pub struct NodeState {
+    pub parent: Option<u32>,
+    pub transform: [f32; 16],
+}
+
+/// Every node of a scene at one tick, ordered by id.
+pub type WorldState = BTreeMap<u32, NodeState>;
+
+/// The fields of one node that differ from the baseline, `None` means unchanged.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct NodeDelta {
+    pub id: u32,
+    pub parent: Option<Option<u32>>,
+    pub transform: Option<[f32; 16]>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub tick: u32,
+    /// Tick the delta is based on, `None` for a full snapshot.
+    pub baseline: Option<u32>,
+    pub changed: Vec<NodeDelta>,
+    pub removed: Vec<u32>,
+}
+
+/// Delta from `baseline` to `current`. Without a baseline every node is sent in full.
+pub fn diff(tick: u32, baseline: Option<(u32, &WorldState)>, current: &WorldState) -> Snapshot {
+    let empty = WorldState::new();
+    let (base_tick, base) = match baseline {
+        Some((t, b)) => (Some(t), b),
+        None => (None, &empty),
+    };
+
+    let mut changed = Vec::new();
+    for (id, n) in current.iter() {
+        match base.get(id) {
+            Some(o) => {
+                let parent = (o.parent != n.parent).then_some(n.parent);
+                let transform = (o.transform != n.transform).then_some(n.transform);
+                if parent.is_some() || transform.is_some() {
+                    changed.push(NodeDelta {
+                        id: *id,
+                        parent,
+                        transform,
+                    });
+                }
+            }
+            None => changed.push(NodeDelta {
+                id: *id,
+                parent: Some(n.parent),
+                transform: Some(n.transform),
+            }),
+        }
+    }
+
+    let removed = base
+        .keys()
+        .filter(|id| !current.contains_key(id))
+        .copied()
+        .collect();
+
+    Snapshot {
+        tick,
+        baseline: base_tick,
+        changed,
+        removed,
+    }
+}
+
+/// Rebuilds the state a snapshot describes. `baseline` must be the state of `snapshot.baseline`.
+pub fn apply(baseline: Option<&WorldState>, snapshot: &Snapshot) -> WorldState {
+    let mut state = baseline.cloned().unwrap_or_default();
+
+    for id in snapshot.removed.iter() {
+        state.remove(id);
+    }
+
+    for d in snapshot.changed.iter() {
+        let mut transform = [0.0; 16];
+        crate::matrix::identity(&mut transform);
+        let n = state.entry(d.id).or_insert(NodeState {
+            parent: None,
+            transform,
+        });
+        if let Some(p) = d.parent {
+            n.parent = p;
+        }
+        if let Some(t) = d.transform {
+            n.transform = t;
+        }
+    }
+
+    state
+}
+
+/// The last `capacity` world states, oldest first.
+pub struct SnapshotHistory {
+    capacity: usize,
+    states: VecDeque<(u32, WorldState)>,
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            states: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, tick: u32, state: WorldState) {
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back((tick, state));
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&WorldState> {
+        self.states.iter().find(|(t, _)| *t == tick).map(|(_, s)| s)
+    }
+
+    pub fn latest(&self) -> Option<(u32, &WorldState)> {
+        self.states.back().map(|(t, s)| (*t, s))
+    }
+
+    /// Delta from the acknowledged tick to the latest state. Falls back to a
+    /// full snapshot once the acknowledged state left the history.
+    pub fn delta(&self, acked: Option<u32>) -> Option<Snapshot> {
+        let (tick, current) = self.latest()?;
+        let baseline = acked.and_then(|a| self.get(a).map(|s| (a, s)));
+        Some(diff(tick, baseline, current))
+    }
+
+    /// Sends the delta for a peer that acknowledged `acked`. Fails with
+    /// `FrameTooLarge` when the delta does not fit in one frame, the peer
+    /// can not catch up then.
+    pub fn replicate<T: Transport>(
+        &self,
+        acked: Option<u32>,
+        transport: &mut T,
+    ) -> std::io::Result<()> {
+        if let Some(s) = self.delta(acked) {
+            let msg = Message::Snapshot(s);
+            let size = borsh::object_length(&msg)?;
+            if size > MAX_FRAME_SIZE as usize {
+                return Err(message::too_large(u32::try_from(size).unwrap_or(u32::MAX)));
+            }
+            transport.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Client side of the replication: keeps the states it acknowledged so the
+/// server can base a delta on any of them.
+pub struct SnapshotReceiver {
+    history: SnapshotHistory,
+}
+
+impl SnapshotReceiver {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            history: SnapshotHistory::new(capacity),
+        }
+    }
+
+    pub fn latest(&self) -> Option<(u32, &WorldState)> {
+        self.history.latest()
+    }
+
+    /// Applies the snapshot and returns the tick to acknowledge. Snapshots
+    /// that are out of date or based on an unknown baseline are skipped.
+    pub fn receive(&mut self, snapshot: &Snapshot) -> Option<u32> {
+        if let Some((latest, _)) = self.history.latest() {
+            if snapshot.tick <= latest {
+                return None;
+            }
+        }
+
+        let state = match snapshot.baseline {
+            Some(b) => apply(Some(self.history.get(b)?), snapshot),
+            None => apply(None, snapshot),
+        };
+        self.history.push(snapshot.tick, state);
+
+        Some(snapshot.tick)
+    }
+
+    /// Like `receive`, but also sends the acknowledgement. Returns whether
+    /// the snapshot was applied.
+    pub fn handle<T: Transport>(
+        &mut self,
+        snapshot: &Snapshot,
+        transport: &mut T,
+    ) -> std::io::Result<bool> {
+        match self.receive(snapshot) {
+            Some(tick) => {
+                transport.send(Message::Ack { tick })?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{loopback, Loopback};
+
+    fn node(parent: Option<u32>, x: f32) -> NodeState {
+        let mut transform = [0.0; 16];
+        crate::matrix::identity(&mut transform);
+        transform[12] = x;
+        NodeState { parent, transform }
+    }
+
+    /// Hands every snapshot waiting at `client` to `receiver`.
+    fn deliver(client: &mut Loopback, receiver: &mut SnapshotReceiver) {
+        while let Some(msg) = client.recv() {
+            if let Message::Snapshot(s) = msg {
+                receiver.handle(&s, client).unwrap();
+            }
+        }
+    }
+
+    /// The newest tick the client acknowledged.
+    fn acks(server: &mut Loopback, acked: &mut Option<u32>) {
+        while let Some(msg) = server.recv() {
+            if let Message::Ack { tick } = msg {
+                *acked = Some(acked.map_or(tick, |a| a.max(tick)));
+            }
+        }
+    }
+
+    #[test]
+    fn replication_converges_despite_losses() {
+        let (mut server, mut client) = loopback();
+        server.drop_every = Some(3);
+        client.drop_every = Some(2);
+        let mut history = SnapshotHistory::new(8);
+        let mut receiver = SnapshotReceiver::new(8);
+        let mut acked = None;
+
+        for tick in 1..=40u32 {
+            let mut state = WorldState::new();
+            state.insert(1, node(None, tick as f32));
+            // a node that comes and goes
+            if tick % 5 < 2 {
+                state.insert(2, node(Some(1), 0.0));
+            }
+            // and one that moves between parents
+            state.insert(3, node(Some(if tick % 7 < 3 { 1 } else { 2 }), 1.0));
+            history.push(tick, state);
+
+            history.replicate(acked, &mut server).unwrap();
+            deliver(&mut client, &mut receiver);
+            acks(&mut server, &mut acked);
+        }
+
+        // nothing changes any more, the lost messages are made up for
+        for _ in 0..4 {
+            history.replicate(acked, &mut server).unwrap();
+            deliver(&mut client, &mut receiver);
+            acks(&mut server, &mut acked);
+        }
+        assert_eq!(receiver.latest(), history.latest());
+    }
+
+    #[test]
+    fn nodes_can_vanish_without_being_removed() {
+        let (mut server, mut client) = loopback();
+        let mut history = SnapshotHistory::new(8);
+        let mut receiver = SnapshotReceiver::new(8);
+        let mut acked = None;
+
+        history.push(1, WorldState::from([(1, node(None, 0.0))]));
+        history.replicate(acked, &mut server).unwrap();
+        deliver(&mut client, &mut receiver);
+        acks(&mut server, &mut acked);
+        assert_eq!(acked, Some(1));
+
+        // node 2 only exists in a snapshot that gets lost
+        history.push(
+            2,
+            WorldState::from([(1, node(None, 0.0)), (2, node(None, 0.0))]),
+        );
+        server.drop_every = Some(1);
+        history.replicate(acked, &mut server).unwrap();
+        server.drop_every = None;
+        history.push(3, WorldState::from([(1, node(None, 1.0))]));
+        history.replicate(acked, &mut server).unwrap();
+
+        let Some(Message::Snapshot(s)) = client.recv() else {
+            panic!("no snapshot");
+        };
+        assert_eq!(s.baseline, Some(1));
+        assert!(s.removed.is_empty());
+        assert!(receiver.handle(&s, &mut client).unwrap());
+        let (tick, state) = receiver.latest().unwrap();
+        assert_eq!(tick, 3);
+        assert!(!state.contains_key(&2));
+    }
+
+    #[test]
+    fn stale_and_unknown_baselines_are_skipped() {
+        let mut receiver = SnapshotReceiver::new(4);
+        let one = WorldState::from([(1, node(None, 0.0))]);
+        let two = WorldState::from([(1, node(None, 2.0))]);
+
+        assert_eq!(receiver.receive(&diff(2, None, &one)), Some(2));
+        assert_eq!(receiver.receive(&diff(1, None, &two)), None);
+        assert_eq!(receiver.receive(&diff(3, Some((9, &one)), &two)), None);
+        assert_eq!(receiver.receive(&diff(3, Some((2, &one)), &two)), Some(3));
+        assert_eq!(receiver.latest(), Some((3, &two)));
+    }
+
+    #[test]
+    fn oversized_snapshots_are_refused() {
+        let (mut server, mut client) = loopback();
+        let mut history = SnapshotHistory::new(2);
+        history.push(1, (0..20_000).map(|id| (id, node(None, 0.0))).collect());
+
+        let e = history.replicate(None, &mut server).unwrap_err();
+        assert!(e
+            .get_ref()
+            .is_some_and(|e| e.is::<message::FrameTooLarge>()));
+        assert!(client.recv().is_none());
+    }
+}
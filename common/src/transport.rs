@@ -0,0 +1,59 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::message::Message;
+
+/// Something messages can be pushed into, a socket or an in-process channel.
+pub trait Transport {
+    fn send(&mut self, msg: Message) -> std::io::Result<()>;
+}
+
+/// One end of an in-process connection, see [`loopback`].
+pub struct Loopback {
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+    /// Drops every n-th outgoing message to simulate packet loss.
+    pub drop_every: Option<u32>,
+    sent: u32,
+}
+
+impl Loopback {
+    pub fn recv(&mut self) -> Option<Message> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, msg: Message) -> std::io::Result<()> {
+        self.sent += 1;
+        if let Some(n) = self.drop_every {
+            if n > 0 && self.sent.is_multiple_of(n) {
+                return Ok(());
+            }
+        }
+
+        self.tx
+            .send(msg)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+}
+
+/// Two connected ends, what one sends the other receives.
+pub fn loopback() -> (Loopback, Loopback) {
+    let (atx, arx) = mpsc::channel();
+    let (btx, brx) = mpsc::channel();
+
+    (
+        Loopback {
+            tx: atx,
+            rx: brx,
+            drop_every: None,
+            sent: 0,
+        },
+        Loopback {
+            tx: btx,
+            rx: arx,
+            drop_every: None,
+            sent: 0,
+        },
+    )
+}
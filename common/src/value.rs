@@ -0,0 +1,200 @@
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Read},
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Tables nested deeper than this are refused, they are most likely a
+/// mistake. Reading refuses them too, a peer could send enough nesting to
+/// overflow the stack otherwise.
+pub const MAX_DEPTH: usize = 32;
+
+/// Plain data a package can hand to another package, the other side of the
+/// network or the disk. It mirrors what a Luau table can hold without
+/// functions, userdata or cycles.
+#[derive(BorshSerialize, Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    /// Never NaN, Borsh refuses to write those.
+    Number(f64),
+    String(String),
+    /// A table with the keys `1..n` and nothing else.
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+        borsh::to_vec(self)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> std::io::Result<Self> {
+        Self::try_from_slice(data)
+    }
+}
+
+impl BorshDeserialize for Value {
+    /// Reads what the derived `BorshSerialize` writes, one tag per variant
+    /// in the order they are declared.
+    fn deserialize_reader<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        read(reader, 0)
+    }
+}
+
+fn read<R: Read>(reader: &mut R, depth: usize) -> std::io::Result<Value> {
+    let tag = u8::deserialize_reader(reader)?;
+    if matches!(tag, 4 | 5) && depth >= MAX_DEPTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "value is nested too deep",
+        ));
+    }
+
+    Ok(match tag {
+        0 => Value::Nil,
+        1 => Value::Boolean(bool::deserialize_reader(reader)?),
+        2 => Value::Number(f64::deserialize_reader(reader)?),
+        3 => Value::String(String::deserialize_reader(reader)?),
+        4 => {
+            let len = u32::deserialize_reader(reader)?;
+            // no capacity up front, the length could be made up
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(read(reader, depth + 1)?);
+            }
+            Value::Array(items)
+        }
+        5 => {
+            let len = u32::deserialize_reader(reader)?;
+            let mut entries = Vec::new();
+            for _ in 0..len {
+                entries.push((read(reader, depth + 1)?, read(reader, depth + 1)?));
+            }
+            Value::Map(entries)
+        }
+        tag => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown value tag {}", tag),
+            ))
+        }
+    })
+}
+
+impl Display for Value {
+    /// Written like a Luau literal, strings at the top stay bare like `print` does.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn literal(v: &Value, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match v {
+                Value::String(s) => write!(f, "{:?}", s),
+                v => write!(f, "{}", v),
+            }
+        }
+
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Array(items) => {
+                write!(f, "{{")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    literal(item, f)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match k {
+                        Value::String(s) if is_name(s) => write!(f, "{} = ", s)?,
+                        k => {
+                            write!(f, "[")?;
+                            literal(k, f)?;
+                            write!(f, "] = ")?;
+                        }
+                    }
+                    literal(v, f)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Whether `s` can be written as a key without brackets.
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `depth` arrays, one inside the other.
+    fn nested(depth: usize) -> Value {
+        (0..depth).fold(Value::Nil, |v, _| Value::Array(vec![v]))
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::Map(vec![
+            (Value::String("name".into()), Value::String("bed".into())),
+            (Value::Number(1.5), Value::Boolean(true)),
+            (
+                Value::String("list".into()),
+                Value::Array(vec![Value::Nil, Value::Number(-3.0), nested(3)]),
+            ),
+        ]);
+        let data = value.to_bytes().unwrap();
+        assert_eq!(Value::from_bytes(&data).unwrap(), value);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deepest = nested(MAX_DEPTH);
+        assert_eq!(
+            Value::from_bytes(&deepest.to_bytes().unwrap()).unwrap(),
+            deepest
+        );
+
+        let e = Value::from_bytes(&nested(MAX_DEPTH + 1).to_bytes().unwrap()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn deeply_nested_input_does_not_overflow() {
+        // about a frame full of arrays that each hold the next one
+        let mut data = Vec::new();
+        for _ in 0..200_000 {
+            data.extend_from_slice(&[4, 1, 0, 0, 0]);
+        }
+        data.push(0);
+
+        let e = Value::from_bytes(&data).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // `Message::Package` with an empty name
+        let mut message = vec![2, 0, 0, 0, 0];
+        message.extend_from_slice(&data);
+        assert!(crate::message::decode(&message).is_err());
+    }
+
+    #[test]
+    fn unknown_tags_are_refused() {
+        assert!(Value::from_bytes(&[6]).is_err());
+        assert!(Value::from_bytes(&[4, 2, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -2,7 +2,30 @@ use std::fmt::Display;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
-#[derive(BorshDeserialize, BorshSerialize, Clone)]
+/// How two versions relate to each other during the handshake.
+///
+/// Major and minor must match, they change the wire format. A different
+/// patch level is tolerated, patch releases must not touch `Message`.
+/// Target, branch and commit are informational only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    /// Same release built from the same sources for the same target.
+    Identical,
+    /// Same release, but a different target, branch or commit.
+    SameRelease,
+    /// Only the patch level differs.
+    PatchMismatch,
+    /// Major or minor differ, the peers can not talk to each other.
+    Incompatible,
+}
+
+impl Compatibility {
+    pub fn is_compatible(self) -> bool {
+        !matches!(self, Compatibility::Incompatible)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
 pub struct Version {
     major: u16,
     minor: u16,
@@ -12,6 +35,20 @@ pub struct Version {
     commit: String,
 }
 
+impl Version {
+    pub fn compatibility(&self, o: &Self) -> Compatibility {
+        if self.major != o.major || self.minor != o.minor {
+            Compatibility::Incompatible
+        } else if self.patch != o.patch {
+            Compatibility::PatchMismatch
+        } else if self != o {
+            Compatibility::SameRelease
+        } else {
+            Compatibility::Identical
+        }
+    }
+}
+
 impl Default for Version {
     fn default() -> Self {
         Self {
@@ -25,14 +62,6 @@ impl Default for Version {
     }
 }
 
-impl Eq for Version {}
-
-impl PartialEq for Version {
-    fn eq(&self, o: &Self) -> bool {
-        self.major == o.major && self.minor == o.minor && self.patch == o.patch
-    }
-}
-
 impl Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -42,3 +71,78 @@ impl Display for Version {
         )
     }
 }
+
+/// Capabilities both sides offered, in the order of `ours`.
+pub fn negotiate(ours: &[String], theirs: &[String]) -> Vec<String> {
+    ours.iter()
+        .filter(|c| theirs.contains(c))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u16, minor: u16, patch: u16, commit: &str) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+            target: "x86_64-unknown-linux-gnu".into(),
+            branch: "main".into(),
+            commit: commit.into(),
+        }
+    }
+
+    #[test]
+    fn same_build_is_identical() {
+        let v = version(1, 2, 3, "abc");
+        assert_eq!(v.compatibility(&v.clone()), Compatibility::Identical);
+    }
+
+    #[test]
+    fn other_commit_is_the_same_release() {
+        let a = version(1, 2, 3, "abc");
+        let b = version(1, 2, 3, "def");
+        assert_eq!(a.compatibility(&b), Compatibility::SameRelease);
+        assert!(a.compatibility(&b).is_compatible());
+    }
+
+    #[test]
+    fn patch_level_may_differ() {
+        let a = version(1, 2, 3, "abc");
+        let b = version(1, 2, 4, "abc");
+        assert_eq!(a.compatibility(&b), Compatibility::PatchMismatch);
+        assert_eq!(b.compatibility(&a), Compatibility::PatchMismatch);
+        assert!(a.compatibility(&b).is_compatible());
+    }
+
+    #[test]
+    fn major_and_minor_must_match() {
+        let a = version(1, 2, 3, "abc");
+        for b in [version(2, 2, 3, "abc"), version(1, 3, 3, "abc")] {
+            assert_eq!(a.compatibility(&b), Compatibility::Incompatible);
+            assert_eq!(b.compatibility(&a), Compatibility::Incompatible);
+            assert!(!a.compatibility(&b).is_compatible());
+        }
+    }
+
+    fn list(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiation_keeps_what_both_offer_in_our_order() {
+        let ours = list(&["snapshot", "compression", "voice"]);
+        let theirs = list(&["voice", "snapshot", "telemetry"]);
+        assert_eq!(negotiate(&ours, &theirs), list(&["snapshot", "voice"]));
+    }
+
+    #[test]
+    fn negotiation_without_overlap_is_empty() {
+        assert!(negotiate(&list(&["snapshot"]), &list(&["voice"])).is_empty());
+        assert!(negotiate(&[], &list(&["voice"])).is_empty());
+        assert!(negotiate(&list(&["snapshot"]), &[]).is_empty());
+    }
+}
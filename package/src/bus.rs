@@ -0,0 +1,486 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::mpsc::Sender,
+};
+
+use common::value::Value;
+use mlua::{Function, Lua, MultiValue, Table, Thread};
+
+use crate::{
+    error::PackageError,
+    task,
+    value::{from_value, to_value},
+    Event, Package, Service,
+};
+
+const TOPICS: &str = "BusTopics";
+const SERVICES: &str = "BusServices";
+/// Coroutines waiting in `Bus.call`, by call id.
+const CALLS: &str = "BusCalls";
+
+/// What a package asks of the bus, the host passes it on with [`Bus::route`].
+pub enum BusMessage {
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish(String, Value),
+    Provide(String),
+    /// Asks a service, the answer or what went wrong goes back to the
+    /// caller as an event with the same call id.
+    Call(String, Value, u64),
+}
+
+/// Host side of the message bus, it knows who listens to which topic and
+/// who provides which service.
+#[derive(Default)]
+pub struct Bus {
+    topics: BTreeMap<String, BTreeSet<String>>,
+    services: BTreeMap<String, String>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops the subscriptions and services of a package that went away.
+    pub fn forget(&mut self, name: &str) {
+        for names in self.topics.values_mut() {
+            names.remove(name);
+        }
+        self.services.retain(|_, owner| owner != name);
+    }
+
+    /// Delivers everything the packages put on the bus since the last call.
+    /// Packages that ended, like by crashing, are forgotten along with what
+    /// they still had to say.
+    pub fn route<M: Service>(&mut self, packages: &[Package<M>]) {
+        for pk in packages {
+            if pk.state().has_ended() {
+                self.forget(&pk.name);
+                while pk.bus_rx.try_recv().is_ok() {}
+                continue;
+            }
+            while let Ok(msg) = pk.bus_rx.try_recv() {
+                self.handle(packages, &pk.name, msg);
+            }
+        }
+    }
+
+    fn handle<M: Service>(&mut self, packages: &[Package<M>], from: &str, msg: BusMessage) {
+        match msg {
+            BusMessage::Subscribe(topic) => {
+                self.topics.entry(topic).or_default().insert(from.into());
+            }
+            BusMessage::Unsubscribe(topic) => {
+                if let Some(names) = self.topics.get_mut(&topic) {
+                    names.remove(from);
+                }
+            }
+            BusMessage::Publish(topic, data) => {
+                let Some(names) = self.topics.get(&topic) else {
+                    return;
+                };
+                for pk in packages.iter().filter(|p| names.contains(&p.name)) {
+                    let _ = pk
+                        .event_tx
+                        .send(deliver(topic.clone(), data.clone(), from.into()));
+                }
+            }
+            BusMessage::Provide(service) => match self.services.get(&service) {
+                Some(owner) if owner != from => {
+                    println!(
+                        "PACKAGE {}: service {} is already provided by {}",
+                        from, service, owner
+                    );
+                }
+                _ => {
+                    self.services.insert(service, from.into());
+                }
+            },
+            BusMessage::Call(service, request, id) => {
+                let Some(caller) = packages.iter().find(|p| p.name == from) else {
+                    return;
+                };
+                let reply = Answer {
+                    id,
+                    tx: Some(caller.event_tx.clone()),
+                };
+                let provider = self
+                    .services
+                    .get(&service)
+                    .and_then(|owner| packages.iter().find(|p| p.name == *owner));
+                match provider {
+                    // if it never gets to the call, dropping it answers
+                    Some(pk) => {
+                        let _ = pk
+                            .event_tx
+                            .send(serve(service, request, from.into(), reply));
+                    }
+                    None => reply.send(Err(format!("no package provides {}", service))),
+                }
+            }
+        }
+    }
+}
+
+/// Calls the handlers `from` subscribed to `topic`.
+fn deliver(topic: String, data: Value, from: String) -> Event {
+    Box::new(move |lua| {
+        let topics: Table = lua.named_registry_value(TOPICS)?;
+        let Some(handlers) = topics.get::<Option<Table>>(topic)? else {
+            return Ok(());
+        };
+        for handler in handlers.sequence_values::<Function>() {
+            handler?.call::<()>((from_value(lua, &data)?, from.as_str()))?;
+        }
+        Ok(())
+    })
+}
+
+/// The way back to the coroutine that made the call `id`. Every call gets
+/// an answer, one that is dropped unsent fails the call. That happens when
+/// the provider crashes, stops or restarts before it got to the call.
+struct Answer {
+    id: u64,
+    tx: Option<Sender<Event>>,
+}
+
+impl Answer {
+    fn send(mut self, answer: Result<Value, String>) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(answered(self.id, answer));
+        }
+    }
+}
+
+impl Drop for Answer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let msg = "the provider went away before it answered".to_string();
+            let _ = tx.send(answered(self.id, Err(msg)));
+        }
+    }
+}
+
+/// Answers a call, a failing handler only fails the call and not the package.
+fn serve(service: String, request: Value, from: String, reply: Answer) -> Event {
+    Box::new(move |lua| {
+        let answer = answer(lua, &service, &request, &from).map_err(|e| match e {
+            mlua::Error::RuntimeError(msg) => msg,
+            e => e.to_string(),
+        });
+        reply.send(answer);
+        Ok(())
+    })
+}
+
+/// Resumes the coroutine that made the call `id` with the answer.
+fn answered(id: u64, answer: Result<Value, String>) -> Event {
+    Box::new(move |lua| {
+        let calls: Table = lua.named_registry_value(CALLS)?;
+        let Some(thread) = calls.get::<Option<Thread>>(id)? else {
+            return Ok(());
+        };
+        calls.set(id, mlua::Value::Nil)?;
+
+        let result = answer
+            .map_err(mlua::Error::runtime)
+            .and_then(|v| from_value(lua, &v))
+            .map(|v| MultiValue::from_iter([v]));
+        task::answer(thread, result)
+    })
+}
+
+fn answer(lua: &Lua, service: &str, request: &Value, from: &str) -> mlua::Result<Value> {
+    let services: Table = lua.named_registry_value(SERVICES)?;
+    let Some(handler) = services.get::<Option<Function>>(service)? else {
+        return Err(mlua::Error::runtime(format!("{} is not provided", service)));
+    };
+    to_value(handler.call((from_value(lua, request)?, from))?)
+}
+
+fn post(tx: &Sender<BusMessage>, msg: BusMessage) -> mlua::Result<()> {
+    tx.send(msg)
+        .map_err(|_| mlua::Error::runtime("the message bus is gone"))
+}
+
+/// Creates the `Bus` global, everything is passed to the host on `tx`.
+pub fn install(lua: &Lua, tx: Sender<BusMessage>) -> Result<(), PackageError> {
+    lua.set_named_registry_value(TOPICS, lua.create_table()?)?;
+    lua.set_named_registry_value(SERVICES, lua.create_table()?)?;
+    lua.set_named_registry_value(CALLS, lua.create_table()?)?;
+
+    let bus = lua.create_table()?;
+
+    let ttx = tx.clone();
+    bus.set(
+        "subscribe",
+        lua.create_function(move |lua, (topic, handler): (String, Function)| {
+            let topics: Table = lua.named_registry_value(TOPICS)?;
+            let handlers = match topics.get::<Option<Table>>(topic.as_str())? {
+                Some(h) => h,
+                None => {
+                    let h = lua.create_table()?;
+                    topics.set(topic.as_str(), &h)?;
+                    post(&ttx, BusMessage::Subscribe(topic))?;
+                    h
+                }
+            };
+            handlers.push(handler)
+        })?,
+    )?;
+
+    let ttx = tx.clone();
+    bus.set(
+        "unsubscribe",
+        lua.create_function(move |lua, topic: String| {
+            let topics: Table = lua.named_registry_value(TOPICS)?;
+            topics.set(topic.as_str(), mlua::Value::Nil)?;
+            post(&ttx, BusMessage::Unsubscribe(topic))
+        })?,
+    )?;
+
+    let ttx = tx.clone();
+    bus.set(
+        "publish",
+        lua.create_function(move |_lua, (topic, data): (String, mlua::Value)| {
+            post(&ttx, BusMessage::Publish(topic, to_value(data)?))
+        })?,
+    )?;
+
+    let ttx = tx.clone();
+    bus.set(
+        "provide",
+        lua.create_function(move |lua, (service, handler): (String, Function)| {
+            let services: Table = lua.named_registry_value(SERVICES)?;
+            services.set(service.as_str(), handler)?;
+            post(&ttx, BusMessage::Provide(service))
+        })?,
+    )?;
+
+    // the coroutine waits for the answer, the package goes on meanwhile
+    let mut last_id = 0u64;
+    let call = lua.create_function_mut(move |lua, (service, data): (String, mlua::Value)| {
+        let request = to_value(data)?;
+
+        // the host would hand it back to us while we wait
+        let services: Table = lua.named_registry_value(SERVICES)?;
+        if services.contains_key(service.as_str())? {
+            let name = crate::name(lua)?;
+            let answer = from_value(lua, &answer(lua, &service, &request, &name)?)?;
+            return Ok(MultiValue::from_iter([answer]));
+        }
+
+        if !task::in_coroutine(lua) {
+            return Err(mlua::Error::runtime(
+                "Bus.call only works inside a coroutine",
+            ));
+        }
+        last_id += 1;
+        post(&tx, BusMessage::Call(service, request, last_id))?;
+        let calls: Table = lua.named_registry_value(CALLS)?;
+        calls.set(last_id, lua.current_thread())?;
+        task::pending(lua)
+    })?;
+    bus.set("call", task::awaitable(lua, call)?)?;
+
+    lua.globals().set("Bus", bus)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::{
+        scratch::{wait_until, Nothing, Scratch},
+        PackageState,
+    };
+
+    fn package(name: &str) -> (Lua, Receiver<BusMessage>) {
+        let lua = Lua::new();
+        let (tx, rx) = mpsc::channel();
+        task::install(&lua).unwrap();
+        install(&lua, tx).unwrap();
+        lua.set_named_registry_value("Name", name).unwrap();
+        (lua, rx)
+    }
+
+    /// Lets `provider` answer the call `caller` put on the bus and hands
+    /// the answer back like the host would.
+    fn route(caller: &Lua, calls: &Receiver<BusMessage>, provider: &Lua) {
+        let Ok(BusMessage::Call(service, request, id)) = calls.try_recv() else {
+            panic!("no call on the bus");
+        };
+        let (tx, rx) = mpsc::channel();
+        let reply = Answer { id, tx: Some(tx) };
+        serve(service, request, "caller".into(), reply)(provider).unwrap();
+        rx.try_recv().unwrap()(caller).unwrap();
+        assert!(rx.try_recv().is_err(), "answered twice");
+    }
+
+    #[test]
+    fn calls_outside_a_coroutine_fail_at_once() {
+        let (lua, calls) = package("caller");
+        let e = lua.load("Bus.call('double', 1)").exec().unwrap_err();
+        assert!(e.to_string().contains("coroutine"), "{}", e);
+        assert!(calls.try_recv().is_err());
+    }
+
+    #[test]
+    fn calls_wait_in_their_coroutine() {
+        let (provider, _bus) = package("provider");
+        provider
+            .load("Bus.provide('double', function(n, from) return { n * 2, from } end)")
+            .exec()
+            .unwrap();
+
+        let (caller, calls) = package("caller");
+        caller
+            .load(
+                r#"
+                co = coroutine.create(function()
+                    result = Bus.call("double", 21)
+                end)
+                coroutine.resume(co)
+                "#,
+            )
+            .exec()
+            .unwrap();
+        let status: String = caller.load("return coroutine.status(co)").eval().unwrap();
+        assert_eq!(status, "suspended");
+
+        route(&caller, &calls, &provider);
+        let (n, from): (i64, String) = caller.load("return result[1], result[2]").eval().unwrap();
+        assert_eq!((n, from.as_str()), (42, "caller"));
+    }
+
+    #[test]
+    fn failing_handlers_fail_the_call() {
+        let (provider, _bus) = package("provider");
+        provider
+            .load("Bus.provide('fail', function() error('no way', 0) end)")
+            .exec()
+            .unwrap();
+
+        let (caller, calls) = package("caller");
+        caller
+            .load(
+                r#"
+                coroutine.resume(coroutine.create(function()
+                    ok, err = pcall(Bus.call, "fail", 1)
+                end))
+                "#,
+            )
+            .exec()
+            .unwrap();
+
+        route(&caller, &calls, &provider);
+        let (ok, err): (bool, String) = caller.load("return ok, tostring(err)").eval().unwrap();
+        assert!(!ok);
+        assert!(err.contains("no way"), "{}", err);
+    }
+
+    #[test]
+    fn own_services_answer_right_away() {
+        let (lua, calls) = package("caller");
+        let n: i64 = lua
+            .load(
+                r#"
+                Bus.provide("double", function(n) return n * 2 end)
+                return Bus.call("double", 4)
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(n, 8);
+        assert!(!calls.try_iter().any(|m| matches!(m, BusMessage::Call(..))));
+    }
+
+    /// Starts a call to `double` in a coroutine of `caller`, what it gets
+    /// ends up in `ok` and `err`.
+    fn call_double(caller: &Lua) {
+        caller
+            .load(
+                r#"
+                coroutine.resume(coroutine.create(function()
+                    ok, err = pcall(Bus.call, "double", 1)
+                end))
+                "#,
+            )
+            .exec()
+            .unwrap();
+    }
+
+    #[test]
+    fn calls_fail_when_the_provider_dies_before_answering() {
+        let (caller, calls) = package("caller");
+        call_double(&caller);
+        let Ok(BusMessage::Call(service, request, id)) = calls.try_recv() else {
+            panic!("no call on the bus");
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let serving = serve(
+            service,
+            request,
+            "caller".into(),
+            Answer { id, tx: Some(tx) },
+        );
+        // the provider crashed with the call still in its queue
+        drop(serving);
+        rx.try_recv().unwrap()(&caller).unwrap();
+
+        let (ok, err): (bool, String) = caller.load("return ok, tostring(err)").eval().unwrap();
+        assert!(!ok);
+        assert!(err.contains("went away"), "{}", err);
+        let calls: Table = caller.named_registry_value(CALLS).unwrap();
+        assert_eq!(calls.pairs::<u64, Thread>().count(), 0);
+    }
+
+    /// Routes until `done` or a few seconds passed.
+    fn route_until(bus: &mut Bus, packages: &[Package<Nothing>], done: impl Fn() -> bool) {
+        wait_until(|| {
+            bus.route(packages);
+            done()
+        });
+    }
+
+    #[test]
+    fn crashed_providers_are_forgotten() {
+        let provider = Scratch::package("bus-provider", "");
+        provider.write(
+            "index.luau",
+            r#"
+            Bus.provide("double", function(n) return n * 2 end)
+            error("boom")
+            "#,
+        );
+        let caller = Scratch::package("bus-caller", "");
+        caller.write(
+            "index.luau",
+            r#"
+            function OnStart()
+                coroutine.resume(coroutine.create(function()
+                    local ok, err = pcall(Bus.call, "double", 1)
+                    error(if ok then "answered" else err, 0)
+                end))
+            end
+            "#,
+        );
+
+        let mut bus = Bus::new();
+        let mut packages = vec![Package::load(provider.manifest(), |_| Ok(())).unwrap()];
+        route_until(&mut bus, &packages, || packages[0].state().has_ended());
+        assert!(bus.services.is_empty());
+
+        packages.push(Package::load(caller.manifest(), |_| Ok(())).unwrap());
+        route_until(&mut bus, &packages, || packages[1].state().has_ended());
+        let PackageState::Crashed(msg) = packages[1].state() else {
+            panic!("the caller did not get an answer");
+        };
+        assert!(msg.contains("no package provides double"), "{}", msg);
+    }
+}
@@ -0,0 +1,480 @@
+use std::fmt::Write;
+
+use mlua::{
+    AnyUserData, FromLua, FromLuaMulti, Function, IntoLua, IntoLuaMulti, Lua, MaybeSend, UserData,
+    UserDataFields, UserDataMethods,
+};
+
+/// Looks a member up without failing, userdata raises errors for unknown ones.
+const PROBE: &str = r#"
+return function(value, key)
+    local ok, member = pcall(function()
+        return value[key]
+    end)
+    return ok and member ~= nil
+end
+"#;
+
+/// A userdata type, methods are called with `:`.
+pub struct Class {
+    name: String,
+    fields: Vec<(String, String)>,
+    methods: Vec<(String, String, String)>,
+}
+
+impl Class {
+    /// A field, or a function called with `.` like `Game.after`.
+    pub fn field(&mut self, name: &str, ty: &str) -> &mut Self {
+        self.fields.push((name.to_string(), ty.to_string()));
+        self
+    }
+
+    pub fn method(&mut self, name: &str, params: &str, ret: &str) -> &mut Self {
+        self.methods
+            .push((name.to_string(), params.to_string(), ret.to_string()));
+        self
+    }
+}
+
+/// The names a userdata type registers, found by letting it register its
+/// fields and methods here instead of with Luau. Metamethods are left out.
+struct Members(Vec<String>);
+
+impl Members {
+    fn of<T: UserData>() -> Vec<String> {
+        let mut members = Members(Vec::new());
+        T::add_fields(&mut members);
+        T::add_methods(&mut members);
+        members.0
+    }
+
+    fn add(&mut self, name: impl ToString) {
+        self.0.push(name.to_string());
+    }
+}
+
+impl<T> UserDataFields<T> for Members {
+    fn add_field<V>(&mut self, name: impl ToString, _value: V)
+    where
+        V: IntoLua + 'static,
+    {
+        self.add(name);
+    }
+
+    fn add_field_method_get<M, R>(&mut self, name: impl ToString, _method: M)
+    where
+        M: Fn(&Lua, &T) -> mlua::Result<R> + MaybeSend + 'static,
+        R: IntoLua,
+    {
+        self.add(name);
+    }
+
+    fn add_field_method_set<M, A>(&mut self, name: impl ToString, _method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> mlua::Result<()> + MaybeSend + 'static,
+        A: FromLua,
+    {
+        self.add(name);
+    }
+
+    fn add_field_function_get<F, R>(&mut self, name: impl ToString, _function: F)
+    where
+        F: Fn(&Lua, AnyUserData) -> mlua::Result<R> + MaybeSend + 'static,
+        R: IntoLua,
+    {
+        self.add(name);
+    }
+
+    fn add_field_function_set<F, A>(&mut self, name: impl ToString, _function: F)
+    where
+        F: FnMut(&Lua, AnyUserData, A) -> mlua::Result<()> + MaybeSend + 'static,
+        A: FromLua,
+    {
+        self.add(name);
+    }
+
+    fn add_meta_field<V>(&mut self, _name: impl ToString, _value: V)
+    where
+        V: IntoLua + 'static,
+    {
+    }
+
+    fn add_meta_field_with<F, R>(&mut self, _name: impl ToString, _f: F)
+    where
+        F: FnOnce(&Lua) -> mlua::Result<R> + 'static,
+        R: IntoLua,
+    {
+    }
+}
+
+impl<T> UserDataMethods<T> for Members {
+    fn add_method<M, A, R>(&mut self, name: impl ToString, _method: M)
+    where
+        M: Fn(&Lua, &T, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.add(name);
+    }
+
+    fn add_method_mut<M, A, R>(&mut self, name: impl ToString, _method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.add(name);
+    }
+
+    fn add_function<F, A, R>(&mut self, name: impl ToString, _function: F)
+    where
+        F: Fn(&Lua, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.add(name);
+    }
+
+    fn add_function_mut<F, A, R>(&mut self, name: impl ToString, _function: F)
+    where
+        F: FnMut(&Lua, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.add(name);
+    }
+
+    fn add_meta_method<M, A, R>(&mut self, _name: impl ToString, _method: M)
+    where
+        M: Fn(&Lua, &T, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+    }
+
+    fn add_meta_method_mut<M, A, R>(&mut self, _name: impl ToString, _method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+    }
+
+    fn add_meta_function<F, A, R>(&mut self, _name: impl ToString, _function: F)
+    where
+        F: Fn(&Lua, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+    }
+
+    fn add_meta_function_mut<F, A, R>(&mut self, _name: impl ToString, _function: F)
+    where
+        F: FnMut(&Lua, A) -> mlua::Result<R> + MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+    }
+}
+
+/// A global table of functions like `Scene` with `Scene.new`.
+pub struct Library {
+    name: String,
+    functions: Vec<(String, String, String)>,
+}
+
+impl Library {
+    pub fn function(&mut self, name: &str, params: &str, ret: &str) -> &mut Self {
+        self.functions
+            .push((name.to_string(), params.to_string(), ret.to_string()));
+        self
+    }
+}
+
+enum Global {
+    Value(String, String),
+    Function(String, String, String),
+    Library(Library),
+    /// Defined by the package and called by the host.
+    Callback(String, String),
+}
+
+/// What a package sees of its host, written as a Luau definition file for
+/// editors and the type checker.
+pub struct Definitions {
+    classes: Vec<Class>,
+    globals: Vec<Global>,
+}
+
+impl Default for Definitions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Definitions {
+    /// Starts with what every package gets, whichever host runs it.
+    pub fn new() -> Self {
+        let mut defs = Self {
+            classes: Vec::new(),
+            globals: Vec::new(),
+        };
+
+        defs.function("print", "...: any", "()")
+            .function("require", "name: string", "any")
+            .function("wait", "seconds: number?", "number")
+            .value("Name", "string");
+        defs.library("Bus")
+            .function(
+                "subscribe",
+                "topic: string, handler: (data: any, from: string) -> ()",
+                "()",
+            )
+            .function("unsubscribe", "topic: string", "()")
+            .function("publish", "topic: string, data: any", "()")
+            .function(
+                "provide",
+                "service: string, handler: (request: any, from: string) -> any",
+                "()",
+            )
+            .function("call", "service: string, request: any", "any");
+        defs.library("Storage")
+            .function("get", "key: string", "any")
+            .function("set", "key: string, value: any", "()")
+            .function("delete", "key: string", "()")
+            .function("list", "", "{ string }");
+        defs.callback("OnStart", "()")
+            .callback("OnUpdate", "(dt: number) -> ()")
+            .callback("OnReload", "(state: any) -> ()")
+            .callback("OnUnload", "() -> any");
+        defs
+    }
+
+    pub fn class(&mut self, name: &str) -> &mut Class {
+        self.classes.push(Class {
+            name: name.to_string(),
+            fields: Vec::new(),
+            methods: Vec::new(),
+        });
+        self.classes.last_mut().unwrap()
+    }
+
+    pub fn library(&mut self, name: &str) -> &mut Library {
+        self.globals.push(Global::Library(Library {
+            name: name.to_string(),
+            functions: Vec::new(),
+        }));
+        let Some(Global::Library(library)) = self.globals.last_mut() else {
+            unreachable!()
+        };
+        library
+    }
+
+    pub fn value(&mut self, name: &str, ty: &str) -> &mut Self {
+        self.globals
+            .push(Global::Value(name.to_string(), ty.to_string()));
+        self
+    }
+
+    pub fn function(&mut self, name: &str, params: &str, ret: &str) -> &mut Self {
+        self.globals.push(Global::Function(
+            name.to_string(),
+            params.to_string(),
+            ret.to_string(),
+        ));
+        self
+    }
+
+    /// A global function the host calls if the package defines it. `()`
+    /// stands for a callback without arguments that returns nothing.
+    pub fn callback(&mut self, name: &str, ty: &str) -> &mut Self {
+        let ty = match ty {
+            "()" => "() -> ()",
+            ty => ty,
+        };
+        self.globals
+            .push(Global::Callback(name.to_string(), ty.to_string()));
+        self
+    }
+
+    /// The definition file, `tool` is the command that wrote it.
+    pub fn to_luau(&self, tool: &str) -> String {
+        let mut out = format!("-- Written by `{}`, do not edit.\n", tool);
+
+        for class in self.classes.iter() {
+            let _ = writeln!(out, "\ndeclare class {}", class.name);
+            for (name, ty) in class.fields.iter() {
+                let _ = writeln!(out, "    {}: {}", name, ty);
+            }
+            for (name, params, ret) in class.methods.iter() {
+                let params = match params.as_str() {
+                    "" => "self".to_string(),
+                    params => format!("self, {}", params),
+                };
+                let _ = writeln!(out, "    function {}({}): {}", name, params, ret);
+            }
+            out.push_str("end\n");
+        }
+
+        out.push('\n');
+        for global in self.globals.iter() {
+            match global {
+                Global::Value(name, ty) => {
+                    let _ = writeln!(out, "declare {}: {}", name, ty);
+                }
+                Global::Function(name, params, ret) => {
+                    let _ = writeln!(out, "declare function {}({}): {}", name, params, ret);
+                }
+                Global::Library(library) => {
+                    let _ = writeln!(out, "declare {}: {{", library.name);
+                    for (name, params, ret) in library.functions.iter() {
+                        let _ = writeln!(out, "    {}: ({}) -> {},", name, params, ret);
+                    }
+                    out.push_str("}\n");
+                }
+                Global::Callback(..) => {}
+            }
+        }
+
+        out.push_str("\n-- called by the host when the package defines them\n");
+        for global in self.globals.iter() {
+            if let Global::Callback(name, ty) = global {
+                let _ = writeln!(out, "declare {}: {}", name, ty);
+            }
+        }
+
+        out
+    }
+
+    /// Compares the definitions with the globals in `lua`, which has to be
+    /// set up like a package, and with `samples` of the classes that are no
+    /// globals. Returns what does not match. Members of userdata the
+    /// definitions do not know about can not be found this way, for those
+    /// there is `check_class`.
+    pub fn check(&self, lua: &Lua, samples: Vec<(&str, mlua::Value)>) -> mlua::Result<Vec<String>> {
+        let mut drift = Vec::new();
+        let probe: Function = lua.load(PROBE).set_name("=probe").call(())?;
+        let has = |value: &mlua::Value, key: &str| probe.call::<bool>((value, key));
+
+        // the standard library is what a bare state has
+        let builtins: Vec<String> = Lua::new()
+            .globals()
+            .pairs::<String, mlua::Value>()
+            .filter_map(|p| p.ok().map(|(k, _)| k))
+            .collect();
+        for pair in lua.globals().pairs::<String, mlua::Value>() {
+            let (name, _) = pair?;
+            if !builtins.contains(&name) && !self.declares(&name) {
+                drift.push(format!("global {} is not declared", name));
+            }
+        }
+
+        let mut instances = samples;
+        for global in self.globals.iter() {
+            let (name, value) = match global {
+                Global::Value(name, ty) => {
+                    let value: mlua::Value = lua.globals().get(name.as_str())?;
+                    if self.classes.iter().any(|c| c.name == *ty) {
+                        instances.push((ty, value.clone()));
+                    }
+                    (name, value)
+                }
+                Global::Function(name, ..) | Global::Library(Library { name, .. }) => {
+                    (name, lua.globals().get(name.as_str())?)
+                }
+                Global::Callback(..) => continue,
+            };
+            if value.is_nil() {
+                drift.push(format!("global {} is declared but missing", name));
+                continue;
+            }
+
+            let Global::Library(library) = global else {
+                continue;
+            };
+            let Some(table) = value.as_table() else {
+                drift.push(format!("global {} is no table", name));
+                continue;
+            };
+            for (function, ..) in library.functions.iter() {
+                if !table.contains_key(function.as_str())? {
+                    drift.push(format!("{}.{} is declared but missing", name, function));
+                }
+            }
+            for pair in table.clone().pairs::<String, mlua::Value>() {
+                let (key, _) = pair?;
+                if !library.functions.iter().any(|(f, ..)| *f == key) {
+                    drift.push(format!("{}.{} is not declared", name, key));
+                }
+            }
+        }
+
+        for (name, value) in instances {
+            let Some(class) = self.classes.iter().find(|c| c.name == name) else {
+                drift.push(format!("class {} is not declared", name));
+                continue;
+            };
+            let members = class.fields.iter().map(|(f, _)| f);
+            for member in members.chain(class.methods.iter().map(|(m, ..)| m)) {
+                if !has(&value, member)? {
+                    drift.push(format!("{}.{} is declared but missing", name, member));
+                }
+            }
+        }
+
+        for class in self.classes.iter() {
+            if !self.uses(&class.name) {
+                drift.push(format!("class {} is never used", class.name));
+            }
+        }
+
+        Ok(drift)
+    }
+
+    /// The members of `T` the class `name` does not declare.
+    pub fn check_class<T: UserData>(&self, name: &str) -> Vec<String> {
+        let Some(class) = self.classes.iter().find(|c| c.name == name) else {
+            return vec![format!("class {} is not declared", name)];
+        };
+        Members::of::<T>()
+            .into_iter()
+            .filter(|m| {
+                !class.fields.iter().any(|(f, _)| f == m)
+                    && !class.methods.iter().any(|(f, ..)| f == m)
+            })
+            .map(|m| format!("{}.{} is not declared", name, m))
+            .collect()
+    }
+
+    fn declares(&self, name: &str) -> bool {
+        self.globals.iter().any(|g| match g {
+            Global::Value(n, _)
+            | Global::Function(n, ..)
+            | Global::Library(Library { name: n, .. })
+            | Global::Callback(n, _) => n == name,
+        })
+    }
+
+    /// Whether any signature mentions the class `name`.
+    fn uses(&self, name: &str) -> bool {
+        let mentions = |ty: &str| {
+            ty.split(|c: char| !c.is_alphanumeric() && c != '_')
+                .any(|word| word == name)
+        };
+        let in_classes = self.classes.iter().any(|c| {
+            c.fields.iter().any(|(_, ty)| mentions(ty))
+                || c.methods
+                    .iter()
+                    .any(|(_, params, ret)| mentions(params) || mentions(ret))
+        });
+        let in_globals = self.globals.iter().any(|g| match g {
+            Global::Value(_, ty) | Global::Callback(_, ty) => mentions(ty),
+            Global::Function(_, params, ret) => mentions(params) || mentions(ret),
+            Global::Library(library) => library
+                .functions
+                .iter()
+                .any(|(_, params, ret)| mentions(params) || mentions(ret)),
+        });
+        in_classes || in_globals
+    }
+}
@@ -1,8 +1,15 @@
 use std::{error::Error, fmt::Display};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PackageErrorKind {
     NotAPackage,
+    ModuleNotFound,
+    CyclicRequire,
+    EscapingPath,
+    Manifest,
+    MissingDependency,
+    ConflictingDependency,
+    CyclicDependency,
     Lua,
     Io,
     Sync,
@@ -22,22 +29,86 @@ impl PackageError {
             msg: String::new(),
         }
     }
+
+    pub fn module_not_found(name: &str) -> Self {
+        Self {
+            kind: PackageErrorKind::ModuleNotFound,
+            msg: format!("module {} not found", name),
+        }
+    }
+
+    pub fn cyclic_require(chain: &[String]) -> Self {
+        Self {
+            kind: PackageErrorKind::CyclicRequire,
+            msg: format!("cyclic require {}", chain.join(" -> ")),
+        }
+    }
+
+    pub fn manifest(msg: String) -> Self {
+        Self {
+            kind: PackageErrorKind::Manifest,
+            msg,
+        }
+    }
+
+    pub fn missing_dependency(msg: String) -> Self {
+        Self {
+            kind: PackageErrorKind::MissingDependency,
+            msg,
+        }
+    }
+
+    pub fn conflicting_dependency(msg: String) -> Self {
+        Self {
+            kind: PackageErrorKind::ConflictingDependency,
+            msg,
+        }
+    }
+
+    pub fn cyclic_dependency(msg: String) -> Self {
+        Self {
+            kind: PackageErrorKind::CyclicDependency,
+            msg,
+        }
+    }
+
+    pub fn escaping_path(name: &str) -> Self {
+        Self {
+            kind: PackageErrorKind::EscapingPath,
+            msg: format!("module {} is outside of the package", name),
+        }
+    }
 }
 
 impl Error for PackageError {}
 
 impl Display for PackageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        // it ends up in front of Lua code, like when `require` fails
+        if self.msg.is_empty() {
+            write!(f, "{:?}", self.kind)
+        } else {
+            f.write_str(&self.msg)
+        }
     }
 }
 
 impl From<mlua::Error> for PackageError {
     fn from(value: mlua::Error) -> Self {
-        Self {
-            kind: PackageErrorKind::Lua,
-            msg: value.to_string(),
-        }
+        // our own errors raised inside Lua, like a failed require, keep their kind
+        let kind = value
+            .chain()
+            .find_map(|e| e.downcast_ref::<PackageError>())
+            .map(|e| e.kind)
+            .unwrap_or(PackageErrorKind::Lua);
+
+        // Luau has no message for these, the limit of a sandbox is the usual cause
+        let msg = match value {
+            mlua::Error::MemoryError(_) => "ran out of memory".to_string(),
+            value => value.to_string(),
+        };
+
+        Self { kind, msg }
     }
 }
 
@@ -1,138 +1,842 @@
 use std::{
-    path::PathBuf,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, Receiver, Sender},
-        Arc,
+        Arc, Mutex,
     },
-    time::{Duration, SystemTime},
+    thread::JoinHandle,
+    time::Instant,
 };
 
+use common::value::Value;
 use error::PackageError;
-use mlua::{Error, FromLua, Lua, UserData};
+use mlua::{AnyUserData, Error, FromLua, IntoLuaMulti, Lua, MultiValue, Thread, UserData};
+use sandbox::Budget;
+use schedule::Clock;
 
+mod bus;
+mod definitions;
 mod error;
+mod manifest;
+mod require;
+mod sandbox;
+mod schedule;
+#[cfg(test)]
+mod scratch;
+mod storage;
+mod task;
+mod testing;
+mod value;
+mod watch;
 
-pub struct App<M> {
-    service_tx: Sender<M>,
-    service_rx: Arc<Receiver<M>>,
+pub use bus::{Bus, BusMessage};
+pub use definitions::{Class, Definitions, Library};
+pub use manifest::{load_order, Manifest, Version, VersionReq};
+pub use sandbox::{allow, check_path, Capability, Sandbox, DEFAULT_MEMORY, DEFAULT_TIMEOUT};
+pub use schedule::{Schedule, UpdateStats, DEFAULT_RATE, MAX_CATCH_UP};
+pub use storage::{data_dir, DEFAULT_QUOTA};
+pub use task::{after, awaitable, awaitable_method, cancel, every};
+pub use testing::{
+    test, TestOptions, TestReport, TestResult, DEFAULT_TICKS, DEFAULT_TIMEOUT_TICKS,
+};
+pub use value::{from_value, to_value};
+pub use watch::Watcher;
+
+/// What a package can ask its host. The host gives exactly one reply to
+/// every request, of the type that belongs to it.
+pub trait Service: Send + 'static {
+    type Reply: Send + 'static;
+}
+
+/// A request of a package, the host replies with the same `id`.
+pub struct Request<M> {
+    pub id: u64,
+    pub msg: M,
 }
 
-impl<M> App<M> {
-    pub fn sync_send(&self, msg: M) -> M {
-        self.service_tx.send(msg).unwrap();
-        self.service_rx.recv().unwrap()
+/// What the host did, or why it could not do it.
+pub type Reply<M> = Result<<M as Service>::Reply, String>;
+
+pub struct App<M: Service> {
+    service_tx: Sender<Request<M>>,
+    service_rx: Arc<Receiver<(u64, Reply<M>)>>,
+    last_id: Cell<u64>,
+    /// Coroutines that asked the host something, by request id.
+    waiting: RefCell<BTreeMap<u64, Waiting<M>>>,
+}
+
+/// Turns the reply of the host into what a coroutine gets back.
+type Then<M> = Box<dyn FnOnce(&Lua, <M as Service>::Reply) -> mlua::Result<MultiValue>>;
+
+struct Waiting<M: Service> {
+    thread: Thread,
+    then: Then<M>,
+    reply: Option<Reply<M>>,
+}
+
+impl<M: Service> App<M> {
+    fn new(service_tx: Sender<Request<M>>, service_rx: Receiver<(u64, Reply<M>)>) -> Self {
+        Self {
+            service_tx,
+            service_rx: Arc::new(service_rx),
+            last_id: Cell::new(0),
+            waiting: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn send(&self, msg: M) -> mlua::Result<u64> {
+        let id = self.last_id.get() + 1;
+        self.last_id.set(id);
+        self.service_tx
+            .send(Request { id, msg })
+            .map_err(|_| Error::runtime("the host is gone"))?;
+        Ok(id)
+    }
+
+    /// Fails when the host went away, like while the package is reloaded,
+    /// or when it refused.
+    pub fn sync_send(&self, msg: M) -> mlua::Result<M::Reply> {
+        let id = self.send(msg)?;
+        loop {
+            let (replied, reply) = self
+                .service_rx
+                .recv()
+                .map_err(|_| Error::runtime("the host is gone"))?;
+            if replied == id {
+                return reply.map_err(Error::runtime);
+            }
+            self.keep(replied, reply);
+        }
+    }
+
+    /// Holds on to the reply for a coroutine until the next round.
+    fn keep(&self, id: u64, reply: Reply<M>) {
+        if let Some(w) = self.waiting.borrow_mut().get_mut(&id) {
+            w.reply = Some(reply);
+        }
+    }
+
+    /// Blocks until every coroutine that asked the host has its reply, so a
+    /// test sees the same replies in the same round every time.
+    fn settle(&self) -> mlua::Result<()> {
+        while self.waiting.borrow().values().any(|w| w.reply.is_none()) {
+            let (id, reply) = self
+                .service_rx
+                .recv()
+                .map_err(|_| Error::runtime("the host is gone"))?;
+            self.keep(id, reply);
+        }
+        Ok(())
+    }
+
+    /// Takes the coroutines whose reply is there.
+    fn answered(&self) -> Vec<(Thread, Then<M>, Reply<M>)> {
+        while let Ok((id, reply)) = self.service_rx.try_recv() {
+            self.keep(id, reply);
+        }
+
+        let mut waiting = self.waiting.borrow_mut();
+        let mut answered = Vec::new();
+        for (id, w) in std::mem::take(&mut *waiting) {
+            match w.reply {
+                Some(reply) => answered.push((w.thread, w.then, reply)),
+                None => {
+                    waiting.insert(id, w);
+                }
+            }
+        }
+        answered
     }
 }
 
-impl<M> UserData for App<M> {}
+impl<M: Service> UserData for App<M> {}
 
-pub struct Package<M> {
-    pub name: String,
-    pub msg_tx: Sender<String>,
-    pub service_rx: Receiver<M>,
-    pub service_tx: Sender<M>,
-}
-
-fn run_package<F, M: 'static>(
-    cb: F,
-    root: PathBuf,
-    name_tx: mpsc::SyncSender<String>,
-    msg_rx: Receiver<String>,
-    service_tx: Sender<M>,
-    service_rx: Receiver<M>,
-) -> Result<(), PackageError>
+/// Asks the host with `msg` and hands the answer to `then`. Inside a
+/// coroutine only the coroutine waits, for that the host function has to
+/// be wrapped with `awaitable`. Anywhere else the whole package waits.
+pub fn request<M, R, F>(lua: &Lua, msg: M, then: F) -> mlua::Result<MultiValue>
 where
-    F: Fn(&Lua) -> Result<(), Box<dyn std::error::Error>>,
+    M: Service,
+    R: IntoLuaMulti,
+    F: FnOnce(&Lua, M::Reply) -> mlua::Result<R> + 'static,
 {
-    let indexlua = root.join("index.luau");
+    let app: AnyUserData = lua.named_registry_value("App")?;
+
+    if !task::in_coroutine(lua) {
+        let reply = app.borrow_scoped(|app: &App<M>| app.sync_send(msg))??;
+        return then(lua, reply)?.into_lua_multi(lua);
+    }
+
+    app.borrow_scoped(|app: &App<M>| {
+        let id = app.send(msg)?;
+        app.waiting.borrow_mut().insert(
+            id,
+            Waiting {
+                thread: lua.current_thread(),
+                then: Box::new(move |lua, reply| then(lua, reply)?.into_lua_multi(lua)),
+                reply: None,
+            },
+        );
+        Ok::<_, Error>(())
+    })??;
+    task::pending(lua)
+}
+
+/// The name of the package running in `lua`.
+pub fn name(lua: &Lua) -> mlua::Result<String> {
+    lua.named_registry_value("Name")
+}
+
+/// Work the host wants done on the package thread, like calling a callback.
+pub type Event = Box<dyn FnOnce(&Lua) -> mlua::Result<()> + Send>;
+
+/// Sets up the globals the host offers, it runs again for every reload.
+pub type Setup = dyn Fn(&Lua) -> Result<(), Box<dyn std::error::Error>> + Send + Sync;
+
+/// Where a package is in its life.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum PackageState {
+    /// Running its entry script and `OnStart`.
+    #[default]
+    Loading,
+    Running,
+    /// Ended with an error, the message ends with the Lua traceback.
+    Crashed(String),
+    /// Ended because the host stopped it.
+    Stopped,
+}
+
+impl PackageState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PackageState::Loading => "loading",
+            PackageState::Running => "running",
+            PackageState::Crashed(_) => "crashed",
+            PackageState::Stopped => "stopped",
+        }
+    }
+
+    /// Whether the package thread has ended.
+    pub fn has_ended(&self) -> bool {
+        matches!(self, PackageState::Crashed(_) | PackageState::Stopped)
+    }
+}
+
+pub struct Package<M: Service> {
+    pub name: String,
+    pub manifest: Manifest,
+    pub msg_tx: Sender<Value>,
+    /// For whatever the host has to tell the package without being asked.
+    pub event_tx: Sender<Event>,
+    pub bus_rx: Receiver<BusMessage>,
+    /// Requests of the package, each one is answered with `reply`.
+    pub service_rx: Receiver<Request<M>>,
+    reply_tx: Sender<(u64, Reply<M>)>,
+    frame_tx: Sender<f64>,
+    setup: Arc<Setup>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Value>>,
+    watcher: Option<Watcher>,
+}
+
+/// What the host and the package thread both look at.
+#[derive(Default)]
+struct Shared {
+    stop: AtomicBool,
+    state: Mutex<PackageState>,
+    stats: Mutex<UpdateStats>,
+    /// The `On...` functions the package defined by the end of its last round.
+    callbacks: Mutex<BTreeSet<String>>,
+}
+
+/// Notes which callbacks the package defines, the host only sends events
+/// to packages that listen for them.
+fn note_callbacks(rt: &Lua, shared: &Shared) -> mlua::Result<()> {
+    let mut callbacks = BTreeSet::new();
+    for pair in rt.globals().pairs::<mlua::Value, mlua::Value>() {
+        let (mlua::Value::String(name), mlua::Value::Function(_)) = pair? else {
+            continue;
+        };
+        let name = name.to_str()?;
+        if name.starts_with("On") {
+            callbacks.insert(name.to_string());
+        }
+    }
+    *shared.callbacks.lock().unwrap() = callbacks;
+    Ok(())
+}
+
+/// The package side of the channels to the host.
+struct Channels<M: Service> {
+    msg_rx: Receiver<Value>,
+    frame_rx: Receiver<f64>,
+    event_rx: Receiver<Event>,
+    bus_tx: Sender<BusMessage>,
+    service_tx: Sender<Request<M>>,
+    service_rx: Receiver<(u64, Reply<M>)>,
+}
+
+/// Sets up the globals of a package, ours first and then the ones of the host.
+fn install(
+    rt: &Lua,
+    setup: &Setup,
+    root: &Path,
+    name: &str,
+    bus_tx: Sender<BusMessage>,
+) -> Result<(), PackageError> {
+    let globals = rt.globals();
+    let print = rt.create_function(|_, args: mlua::MultiValue| {
+        let args: Vec<String> = args.into_iter().map(value::display).collect();
+        println!("{}", args.join("\t"));
+        Ok(())
+    })?;
+    globals.set("print", print)?;
+    require::install(rt, root)?;
+    // the awaitable `Bus.call` needs the tasks
+    task::install(rt)?;
+    bus::install(rt, bus_tx)?;
+    storage::install(rt)?;
+    globals.set("Name", name)?;
+    // a sandboxed package can shadow its globals, the host asks `name`
+    rt.set_named_registry_value("Name", name)?;
+
+    setup(rt)?;
+    Ok(())
+}
+
+/// A state with the globals a package would get from us and `setup`, but
+/// without a package. For tools that look at the API, like the check of
+/// the definitions.
+pub fn api(setup: &Setup) -> Result<Lua, PackageError> {
     let rt = Lua::new();
+    install(&rt, setup, Path::new("."), "api", mpsc::channel().0)?;
+    Ok(rt)
+}
 
-    {
-        let globals = rt.globals();
-        let print = rt.create_function(|_, args: mlua::Value| {
-            println!("{:?}", args);
-            Ok(())
-        })?;
-        globals.set("print", print)?;
-        let require = rt.create_function(move |_, filename: String| {
-            println!("load {}", root.join(filename).display());
-            Ok(())
-        })?;
-        globals.set("require", require)?;
+/// A package that ran its entry script and `OnStart`, ready for updates.
+struct Loaded {
+    rt: Lua,
+    app: AnyUserData,
+    budget: Budget,
+}
+
+/// Sets up the state of a package with `app` to ask the host and the
+/// storage in `storage`, then runs its entry script and `OnStart`. With
+/// `reloaded` it is a reload and `OnReload` gets the state. `extend` adds
+/// globals of our own before the sandbox looks at them.
+fn load_package<M: Service>(
+    setup: &Setup,
+    manifest: &Manifest,
+    bus_tx: Sender<BusMessage>,
+    app: App<M>,
+    storage: Option<PathBuf>,
+    reloaded: Option<Value>,
+    extend: impl FnOnce(&Lua) -> mlua::Result<()>,
+) -> Result<Loaded, PackageError> {
+    let root = &manifest.root;
+    let rt = Lua::new();
+    install(&rt, setup, root, &manifest.name, bus_tx)?;
+    extend(&rt)?;
+
+    let app = rt.create_userdata(app)?;
+    rt.set_named_registry_value("App", &app)?;
+    storage::open(&rt, storage, manifest.quota);
+    let budget = sandbox::install(&rt, manifest)?;
+
+    let data = std::fs::read_to_string(root.join(&manifest.entry))?;
+    budget.start();
+    rt.load(&data)
+        .set_name(format!("@{}", manifest.entry))
+        .exec()?;
 
-        cb(&rt)?;
+    // the callbacks are all optional, a package only defines what it needs
+    if let Some(on_start) = rt.globals().get::<Option<mlua::Function>>("OnStart")? {
+        budget.start();
+        let _: () = on_start.call(())?;
     }
 
-    rt.set_named_registry_value(
-        "App",
-        App {
-            service_tx,
-            service_rx: Arc::new(service_rx),
-        },
-    )?;
+    if let Some(previous) = reloaded {
+        budget.start();
+        on_reload(&rt, &previous)?;
+    }
+
+    Ok(Loaded { rt, app, budget })
+}
 
-    let data = std::fs::read_to_string(&indexlua)?;
-    rt.load(&data).exec()?;
+/// Hands `OnReload` what the previous instance of the package left.
+fn on_reload(rt: &Lua, previous: &Value) -> mlua::Result<()> {
+    match rt.globals().get::<Option<mlua::Function>>("OnReload")? {
+        Some(on_reload) => on_reload.call(from_value(rt, previous)?),
+        None => Ok(()),
+    }
+}
+
+/// Whatever `OnUnload` returns, it is handed to `OnReload` of the next
+/// instance.
+fn on_unload(rt: &Lua) -> mlua::Result<Value> {
+    match rt.globals().get::<Option<mlua::Function>>("OnUnload")? {
+        Some(on_unload) => to_value(on_unload.call(())?),
+        None => Ok(Value::Nil),
+    }
+}
 
-    let name: String = rt.globals().get("Name")?;
-    name_tx.send(name)?;
-    drop(name_tx);
+/// Runs the package until it is told to stop. `reloaded` is the state the
+/// previous instance handed over, the state of this one is returned.
+fn run_package<M: Service>(
+    setup: &Setup,
+    manifest: Manifest,
+    channels: Channels<M>,
+    shared: &Shared,
+    reloaded: Option<Value>,
+) -> Result<Value, PackageError> {
+    let app = App::new(channels.service_tx, channels.service_rx);
+    let storage = storage::file(&manifest);
+    let Loaded { rt, app, budget } = load_package(
+        setup,
+        &manifest,
+        channels.bus_tx,
+        app,
+        storage,
+        reloaded,
+        |_| Ok(()),
+    )?;
+    note_callbacks(&rt, shared)?;
+    *shared.state.lock().unwrap() = PackageState::Running;
 
-    let on_start: mlua::Function = rt.globals().get("OnStart")?;
+    let on_message: Option<mlua::Function> = rt.globals().get("OnMessage")?;
+    let on_update: Option<mlua::Function> = rt.globals().get("OnUpdate")?;
 
-    let _: () = on_start.call(())?;
+    let mut clock = Clock::new(manifest.schedule);
+    let round = |clock: &mut Clock| -> mlua::Result<usize> {
+        while let Ok(msg) = channels.msg_rx.try_recv() {
+            if let Some(on_message) = &on_message {
+                let _: () = on_message.call((from_value(&rt, &msg)?,))?;
+            }
+        }
 
-    let on_message: mlua::Function = rt.globals().get("OnMessage")?;
-    let on_update: mlua::Function = rt.globals().get("OnUpdate")?;
+        while let Ok(event) = channels.event_rx.try_recv() {
+            event(&rt)?;
+        }
 
-    loop {
-        let start = SystemTime::now();
+        for (thread, then, reply) in app.borrow_scoped(|app: &App<M>| app.answered())? {
+            let result = reply.map_err(Error::runtime).and_then(|r| then(&rt, r));
+            task::answer(thread, result)?;
+        }
+        task::run(&rt)?;
 
-        while let Ok(msg) = msg_rx.try_recv() {
-            let _: () = on_message.call((msg,))?;
+        let steps = clock.steps(&shared.stats);
+        if let Some(on_update) = &on_update {
+            for dt in steps.iter() {
+                let _: () = on_update.call(*dt)?;
+            }
         }
+        Ok(steps.len())
+    };
 
-        let _: () = on_update.call(())?;
+    while !shared.stop.load(Ordering::Relaxed) {
+        clock.wait(&channels.frame_rx, &shared.stats);
+        let start = Instant::now();
+        budget.start();
 
-        let elapsed = start.elapsed().unwrap();
-        let to_wait = Duration::from_millis(100) - elapsed;
-        std::thread::sleep(to_wait);
+        let steps = match round(&mut clock) {
+            Ok(steps) => steps,
+            // the host went away while the package waited for an answer,
+            // it is being unloaded and still gets its `OnUnload`
+            Err(_) if shared.stop.load(Ordering::Relaxed) => break,
+            Err(e) => return Err(e.into()),
+        };
+        note_callbacks(&rt, shared)?;
+        clock.record(start.elapsed(), steps, &shared.stats);
     }
+
+    budget.start();
+    Ok(on_unload(&rt)?)
 }
 
-impl<M> Package<M>
-where
-    M: Send + 'static,
-{
-    pub fn load<F>(root: PathBuf, cb: F) -> Result<Package<M>, PackageError>
+impl<M: Service> Package<M> {
+    pub fn load<F>(manifest: Manifest, cb: F) -> Result<Package<M>, PackageError>
     where
-        F: Fn(&Lua) -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+        F: Fn(&Lua) -> Result<(), Box<dyn std::error::Error>> + Send + Sync + 'static,
     {
-        println!("PACKAGE: load {}", root.display());
-        let indexlua = root.join("index.luau");
+        println!(
+            "PACKAGE: load {} {} from {}",
+            manifest.name,
+            manifest.version,
+            manifest.root.display()
+        );
 
-        if !indexlua.exists() {
+        Self::start(manifest, Arc::new(cb), None)
+    }
+
+    fn start(
+        manifest: Manifest,
+        setup: Arc<Setup>,
+        reloaded: Option<Value>,
+    ) -> Result<Package<M>, PackageError> {
+        if !manifest.root.join(&manifest.entry).is_file() {
             return Err(PackageError::not_a_package());
         }
 
         let (tx, rx) = mpsc::channel();
+        let (ftx, frx) = mpsc::channel();
+        let (etx, erx) = mpsc::channel();
+        let (btx, brx) = mpsc::channel();
         let (rtx, rrx) = mpsc::channel();
         let (atx, arx) = mpsc::channel();
-        let (otx, orx) = mpsc::sync_channel(1);
+        let shared = Arc::new(Shared::default());
 
-        std::thread::spawn(move || {
-            if let Err(e) = run_package(cb, root.clone(), otx, rx, rtx, arx) {
-                println!("PACKAGE {}: {}", root.display(), e);
-            }
-        });
-
-        let name: String = orx.recv()?;
+        let channels = Channels {
+            msg_rx: rx,
+            frame_rx: frx,
+            event_rx: erx,
+            bus_tx: btx,
+            service_tx: rtx,
+            service_rx: arx,
+        };
+        let thread = {
+            let manifest = manifest.clone();
+            let setup = setup.clone();
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let name = manifest.name.clone();
+                let result = run_package(setup.as_ref(), manifest, channels, &shared, reloaded);
+                let (ended, handed_over) = match result {
+                    Ok(v) => (PackageState::Stopped, v),
+                    Err(e) => {
+                        println!("PACKAGE {}: crashed\n{}", name, e.msg);
+                        (PackageState::Crashed(e.msg), Value::Nil)
+                    }
+                };
+                *shared.state.lock().unwrap() = ended;
+                handed_over
+            })
+        };
 
         Ok(Self {
-            name,
+            name: manifest.name.clone(),
+            manifest,
             msg_tx: tx,
-            service_tx: atx,
+            event_tx: etx,
+            bus_rx: brx,
+            reply_tx: atx,
             service_rx: rrx,
+            setup,
+            frame_tx: ftx,
+            shared,
+            thread: Some(thread),
+            watcher: None,
+        })
+    }
+
+    /// Answers the request `id` of the package. A package that went away
+    /// in the meantime does not need it anymore.
+    pub fn reply(&self, id: u64, reply: Reply<M>) {
+        let _ = self.reply_tx.send((id, reply));
+    }
+
+    pub fn state(&self) -> PackageState {
+        self.shared.state.lock().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> UpdateStats {
+        *self.shared.stats.lock().unwrap()
+    }
+
+    /// Whether the package defines the global function `callback`, like
+    /// `OnAgentDecide`. Known once it started, and again after every round.
+    pub fn defines(&self, callback: &str) -> bool {
+        self.shared.callbacks.lock().unwrap().contains(callback)
+    }
+
+    /// Tells a frame synced package that the host drew a frame that took
+    /// `dt` seconds, other packages keep their own time.
+    pub fn frame(&self, dt: f64) {
+        if self.manifest.schedule == Schedule::Frame {
+            let _ = self.frame_tx.send(dt);
+        }
+    }
+
+    /// Ends the package after its current update.
+    pub fn stop(&mut self) {
+        self.unload();
+    }
+
+    /// Ends the package thread after its current update and returns what
+    /// its `OnUnload` handed over.
+    pub fn unload(&mut self) -> Value {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        // a package waiting for an answer would wait forever
+        self.reply_tx = mpsc::channel().0;
+
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(state)) => state,
+            _ => Value::Nil,
+        }
+    }
+
+    /// Runs the package again from its files. With `reloaded` it is a
+    /// reload and `OnReload` gets the state, otherwise it starts afresh.
+    pub fn restart(&mut self, reloaded: Option<Value>) -> Result<(), PackageError> {
+        if self.thread.is_some() {
+            self.unload();
+        }
+
+        let started = Manifest::read(&self.manifest.root).and_then(|manifest| {
+            if manifest.name != self.name {
+                return Err(PackageError::manifest(format!(
+                    "{} was renamed to {}, it needs a restart of the host",
+                    self.name, manifest.name
+                )));
+            }
+            Self::start(manifest, self.setup.clone(), reloaded)
+        });
+
+        match started {
+            Ok(pk) => {
+                let watcher = self.watcher.take();
+                *self = pk;
+                self.watcher = watcher;
+                Ok(())
+            }
+            Err(e) => {
+                *self.shared.state.lock().unwrap() = PackageState::Crashed(e.msg.clone());
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts looking for changes to the files of the package.
+    pub fn watch(&mut self) {
+        self.watcher = Some(Watcher::new(&self.manifest.root));
+    }
+
+    /// Whether a watched package changed on disk since the last time.
+    pub fn changed(&mut self) -> bool {
+        self.watcher.as_mut().is_some_and(|w| w.changed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::{wait_until, Nothing, Scratch};
+
+    fn start(scratch: &Scratch) -> Package<Nothing> {
+        Package::load(scratch.manifest(), |_| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn defined_callbacks_are_known() {
+        let scratch = Scratch::package("callbacks", "");
+        scratch.write(
+            "index.luau",
+            r#"
+            OnNumber = 1
+            function OnStart() end
+            function OnUpdate()
+                function OnLater() end
+            end
+            "#,
+        );
+        let mut pk = start(&scratch);
+        wait_until(|| pk.defines("OnLater"));
+        assert!(pk.defines("OnStart") && pk.defines("OnUpdate"));
+        assert!(!pk.defines("OnNumber") && !pk.defines("OnAgentDecide"));
+        pk.stop();
+
+        // a sandboxed package defines them on a table of its own
+        let sandboxed = Scratch::package("callbacks-sandboxed", "[sandbox]\n");
+        sandboxed.write("index.luau", "function OnAgentDecide() end");
+        let mut pk = start(&sandboxed);
+        wait_until(|| pk.state() == PackageState::Running);
+        assert!(pk.defines("OnAgentDecide"));
+        pk.stop();
+    }
+
+    #[test]
+    fn errors_crash_the_package_with_a_traceback() {
+        let scratch = Scratch::package("crash", "");
+        scratch.write(
+            "index.luau",
+            r#"
+            local function explode() error("boom") end
+            function OnUpdate() explode() end
+            "#,
+        );
+        let mut pk = start(&scratch);
+        wait_until(|| pk.state().has_ended());
+        let PackageState::Crashed(msg) = pk.state() else {
+            panic!("{:?}", pk.state());
+        };
+        assert!(msg.contains("boom") && msg.contains("explode"), "{msg}");
+        assert_eq!(pk.unload(), Value::Nil);
+        assert_eq!(pk.state().name(), "crashed");
+    }
+
+    #[test]
+    fn stopped_packages_stop() {
+        let scratch = Scratch::package("stop", "");
+        scratch.write("index.luau", "function OnUpdate() end");
+        let mut pk = start(&scratch);
+        wait_until(|| pk.state() == PackageState::Running);
+        pk.stop();
+        assert_eq!(pk.state(), PackageState::Stopped);
+    }
+
+    #[test]
+    fn restarts_hand_the_state_over() {
+        let scratch = Scratch::package("restart", "");
+        scratch.write(
+            "index.luau",
+            r#"
+            generation = 1
+            function OnUnload() return { generation = generation } end
+            function OnReload(previous) generation = previous.generation + 1 end
+            "#,
+        );
+        let generation =
+            |n| Value::Map(vec![(Value::String("generation".into()), Value::Number(n))]);
+        let mut pk = start(&scratch);
+        wait_until(|| pk.state() == PackageState::Running);
+
+        let state = pk.unload();
+        assert_eq!(state, generation(1.0));
+        pk.restart(Some(state)).unwrap();
+        wait_until(|| pk.state() == PackageState::Running);
+        assert_eq!(pk.unload(), generation(2.0));
+
+        // without a state it starts afresh
+        pk.restart(None).unwrap();
+        wait_until(|| pk.state() == PackageState::Running);
+        assert_eq!(pk.unload(), generation(1.0));
+    }
+
+    #[test]
+    fn unloading_ends_requests_in_flight() {
+        let scratch = Scratch::package("in-flight", "");
+        scratch.write(
+            "index.luau",
+            r#"
+            function OnUpdate() Ask() end
+            function OnUnload() return "unloaded" end
+            "#,
+        );
+        let mut pk: Package<Nothing> = Package::load(scratch.manifest(), |lua| {
+            let ask = lua.create_function(|lua, ()| request(lua, Nothing, |_, ()| Ok(())))?;
+            lua.globals().set("Ask", ask)?;
+            Ok(())
         })
+        .unwrap();
+
+        // never answered, the package waits for it until it is unloaded
+        pk.service_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(pk.unload(), Value::String("unloaded".into()));
+        assert_eq!(pk.state(), PackageState::Stopped);
+    }
+
+    /// Replies with what the host made of the number.
+    struct Echo(u32);
+
+    impl Service for Echo {
+        type Reply = u32;
+    }
+
+    #[test]
+    fn replies_out_of_order_reach_their_caller() {
+        let (service_tx, requests) = mpsc::channel();
+        let (replies, service_rx) = mpsc::channel();
+        let lua = Lua::new();
+        task::install(&lua).unwrap();
+        let app = lua
+            .create_userdata(App::<Echo>::new(service_tx, service_rx))
+            .unwrap();
+        lua.set_named_registry_value("App", &app).unwrap();
+        let ask = lua
+            .create_function(|lua, n: u32| request(lua, Echo(n), |_, reply| Ok(reply)))
+            .unwrap();
+        lua.globals()
+            .set("ask", awaitable(&lua, ask).unwrap())
+            .unwrap();
+        let answer_all = || {
+            for (thread, then, reply) in
+                app.borrow_scoped(|app: &App<Echo>| app.answered()).unwrap()
+            {
+                let result = reply.map_err(Error::runtime).and_then(|r| then(&lua, r));
+                task::answer(thread, result).unwrap();
+            }
+        };
+        let global = |name: &str| lua.globals().get::<Option<u32>>(name).unwrap();
+
+        lua.load(
+            r#"
+            coroutine.resume(coroutine.create(function() first = ask(1) end))
+            coroutine.resume(coroutine.create(function() second = ask(2) end))
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let asked: Vec<Request<Echo>> = requests.try_iter().collect();
+        for r in asked.iter().rev() {
+            replies.send((r.id, Ok(r.msg.0 * 10))).unwrap();
+        }
+        answer_all();
+        assert_eq!((global("first"), global("second")), (Some(10), Some(20)));
+
+        // a reply for a coroutine arrives while the package waits for its own
+        lua.load("coroutine.resume(coroutine.create(function() third = ask(3) end))")
+            .exec()
+            .unwrap();
+        replies.send((3, Ok(30))).unwrap();
+        replies.send((4, Ok(40))).unwrap();
+        lua.load("fourth = ask(4)").exec().unwrap();
+        assert_eq!((global("third"), global("fourth")), (None, Some(40)));
+        answer_all();
+        assert_eq!(global("third"), Some(30));
+    }
+
+    /// Reloads `pk` when it changed like the client does, for a few polls.
+    /// Returns how often it reloaded.
+    fn reload_for_a_while(pk: &mut Package<Nothing>) -> u32 {
+        let mut reloads = 0;
+        let start = Instant::now();
+        while start.elapsed() < watch::POLL_INTERVAL * 5 / 2 {
+            if pk.changed() {
+                reloads += 1;
+                let state = pk.unload();
+                pk.restart(Some(state)).unwrap();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        reloads
+    }
+
+    #[test]
+    fn changed_files_reload_the_package_once() {
+        const SCRIPT: &str = r#"
+            reloads = 0
+            function OnUnload() return reloads end
+            function OnReload(previous) reloads = previous + 1 end
+        "#;
+        let scratch = Scratch::package("reload", "");
+        scratch.write("index.luau", SCRIPT);
+        let mut pk = start(&scratch);
+        pk.watch();
+        assert_eq!(reload_for_a_while(&mut pk), 0);
+
+        scratch.write("index.luau", &format!("{SCRIPT}-- changed\n"));
+        // a file system with a coarse clock would not see a quick write
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(scratch.path().join("index.luau"))
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+        assert_eq!(reload_for_a_while(&mut pk), 1);
+        assert_eq!(pk.unload(), Value::Number(1.0));
     }
 }
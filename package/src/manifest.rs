@@ -0,0 +1,796 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use toml_edit::DocumentMut;
+
+use crate::{
+    error::{PackageError, PackageErrorKind},
+    sandbox::{Capability, Sandbox},
+    schedule::{self, Schedule},
+    storage,
+};
+
+pub const MANIFEST: &str = "package.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl FromStr for Version {
+    type Err = PackageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('.').collect();
+        let [major, minor, patch] = parts[..] else {
+            return Err(PackageError::manifest(format!("bad version {}", s)));
+        };
+        let number = |p: &str| {
+            p.parse::<u64>()
+                .map_err(|_| PackageError::manifest(format!("bad version {}", s)))
+        };
+
+        Ok(Self {
+            major: number(major)?,
+            minor: number(minor)?,
+            patch: number(patch)?,
+        })
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+    Any,
+}
+
+/// One part of a requirement, the version may leave out minor and patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Comparator {
+    op: Op,
+    major: u64,
+    minor: Option<u64>,
+    patch: Option<u64>,
+}
+
+impl Comparator {
+    fn lowest(&self) -> Version {
+        Version {
+            major: self.major,
+            minor: self.minor.unwrap_or(0),
+            patch: self.patch.unwrap_or(0),
+        }
+    }
+
+    /// Compares `v` only as far as the comparator spells its version out.
+    fn cmp_prefix(&self, v: &Version) -> Ordering {
+        v.major
+            .cmp(&self.major)
+            .then(self.minor.map_or(Ordering::Equal, |m| v.minor.cmp(&m)))
+            .then(self.patch.map_or(Ordering::Equal, |p| v.patch.cmp(&p)))
+    }
+
+    fn matches(&self, v: &Version) -> bool {
+        match self.op {
+            Op::Any => true,
+            Op::Exact => self.cmp_prefix(v) == Ordering::Equal,
+            Op::Greater => self.cmp_prefix(v) == Ordering::Greater,
+            Op::GreaterEq => *v >= self.lowest(),
+            Op::Less => *v < self.lowest(),
+            Op::LessEq => self.cmp_prefix(v) != Ordering::Greater,
+            Op::Tilde => {
+                *v >= self.lowest()
+                    && v.major == self.major
+                    && self.minor.is_none_or(|m| v.minor == m)
+            }
+            Op::Caret => {
+                if *v < self.lowest() || v.major != self.major {
+                    return false;
+                }
+                match (self.major, self.minor, self.patch) {
+                    (0, Some(0), Some(p)) => v.minor == 0 && v.patch == p,
+                    (0, Some(m), _) => v.minor == m,
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Comparator {
+    type Err = PackageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let bad = || PackageError::manifest(format!("bad version requirement {}", s));
+
+        if s == "*" {
+            return Ok(Self {
+                op: Op::Any,
+                major: 0,
+                minor: None,
+                patch: None,
+            });
+        }
+
+        let (op, rest) = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("~", Op::Tilde),
+            ("^", Op::Caret),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| s.strip_prefix(prefix).map(|r| (op, r)))
+        .unwrap_or((Op::Caret, s));
+
+        let mut parts = rest.trim().split('.');
+        let mut number = || -> Result<Option<u64>, PackageError> {
+            parts
+                .next()
+                .map(|p| p.parse::<u64>().map_err(|_| bad()))
+                .transpose()
+        };
+        let major = number()?.ok_or_else(bad)?;
+        let minor = number()?;
+        let patch = number()?;
+        if number()?.is_some() {
+            return Err(bad());
+        }
+
+        Ok(Self {
+            op,
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+/// What versions of a dependency will do, like `^1.2` or `>=1.0, <1.4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    text: String,
+    comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    pub fn matches(&self, v: &Version) -> bool {
+        self.comparators.iter().all(|c| c.matches(v))
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = PackageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            text: s.trim().to_string(),
+            comparators: s.split(',').map(str::parse).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl Display for VersionReq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// The `package.toml` next to the entry point of a package.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub root: PathBuf,
+    pub name: String,
+    pub version: Version,
+    pub description: String,
+    /// Script that is run first, relative to the root.
+    pub entry: String,
+    pub dependencies: Vec<(String, VersionReq)>,
+    /// From the `[update]` table, `mode` and `rate` in updates per second.
+    pub schedule: Schedule,
+    /// What the package needs when it runs in a sandbox.
+    pub capabilities: Vec<Capability>,
+    /// From the `[sandbox]` table, `memory` in MiB and `timeout` in seconds.
+    pub sandbox: Option<Sandbox>,
+    /// What the package may keep in `Storage`, in bytes. From `quota` in
+    /// KiB of the `[storage]` table.
+    pub quota: usize,
+    /// Where the host keeps `Storage`, not from the file. Without it
+    /// whatever the package stores is gone when it ends.
+    pub storage: Option<PathBuf>,
+}
+
+impl Manifest {
+    /// Packages from before manifests only have an `index.luau`, they are
+    /// named after their directory and can not have dependencies.
+    fn legacy(root: &Path) -> Result<Self, PackageError> {
+        let name = root.file_name().map(|n| n.to_string_lossy().to_string());
+        match name {
+            Some(name) if root.join("index.luau").is_file() => Ok(Self {
+                root: root.to_path_buf(),
+                name,
+                version: Version {
+                    major: 0,
+                    minor: 0,
+                    patch: 0,
+                },
+                description: String::new(),
+                entry: "index.luau".into(),
+                dependencies: Vec::new(),
+                schedule: Schedule::default(),
+                capabilities: Vec::new(),
+                sandbox: None,
+                quota: storage::DEFAULT_QUOTA,
+                storage: None,
+            }),
+            _ => Err(PackageError::not_a_package()),
+        }
+    }
+
+    pub fn read(root: &Path) -> Result<Self, PackageError> {
+        let file = root.join(MANIFEST);
+        if !file.exists() {
+            return Self::legacy(root);
+        }
+
+        let doc: DocumentMut = std::fs::read_to_string(&file)?
+            .parse()
+            .map_err(|e| PackageError::manifest(format!("{}: {}", file.display(), e)))?;
+
+        let field = |key: &str| doc.get(key).and_then(|v| v.as_str());
+        let name = field("name").ok_or_else(|| {
+            PackageError::manifest(format!("{}: name is missing", file.display()))
+        })?;
+        // the name is also the file the package stores in
+        let mut parts = Path::new(name).components();
+        if !matches!(
+            (parts.next(), parts.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(PackageError::manifest(format!(
+                "{}: name {:?} can not be a file name",
+                file.display(),
+                name
+            )));
+        }
+        let version = field("version")
+            .ok_or_else(|| {
+                PackageError::manifest(format!("{}: version is missing", file.display()))
+            })?
+            .parse()?;
+        let entry = field("entry").unwrap_or("index.luau");
+
+        if Path::new(entry)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(PackageError::manifest(format!(
+                "{}: entry {} is outside of the package",
+                file.display(),
+                entry
+            )));
+        }
+
+        let mut dependencies = Vec::new();
+        if let Some(deps) = doc.get("dependencies").and_then(|d| d.as_table_like()) {
+            for (dep, req) in deps.iter() {
+                let req = req.as_str().ok_or_else(|| {
+                    PackageError::manifest(format!(
+                        "{}: requirement of {} is not a string",
+                        file.display(),
+                        dep
+                    ))
+                })?;
+                dependencies.push((dep.to_string(), req.parse()?));
+            }
+        }
+
+        let mut schedule = Schedule::default();
+        if let Some(update) = doc.get("update").and_then(|u| u.as_table_like()) {
+            let bad = |what: &str| PackageError::manifest(format!("{}: {}", file.display(), what));
+            let rate = match update.get("rate") {
+                None => schedule::DEFAULT_RATE,
+                Some(r) => r
+                    .as_float()
+                    .or_else(|| r.as_integer().map(|i| i as f64))
+                    .filter(|r| *r > 0.0 && r.is_finite())
+                    .ok_or_else(|| bad("update rate must be a positive number"))?,
+            };
+            let period = Duration::try_from_secs_f64(1.0 / rate)
+                .ok()
+                .filter(|p| !p.is_zero())
+                .ok_or_else(|| bad("update rate is out of range"))?;
+            schedule = match update.get("mode").map(|m| m.as_str()) {
+                None | Some(Some("variable")) => Schedule::Variable(period),
+                Some(Some("fixed")) => Schedule::Fixed(period),
+                Some(Some("frame")) => Schedule::Frame,
+                _ => return Err(bad("update mode must be variable, fixed or frame")),
+            };
+        }
+
+        let mut capabilities = Vec::new();
+        if let Some(caps) = doc.get("capabilities") {
+            let caps = caps.as_array().ok_or_else(|| {
+                PackageError::manifest(format!("{}: capabilities must be a list", file.display()))
+            })?;
+            for cap in caps.iter() {
+                let cap = cap.as_str().ok_or_else(|| {
+                    PackageError::manifest(format!(
+                        "{}: capabilities must be strings",
+                        file.display()
+                    ))
+                })?;
+                capabilities.push(cap.parse()?);
+            }
+        }
+
+        let mut sandbox = None;
+        if let Some(table) = doc.get("sandbox").and_then(|s| s.as_table_like()) {
+            let bad = |what: &str| PackageError::manifest(format!("{}: {}", file.display(), what));
+            let mut limits = Sandbox::default();
+            if let Some(memory) = table.get("memory") {
+                let mib = memory
+                    .as_integer()
+                    .filter(|m| *m > 0)
+                    .ok_or_else(|| bad("sandbox memory must be a positive number of MiB"))?;
+                limits.memory = usize::try_from(mib)
+                    .ok()
+                    .and_then(|m| m.checked_mul(1024 * 1024))
+                    .ok_or_else(|| bad("sandbox memory is too large"))?;
+            }
+            if let Some(timeout) = table.get("timeout") {
+                let secs = timeout
+                    .as_float()
+                    .or_else(|| timeout.as_integer().map(|i| i as f64))
+                    .filter(|t| *t > 0.0 && t.is_finite())
+                    .ok_or_else(|| bad("sandbox timeout must be a positive number of seconds"))?;
+                limits.timeout = Duration::try_from_secs_f64(secs)
+                    .map_err(|_| bad("sandbox timeout is too long"))?;
+            }
+            sandbox = Some(limits);
+        }
+
+        let mut quota = storage::DEFAULT_QUOTA;
+        if let Some(table) = doc.get("storage").and_then(|s| s.as_table_like()) {
+            if let Some(q) = table.get("quota") {
+                let kib = q.as_integer().filter(|q| *q > 0).ok_or_else(|| {
+                    PackageError::manifest(format!(
+                        "{}: storage quota must be a positive number of KiB",
+                        file.display()
+                    ))
+                })?;
+                quota = usize::try_from(kib)
+                    .ok()
+                    .and_then(|q| q.checked_mul(1024))
+                    .ok_or_else(|| {
+                        PackageError::manifest(format!(
+                            "{}: storage quota is too large",
+                            file.display()
+                        ))
+                    })?;
+            }
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            name: name.to_string(),
+            version,
+            description: field("description").unwrap_or_default().to_string(),
+            entry: entry.to_string(),
+            dependencies,
+            schedule,
+            capabilities,
+            sandbox,
+            quota,
+            storage: None,
+        })
+    }
+}
+
+/// Reads the manifest of every package in `data` and orders them so that
+/// dependencies come first. Packages that can not be started, because
+/// their manifest is broken or a dependency is missing, does not match,
+/// is broken itself or depends on them in turn, end up in the errors.
+pub fn load_order(data: &Path) -> Result<(Vec<Manifest>, Vec<PackageError>), PackageError> {
+    let mut errors = Vec::new();
+    let mut found: BTreeMap<String, Vec<Manifest>> = BTreeMap::new();
+
+    let mut dirs: Vec<PathBuf> = data
+        .read_dir()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir())
+        .collect();
+    dirs.sort();
+
+    for dir in dirs {
+        match Manifest::read(&dir) {
+            Ok(m) => found.entry(m.name.clone()).or_default().push(m),
+            Err(e) if e.kind == PackageErrorKind::NotAPackage => {}
+            Err(e) => errors.push(e),
+        }
+    }
+
+    let mut pending = Vec::new();
+    let mut refused: Vec<String> = Vec::new();
+    for (name, mut manifests) in found {
+        if manifests.len() > 1 {
+            let roots: Vec<String> = manifests
+                .iter()
+                .map(|m| m.root.display().to_string())
+                .collect();
+            errors.push(PackageError::conflicting_dependency(format!(
+                "{} is provided by {}",
+                name,
+                roots.join(" and ")
+            )));
+            refused.push(name);
+            continue;
+        }
+        pending.push(manifests.remove(0));
+    }
+
+    let versions: BTreeMap<String, Version> = pending
+        .iter()
+        .map(|m| (m.name.clone(), m.version))
+        .collect();
+
+    let mut ordered: Vec<Manifest> = Vec::new();
+
+    loop {
+        let before = pending.len();
+
+        let mut waiting = Vec::new();
+        for m in pending {
+            let mut ready = true;
+            let mut error = None;
+
+            for (dep, req) in m.dependencies.iter() {
+                match versions.get(dep) {
+                    None if !refused.contains(dep) => {
+                        error = Some(PackageError::missing_dependency(format!(
+                            "{} needs {} {}",
+                            m.name, dep, req
+                        )));
+                    }
+                    Some(v) if !req.matches(v) => {
+                        error = Some(PackageError::conflicting_dependency(format!(
+                            "{} needs {} {} but found {}",
+                            m.name, dep, req, v
+                        )));
+                    }
+                    _ if refused.contains(dep) => {
+                        error = Some(PackageError::missing_dependency(format!(
+                            "{} needs {} which can not be started",
+                            m.name, dep
+                        )));
+                    }
+                    _ => ready &= ordered.iter().any(|o| o.name == *dep),
+                }
+                if error.is_some() {
+                    break;
+                }
+            }
+
+            match error {
+                Some(e) => {
+                    refused.push(m.name.clone());
+                    errors.push(e);
+                }
+                None if ready => ordered.push(m),
+                None => waiting.push(m),
+            }
+        }
+        pending = waiting;
+
+        if pending.is_empty() || pending.len() == before {
+            break;
+        }
+    }
+
+    // nothing left can make progress, they wait on each other
+    for m in pending {
+        errors.push(PackageError::cyclic_dependency(format!(
+            "{} is part of a dependency cycle",
+            m.name
+        )));
+    }
+
+    Ok((ordered, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    fn matches(req: &str, v: &str) -> bool {
+        req.parse::<VersionReq>().unwrap().matches(&version(v))
+    }
+
+    #[test]
+    fn requirements_match_like_cargo() {
+        let cases = [
+            ("1.2.3", "1.2.3", true),
+            ("1.2.3", "1.9.0", true),
+            ("1.2.3", "1.2.2", false),
+            ("1.2.3", "2.0.0", false),
+            ("^0.2.3", "0.2.9", true),
+            ("^0.2.3", "0.3.0", false),
+            ("^0.0.3", "0.0.3", true),
+            ("^0.0.3", "0.0.4", false),
+            ("~1.2", "1.2.7", true),
+            ("~1.2", "1.3.0", false),
+            ("~1", "1.9.9", true),
+            ("=1.2", "1.2.5", true),
+            ("=1.2", "1.3.0", false),
+            (">1.2", "1.2.9", false),
+            (">1.2", "1.3.0", true),
+            (">=1.2.3", "1.2.3", true),
+            ("<1.4", "1.3.9", true),
+            ("<1.4", "1.4.0", false),
+            ("<=1.4", "1.4.9", true),
+            ("<=1.4", "1.5.0", false),
+            (">=1.0, <1.4", "1.3.0", true),
+            (">=1.0, <1.4", "1.4.0", false),
+            ("*", "7.0.1", true),
+        ];
+
+        for (req, v, expected) in cases {
+            assert_eq!(matches(req, v), expected, "{} against {}", req, v);
+        }
+    }
+
+    #[test]
+    fn bad_versions_are_refused() {
+        for v in ["1.2", "1.2.3.4", "one.2.3", ""] {
+            assert!(v.parse::<Version>().is_err(), "{}", v);
+        }
+        for req in ["", ">=", "1.x", "1.2.3.4", "^1, what"] {
+            assert!(req.parse::<VersionReq>().is_err(), "{}", req);
+        }
+    }
+
+    /// A data directory of packages that is gone after the test.
+    struct Packages(PathBuf);
+
+    impl Packages {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "einkrad-manifest-{}-{}",
+                std::process::id(),
+                test
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// Adds the package `dir` with `deps` like `b = "^1"`.
+        fn add(&self, dir: &str, name: &str, version: &str, deps: &[&str]) -> &Self {
+            let root = self.0.join(dir);
+            std::fs::create_dir_all(&root).unwrap();
+            let manifest = format!(
+                "name = \"{}\"\nversion = \"{}\"\n\n[dependencies]\n{}\n",
+                name,
+                version,
+                deps.join("\n")
+            );
+            std::fs::write(root.join(MANIFEST), manifest).unwrap();
+            self
+        }
+
+        fn order(&self) -> (Vec<String>, Vec<PackageError>) {
+            let (ordered, errors) = load_order(&self.0).unwrap();
+            (ordered.into_iter().map(|m| m.name).collect(), errors)
+        }
+    }
+
+    impl Drop for Packages {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn kinds(errors: &[PackageError]) -> Vec<PackageErrorKind> {
+        errors.iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn dependencies_load_first() {
+        let packages = Packages::new("order");
+        packages
+            .add("1", "app", "1.0.0", &["ui = \"^2\"", "core = \"1.1\""])
+            .add("2", "ui", "2.3.0", &["core = \">=1.0, <2\""])
+            .add("3", "core", "1.4.2", &[])
+            .add("4", "tools", "0.1.0", &[]);
+
+        let (order, errors) = packages.order();
+        assert!(errors.is_empty(), "{:?}", errors);
+        // independent packages keep the order of their directories
+        assert_eq!(order, ["core", "tools", "ui", "app"]);
+    }
+
+    #[test]
+    fn packages_without_manifest_are_named_after_their_directory() {
+        let packages = Packages::new("legacy");
+        let root = packages.0.join("old");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.luau"), "").unwrap();
+        std::fs::create_dir_all(packages.0.join("empty")).unwrap();
+
+        let (order, errors) = packages.order();
+        assert!(errors.is_empty());
+        assert_eq!(order, ["old"]);
+    }
+
+    #[test]
+    fn missing_and_mismatched_dependencies_are_refused() {
+        let packages = Packages::new("mismatch");
+        packages
+            .add("1", "a", "1.0.0", &["gone = \"1\""])
+            .add("2", "b", "1.0.0", &["c = \"^2\""])
+            .add("3", "c", "1.5.0", &[])
+            .add("4", "d", "1.0.0", &["a = \"1\""]);
+
+        let (order, errors) = packages.order();
+        assert_eq!(order, ["c"]);
+        assert_eq!(
+            kinds(&errors),
+            [
+                PackageErrorKind::MissingDependency,
+                PackageErrorKind::ConflictingDependency,
+                // d needs a, which can not be started
+                PackageErrorKind::MissingDependency,
+            ]
+        );
+    }
+
+    #[test]
+    fn cycles_are_detected() {
+        let packages = Packages::new("cycle");
+        packages
+            .add("1", "a", "1.0.0", &["b = \"1\""])
+            .add("2", "b", "1.0.0", &["c = \"1\""])
+            .add("3", "c", "1.0.0", &["a = \"1\""])
+            .add("4", "d", "1.0.0", &[])
+            .add("5", "self", "1.0.0", &["self = \"1\""]);
+
+        let (order, errors) = packages.order();
+        assert_eq!(order, ["d"]);
+        assert_eq!(kinds(&errors), [PackageErrorKind::CyclicDependency; 4]);
+    }
+
+    #[test]
+    fn names_provided_twice_conflict() {
+        let packages = Packages::new("twice");
+        packages
+            .add("1", "a", "1.0.0", &[])
+            .add("2", "a", "1.1.0", &[])
+            .add("3", "b", "1.0.0", &["a = \"1\""]);
+
+        let (order, errors) = packages.order();
+        assert!(order.is_empty());
+        assert_eq!(
+            kinds(&errors),
+            [
+                PackageErrorKind::ConflictingDependency,
+                PackageErrorKind::MissingDependency,
+            ]
+        );
+    }
+
+    /// Reads a manifest called `test` with `extra` below its name and version.
+    fn read(test: &str, extra: &str) -> Result<Manifest, PackageError> {
+        Manifest::read(Scratch::package(test, extra).path())
+    }
+
+    #[test]
+    fn sandbox_limits_are_read() {
+        let manifest = read("limits", "[sandbox]\nmemory = 16\ntimeout = 0.5\n").unwrap();
+        assert_eq!(
+            manifest.sandbox,
+            Some(Sandbox {
+                memory: 16 * 1024 * 1024,
+                timeout: Duration::from_millis(500),
+            })
+        );
+    }
+
+    #[test]
+    fn sandbox_limits_that_do_not_fit_are_refused() {
+        for (test, sandbox) in [
+            ("timeout-huge", "timeout = 1e300"),
+            ("timeout-nan", "timeout = nan"),
+            ("timeout-zero", "timeout = 0"),
+            ("memory-huge", "memory = 9223372036854775807"),
+            ("memory-negative", "memory = -1"),
+        ] {
+            let e = read(test, &format!("[sandbox]\n{}\n", sandbox)).unwrap_err();
+            assert_eq!(e.kind, PackageErrorKind::Manifest, "{}: {}", sandbox, e.msg);
+        }
+    }
+
+    #[test]
+    fn update_schedules_are_read() {
+        let manifest = read("fixed", "[update]\nmode = \"fixed\"\nrate = 20\n").unwrap();
+        assert_eq!(
+            manifest.schedule,
+            Schedule::Fixed(Duration::from_millis(50))
+        );
+        let manifest = read("frame", "[update]\nmode = \"frame\"\n").unwrap();
+        assert_eq!(manifest.schedule, Schedule::Frame);
+        let manifest = read("variable", "[update]\nrate = 2.5\n").unwrap();
+        assert_eq!(
+            manifest.schedule,
+            Schedule::Variable(Duration::from_millis(400))
+        );
+    }
+
+    #[test]
+    fn update_rates_out_of_range_are_refused() {
+        for (test, update) in [
+            ("rate-tiny", "rate = 1e-300"),
+            ("rate-huge", "mode = \"fixed\"\nrate = 1e10"),
+            ("rate-inf", "rate = inf"),
+            ("rate-zero", "rate = 0"),
+            ("mode-unknown", "mode = \"sometimes\""),
+        ] {
+            let e = read(test, &format!("[update]\n{}\n", update)).unwrap_err();
+            assert_eq!(e.kind, PackageErrorKind::Manifest, "{}: {}", update, e.msg);
+        }
+    }
+
+    #[test]
+    fn names_have_to_be_file_names() {
+        for (test, name) in [
+            ("name-slash", "a/b"),
+            ("name-dots", ".."),
+            ("name-root", "/"),
+            ("name-empty", ""),
+        ] {
+            let scratch = Scratch::new(test);
+            scratch.write(
+                MANIFEST,
+                &format!("name = {:?}\nversion = \"1.0.0\"\n", name),
+            );
+            let e = Manifest::read(scratch.path()).unwrap_err();
+            assert_eq!(e.kind, PackageErrorKind::Manifest, "{}", name);
+        }
+    }
+
+    #[test]
+    fn storage_quotas_that_do_not_fit_are_refused() {
+        let manifest = read("quota", "[storage]\nquota = 16\n").unwrap();
+        assert_eq!(manifest.quota, 16 * 1024);
+
+        let e = read("quota-huge", "[storage]\nquota = 9223372036854775807\n").unwrap_err();
+        assert_eq!(e.kind, PackageErrorKind::Manifest, "{}", e.msg);
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+};
+
+use mlua::{Lua, Value};
+
+use crate::error::PackageError;
+
+#[derive(Default)]
+struct Modules {
+    /// Return values of the modules that finished loading.
+    cache: HashMap<PathBuf, Value>,
+    /// Modules being loaded right now, the outermost first.
+    loading: Vec<PathBuf>,
+}
+
+/// Finds the file of module `name`, relative to the package `root`. The
+/// `.luau` extension may be left out, a directory stands for its `init.luau`.
+fn resolve(root: &Path, name: &str) -> Result<PathBuf, PackageError> {
+    let rel = Path::new(name);
+    if rel
+        .components()
+        .any(|c| matches!(c, Component::RootDir | Component::Prefix(_)))
+    {
+        return Err(PackageError::escaping_path(name));
+    }
+
+    let path = root.join(rel);
+    let candidates = [
+        path.clone(),
+        PathBuf::from(format!("{}.luau", path.display())),
+        path.join("init.luau"),
+    ];
+    let Some(file) = candidates.into_iter().find(|c| c.is_file()) else {
+        return Err(PackageError::module_not_found(name));
+    };
+
+    // symlinks and `..` may still lead outside
+    let file = file.canonicalize()?;
+    if !file.starts_with(root) {
+        return Err(PackageError::escaping_path(name));
+    }
+
+    Ok(file)
+}
+
+fn load(lua: &Lua, root: &Path, modules: &RefCell<Modules>, name: &str) -> mlua::Result<Value> {
+    let file = resolve(root, name).map_err(mlua::Error::external)?;
+
+    if let Some(value) = modules.borrow().cache.get(&file) {
+        return Ok(value.clone());
+    }
+
+    if modules.borrow().loading.contains(&file) {
+        let mut chain: Vec<String> = modules
+            .borrow()
+            .loading
+            .iter()
+            .skip_while(|f| **f != file)
+            .map(|f| f.strip_prefix(root).unwrap_or(f).display().to_string())
+            .collect();
+        chain.push(name.to_string());
+        return Err(mlua::Error::external(PackageError::cyclic_require(&chain)));
+    }
+
+    let source = std::fs::read_to_string(&file)?;
+    let chunk_name = format!("@{}", file.strip_prefix(root).unwrap_or(&file).display());
+
+    modules.borrow_mut().loading.push(file.clone());
+    let value = lua.load(&source).set_name(chunk_name).eval::<Value>();
+    modules.borrow_mut().loading.pop();
+
+    // like Lua, a module that returns nothing counts as loaded
+    let value = match value? {
+        Value::Nil => Value::Boolean(true),
+        v => v,
+    };
+    modules.borrow_mut().cache.insert(file, value.clone());
+
+    Ok(value)
+}
+
+/// Replaces the `require` global with one that loads modules of the package at `root`.
+pub fn install(lua: &Lua, root: &Path) -> Result<(), PackageError> {
+    let root = root.canonicalize()?;
+    let modules = Rc::new(RefCell::new(Modules::default()));
+
+    let require =
+        lua.create_function(move |lua, name: String| load(lua, &root, &modules, &name))?;
+    lua.globals().set("require", require)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::PackageErrorKind, scratch::Scratch};
+
+    fn lua(root: &Path) -> Lua {
+        let lua = Lua::new();
+        install(&lua, root).unwrap();
+        lua
+    }
+
+    fn fails(lua: &Lua, code: &str) -> PackageError {
+        lua.load(code).exec().unwrap_err().into()
+    }
+
+    #[test]
+    fn modules_are_found_without_their_extension() {
+        let scratch = Scratch::new("require-extension");
+        scratch
+            .write("util.luau", "return 1")
+            .write("lib/init.luau", "return 2");
+        let lua = lua(scratch.path());
+
+        let found: (u32, u32, u32) = lua
+            .load("return require('util'), require('util.luau'), require('lib')")
+            .eval()
+            .unwrap();
+        assert_eq!(found, (1, 1, 2));
+        let e = fails(&lua, "require('missing')");
+        assert_eq!(e.kind, PackageErrorKind::ModuleNotFound);
+        assert!(e.msg.contains("module missing not found"), "{}", e);
+    }
+
+    #[test]
+    fn modules_run_once() {
+        let scratch = Scratch::new("require-once");
+        scratch
+            .write("counter.luau", "loads = (loads or 0) + 1\nreturn {}")
+            .write("nothing.luau", "");
+        let lua = lua(scratch.path());
+
+        let same: bool = lua
+            .load("return rawequal(require('counter'), require('./counter'))")
+            .eval()
+            .unwrap();
+        assert!(same);
+        assert_eq!(lua.globals().get::<u32>("loads").unwrap(), 1);
+        // like Lua, a module that returns nothing counts as loaded
+        let nothing: bool = lua.load("return require('nothing')").eval().unwrap();
+        assert!(nothing);
+    }
+
+    #[test]
+    fn cycles_name_the_modules_in_them() {
+        let scratch = Scratch::new("require-cycle");
+        scratch
+            .write("a.luau", "return require('b')")
+            .write("b.luau", "return require('a')");
+        let lua = lua(scratch.path());
+
+        let e = fails(&lua, "require('a')");
+        assert_eq!(e.kind, PackageErrorKind::CyclicRequire);
+        assert!(
+            e.msg.contains("cyclic require a.luau -> b.luau -> a"),
+            "{}",
+            e
+        );
+        assert!(!e.msg.contains("PackageError"), "{}", e);
+    }
+
+    #[test]
+    fn modules_outside_the_package_are_refused() {
+        let scratch = Scratch::new("require-outside");
+        scratch
+            .write("outside.luau", "return 1")
+            .write("package/inside.luau", "return 2");
+        let lua = lua(&scratch.path().join("package"));
+
+        let outside = scratch.path().join("outside.luau");
+        for code in [
+            "require('../outside')".to_string(),
+            format!("require({:?})", outside.display().to_string()),
+        ] {
+            let e = fails(&lua, &code);
+            assert_eq!(e.kind, PackageErrorKind::EscapingPath, "{}", e);
+            assert!(e.msg.contains("is outside of the package"), "{}", e);
+        }
+        let inside: u32 = lua
+            .load("return require('../package/inside')")
+            .eval()
+            .unwrap();
+        assert_eq!(inside, 2);
+    }
+}
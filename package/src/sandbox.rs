@@ -0,0 +1,271 @@
+use std::{
+    cell::Cell,
+    fmt::Display,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use mlua::{Lua, VmState};
+
+use crate::{error::PackageError, manifest::Manifest};
+
+/// Memory a sandboxed package may use unless its manifest says otherwise.
+pub const DEFAULT_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Time a sandboxed package may spend in one round of messages, events
+/// and updates unless its manifest says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Something a sandboxed package has to ask for in its manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    /// Files outside of the package, like models from elsewhere.
+    Filesystem,
+    /// Connecting to and talking with the other side.
+    Network,
+    /// Creating scenes and nodes, or agents and objects on the server.
+    Scene,
+    /// Looking at, stopping and restarting other packages.
+    Packages,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Filesystem => "filesystem",
+            Capability::Network => "network",
+            Capability::Scene => "scene",
+            Capability::Packages => "packages",
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = PackageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "filesystem" => Ok(Capability::Filesystem),
+            "network" => Ok(Capability::Network),
+            "scene" => Ok(Capability::Scene),
+            "packages" => Ok(Capability::Packages),
+            _ => Err(PackageError::manifest(format!("unknown capability {}", s))),
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Limits of a package that is not trusted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sandbox {
+    /// In bytes.
+    pub memory: usize,
+    pub timeout: Duration,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            memory: DEFAULT_MEMORY,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// What the host looks at before it does something for a package.
+struct Permissions {
+    root: PathBuf,
+    sandboxed: bool,
+    capabilities: Vec<Capability>,
+}
+
+/// Fails unless the package may use `capability`. Packages that run
+/// without a sandbox may use everything.
+pub fn allow(lua: &Lua, capability: Capability) -> mlua::Result<()> {
+    let Some(p) = lua.app_data_ref::<Permissions>() else {
+        return Ok(());
+    };
+    if !p.sandboxed || p.capabilities.contains(&capability) {
+        return Ok(());
+    }
+    Err(mlua::Error::runtime(format!(
+        "this needs the {} capability",
+        capability
+    )))
+}
+
+/// The file at `path` if the package may read it. Without the filesystem
+/// capability a sandboxed package only gets files of its own, `path` may
+/// then also be relative to the package root.
+pub fn check_path(lua: &Lua, path: &str) -> mlua::Result<PathBuf> {
+    let Some(p) = lua.app_data_ref::<Permissions>() else {
+        return Ok(path.into());
+    };
+    if !p.sandboxed || p.capabilities.contains(&Capability::Filesystem) {
+        return Ok(path.into());
+    }
+
+    [Path::new(path).to_path_buf(), p.root.join(path)]
+        .iter()
+        .filter_map(|c| c.canonicalize().ok())
+        .find(|c| c.starts_with(&p.root))
+        .ok_or_else(|| {
+            mlua::Error::runtime(format!(
+                "{} is outside of the package, this needs the filesystem capability",
+                path
+            ))
+        })
+}
+
+/// Time left for the current round of a sandboxed package.
+pub(crate) struct Budget {
+    deadline: Rc<Cell<Option<Instant>>>,
+    timeout: Option<Duration>,
+}
+
+impl Budget {
+    /// Gives the package its full time again.
+    pub fn start(&self) {
+        if let Some(timeout) = self.timeout {
+            self.deadline.set(Some(Instant::now() + timeout));
+        }
+    }
+}
+
+/// Limits memory and time of a sandboxed package. Must run after the
+/// host set up its globals, they turn read-only.
+pub(crate) fn install(lua: &Lua, manifest: &Manifest) -> Result<Budget, PackageError> {
+    lua.set_app_data(Permissions {
+        root: manifest.root.canonicalize()?,
+        sandboxed: manifest.sandbox.is_some(),
+        capabilities: manifest.capabilities.clone(),
+    });
+
+    let deadline = Rc::new(Cell::new(None));
+    let Some(sandbox) = manifest.sandbox else {
+        return Ok(Budget {
+            deadline,
+            timeout: None,
+        });
+    };
+
+    lua.set_memory_limit(sandbox.memory)?;
+
+    let timeout = sandbox.timeout;
+    let interrupted = deadline.clone();
+    lua.set_interrupt(move |_| match interrupted.get() {
+        Some(d) if Instant::now() > d => Err(mlua::Error::runtime(format!(
+            "stopped after running for more than {:?}",
+            timeout
+        ))),
+        _ => Ok(VmState::Continue),
+    });
+
+    lua.sandbox(true)?;
+
+    Ok(Budget {
+        deadline,
+        timeout: Some(timeout),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+
+    /// A bare state with the sandbox of the package in `scratch`.
+    fn sandboxed(scratch: &Scratch) -> (Lua, Budget) {
+        let lua = Lua::new();
+        lua.globals()
+            .set("Host", lua.create_table().unwrap())
+            .unwrap();
+        let budget = install(&lua, &scratch.manifest()).unwrap();
+        (lua, budget)
+    }
+
+    #[test]
+    fn capabilities_are_checked() {
+        let scratch = Scratch::package("capabilities", "capabilities = [\"network\"]\n[sandbox]\n");
+        let (lua, _) = sandboxed(&scratch);
+        assert!(allow(&lua, Capability::Network).is_ok());
+        let e = allow(&lua, Capability::Scene).unwrap_err();
+        assert!(e.to_string().contains("scene capability"), "{}", e);
+
+        // without a sandbox everything goes
+        let trusted = Scratch::package("trusted", "");
+        let (lua, _) = sandboxed(&trusted);
+        assert!(allow(&lua, Capability::Scene).is_ok());
+        assert!(check_path(&lua, "/").is_ok());
+    }
+
+    #[test]
+    fn paths_outside_the_package_are_refused() {
+        let scratch = Scratch::package("paths", "[sandbox]\n");
+        scratch.write("models/bed.glb", "").write("index.luau", "");
+        let (lua, _) = sandboxed(&scratch);
+
+        let inside = scratch.path().join("models/bed.glb");
+        assert_eq!(check_path(&lua, "models/bed.glb").unwrap(), inside);
+        assert_eq!(check_path(&lua, inside.to_str().unwrap()).unwrap(), inside);
+        for outside in ["../", "models/../../", "/", "missing.glb"] {
+            let e = check_path(&lua, outside).unwrap_err();
+            assert!(e.to_string().contains("filesystem capability"), "{}", e);
+        }
+
+        let trusted = Scratch::package(
+            "paths-filesystem",
+            "capabilities = [\"filesystem\"]\n[sandbox]\n",
+        );
+        let (lua, _) = sandboxed(&trusted);
+        assert!(check_path(&lua, "/").is_ok());
+    }
+
+    #[test]
+    fn host_globals_are_read_only() {
+        let scratch = Scratch::package("globals", "[sandbox]\n");
+        let (lua, _) = sandboxed(&scratch);
+        for code in ["Host.changed = true", "string.upper = nil", "math.pi = 3"] {
+            assert!(lua.load(code).exec().is_err(), "{}", code);
+        }
+        // the package still gets globals of its own
+        lua.load("Mine = 1").exec().unwrap();
+    }
+
+    #[test]
+    fn memory_is_capped() {
+        let scratch = Scratch::package("memory", "[sandbox]\nmemory = 1\n");
+        let (lua, budget) = sandboxed(&scratch);
+        budget.start();
+        let e = lua
+            .load("local t = {} for i = 1, 1e7 do t[i] = tostring(i) end")
+            .exec()
+            .unwrap_err();
+        assert!(matches!(e, mlua::Error::MemoryError(_)), "{}", e);
+    }
+
+    #[test]
+    fn rounds_are_stopped_after_the_timeout() {
+        let scratch = Scratch::package("timeout", "[sandbox]\ntimeout = 0.05\n");
+        let (lua, budget) = sandboxed(&scratch);
+        budget.start();
+        let start = Instant::now();
+        let e = lua.load("while true do end").exec().unwrap_err();
+        assert!(e.to_string().contains("stopped after running"), "{}", e);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // a new round gets its full time again
+        std::thread::sleep(Duration::from_millis(60));
+        budget.start();
+        lua.load("local n = 0 for i = 1, 1000 do n += i end")
+            .exec()
+            .unwrap();
+    }
+}
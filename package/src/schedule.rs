@@ -0,0 +1,233 @@
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Updates per second of packages that do not ask for anything else.
+pub const DEFAULT_RATE: f64 = 10.0;
+
+/// A fixed step package drops time it is behind by more than this many steps.
+pub const MAX_CATCH_UP: u32 = 5;
+
+/// How long a frame synced package waits for a frame before it looks
+/// at its messages anyway.
+const FRAME_WAIT: Duration = Duration::from_millis(100);
+
+/// When `OnUpdate` is called and what `dt` it gets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// Every period, with the time that really passed since the last update.
+    Variable(Duration),
+    /// With a constant step, several times in a row when it fell behind.
+    Fixed(Duration),
+    /// Once for every frame of the host, with the frame time.
+    Frame,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::Variable(Duration::from_secs_f64(1.0 / DEFAULT_RATE))
+    }
+}
+
+impl Schedule {
+    pub(crate) fn period(&self) -> Option<Duration> {
+        match self {
+            Schedule::Variable(p) | Schedule::Fixed(p) => Some(*p),
+            Schedule::Frame => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UpdateStats {
+    pub updates: u64,
+    /// Rounds that took longer than the period, or frames that came in
+    /// while the package was still busy.
+    pub overruns: u64,
+    /// Fixed steps that were dropped instead of caught up.
+    pub skipped: u64,
+    /// Time spent on messages, events and updates in the last round.
+    pub last: Duration,
+    pub worst: Duration,
+}
+
+/// Keeps the time of one package.
+pub(crate) struct Clock {
+    schedule: Schedule,
+    last: Instant,
+    next: Instant,
+    /// Time a fixed step package still has to catch up on.
+    behind: Duration,
+    /// Frame time that came in since the last update.
+    frames: Option<f64>,
+}
+
+impl Clock {
+    pub fn new(schedule: Schedule) -> Self {
+        let now = Instant::now();
+        Self {
+            schedule,
+            last: now,
+            next: now,
+            behind: Duration::ZERO,
+            frames: None,
+        }
+    }
+
+    /// Blocks until the next round is due.
+    pub fn wait(&mut self, frame_rx: &Receiver<f64>, stats: &Mutex<UpdateStats>) {
+        match self.schedule.period() {
+            Some(period) => {
+                std::thread::sleep(self.next.saturating_duration_since(Instant::now()));
+                // a round that ran late does not make the next ones hurry
+                self.next = (self.next + period).max(Instant::now());
+            }
+            None => {
+                let mut dt = match frame_rx.recv_timeout(FRAME_WAIT) {
+                    Ok(dt) => dt,
+                    Err(RecvTimeoutError::Timeout) => return,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        std::thread::sleep(FRAME_WAIT);
+                        return;
+                    }
+                };
+                let mut missed = 0;
+                while let Ok(more) = frame_rx.try_recv() {
+                    dt += more;
+                    missed += 1;
+                }
+                stats.lock().unwrap().overruns += missed;
+                self.frames = Some(self.frames.unwrap_or(0.0) + dt);
+            }
+        }
+    }
+
+    /// The `dt` of every `OnUpdate` that is due now, in seconds.
+    pub fn steps(&mut self, stats: &Mutex<UpdateStats>) -> Vec<f64> {
+        let now = Instant::now();
+        let passed = now - self.last;
+        self.last = now;
+
+        match self.schedule {
+            Schedule::Variable(_) => vec![passed.as_secs_f64()],
+            Schedule::Fixed(step) => {
+                self.behind += passed;
+                let due =
+                    u32::try_from(self.behind.as_nanos() / step.as_nanos()).unwrap_or(u32::MAX);
+                let run = due.min(MAX_CATCH_UP);
+                if due > run {
+                    stats.lock().unwrap().skipped += (due - run) as u64;
+                    self.behind = Duration::ZERO;
+                } else {
+                    self.behind -= step * run;
+                }
+                vec![step.as_secs_f64(); run as usize]
+            }
+            Schedule::Frame => self.frames.take().into_iter().collect(),
+        }
+    }
+
+    /// Books a round that kept the package busy for `busy`.
+    pub fn record(&self, busy: Duration, updates: usize, stats: &Mutex<UpdateStats>) {
+        let mut stats = stats.lock().unwrap();
+        stats.updates += updates as u64;
+        stats.last = busy;
+        stats.worst = stats.worst.max(busy);
+        if self.schedule.period().is_some_and(|p| busy > p) {
+            stats.overruns += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    const STEP: Duration = Duration::from_millis(100);
+
+    /// Makes the clock believe its last update was `ago`.
+    fn rewind(clock: &mut Clock, ago: Duration) {
+        clock.last = Instant::now() - ago;
+    }
+
+    #[test]
+    fn fixed_steps_catch_up() {
+        let stats = Mutex::new(UpdateStats::default());
+        let mut clock = Clock::new(Schedule::Fixed(STEP));
+
+        rewind(&mut clock, Duration::from_millis(250));
+        assert_eq!(clock.steps(&stats), vec![0.1; 2]);
+        // the rest is carried over to the next round
+        rewind(&mut clock, Duration::from_millis(60));
+        assert_eq!(clock.steps(&stats), vec![0.1]);
+        assert_eq!(clock.steps(&stats), Vec::<f64>::new());
+        assert_eq!(stats.lock().unwrap().skipped, 0);
+    }
+
+    #[test]
+    fn fixed_steps_skip_what_is_too_far_behind() {
+        let stats = Mutex::new(UpdateStats::default());
+        let mut clock = Clock::new(Schedule::Fixed(STEP));
+
+        rewind(&mut clock, STEP * (MAX_CATCH_UP + 3) + STEP / 2);
+        assert_eq!(clock.steps(&stats), vec![0.1; MAX_CATCH_UP as usize]);
+        assert_eq!(stats.lock().unwrap().skipped, 3);
+        // nothing is left to catch up on
+        assert_eq!(clock.steps(&stats), Vec::<f64>::new());
+    }
+
+    #[test]
+    fn variable_steps_are_the_time_that_passed() {
+        let stats = Mutex::new(UpdateStats::default());
+        let mut clock = Clock::new(Schedule::Variable(STEP));
+
+        rewind(&mut clock, Duration::from_millis(250));
+        let steps = clock.steps(&stats);
+        assert_eq!(steps.len(), 1);
+        assert!((0.25..0.3).contains(&steps[0]), "{:?}", steps);
+    }
+
+    #[test]
+    fn frames_add_up_until_the_update() {
+        let stats = Mutex::new(UpdateStats::default());
+        let mut clock = Clock::new(Schedule::Frame);
+        let (tx, rx) = mpsc::channel();
+
+        tx.send(0.016).unwrap();
+        tx.send(0.017).unwrap();
+        clock.wait(&rx, &stats);
+        let steps = clock.steps(&stats);
+        assert_eq!(steps.len(), 1);
+        assert!((steps[0] - 0.033).abs() < 1e-9);
+        // the second frame came in while the package was busy
+        assert_eq!(stats.lock().unwrap().overruns, 1);
+        assert_eq!(clock.steps(&stats), Vec::<f64>::new());
+    }
+
+    #[test]
+    fn rounds_longer_than_the_period_are_overruns() {
+        let stats = Mutex::new(UpdateStats::default());
+        let clock = Clock::new(Schedule::Variable(STEP));
+
+        clock.record(STEP / 2, 1, &stats);
+        clock.record(STEP * 2, 1, &stats);
+        clock.record(STEP / 4, 0, &stats);
+
+        let stats = stats.lock().unwrap();
+        assert_eq!(stats.updates, 2);
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.last, STEP / 4);
+        assert_eq!(stats.worst, STEP * 2);
+
+        // frame synced packages have no period to overrun
+        let frames = Mutex::new(UpdateStats::default());
+        Clock::new(Schedule::Frame).record(STEP * 10, 1, &frames);
+        assert_eq!(frames.lock().unwrap().overruns, 0);
+    }
+}
@@ -0,0 +1,72 @@
+//! Package directories for tests that are gone afterwards.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    manifest::{Manifest, MANIFEST},
+    Service,
+};
+
+/// A host that is never asked anything.
+pub struct Nothing;
+
+impl Service for Nothing {
+    type Reply = ();
+}
+
+/// Waits for package threads until `done`, a few seconds at most.
+pub fn wait_until(mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// An empty directory, `test` keeps tests that run at once apart.
+    pub fn new(test: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("einkrad-package-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir.canonicalize().unwrap())
+    }
+
+    /// A package called `test` with `extra` below its name and version.
+    pub fn package(test: &str, extra: &str) -> Self {
+        let scratch = Self::new(test);
+        scratch.write(
+            MANIFEST,
+            &format!("name = \"{}\"\nversion = \"0.1.0\"\n{}", test, extra),
+        );
+        scratch
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes `file`, relative to the directory, with the directories it is in.
+    pub fn write(&self, file: &str, data: &str) -> &Self {
+        let path = self.0.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+        self
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest::read(&self.0).unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
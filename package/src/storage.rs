@@ -0,0 +1,316 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use common::value::Value;
+use mlua::{Error, Lua};
+
+use crate::{
+    manifest::Manifest,
+    value::{from_value, to_value},
+};
+
+/// What a package may store unless its manifest says otherwise.
+pub const DEFAULT_QUOTA: usize = 1024 * 1024;
+
+/// Where a host keeps the data of its user, like `~/.local/share/einkrad/server`
+/// on Linux. `None` if the system does not say where home is.
+pub fn data_dir(host: &str) -> Option<PathBuf> {
+    let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty());
+    let base = if cfg!(windows) {
+        var("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| Path::new(&home).join("Library/Application Support"))
+    } else {
+        var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".local/share")))
+    };
+    Some(base?.join("einkrad").join(host))
+}
+
+/// The entries of one package, they are all written whenever one changes.
+struct Store {
+    file: Option<PathBuf>,
+    quota: usize,
+    /// Read from the file the first time the package looks.
+    entries: Option<BTreeMap<String, Value>>,
+}
+
+impl Store {
+    fn entries(&mut self) -> mlua::Result<&mut BTreeMap<String, Value>> {
+        if self.entries.is_none() {
+            self.entries = Some(self.read()?);
+        }
+        Ok(self.entries.get_or_insert_with(BTreeMap::new))
+    }
+
+    fn read(&self) -> mlua::Result<BTreeMap<String, Value>> {
+        let Some(file) = &self.file else {
+            return Ok(BTreeMap::new());
+        };
+        let data = match std::fs::read(file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => {
+                return Err(Error::runtime(format!(
+                    "could not read {}: {}",
+                    file.display(),
+                    e
+                )))
+            }
+        };
+
+        let broken = || Error::runtime(format!("{} is broken", file.display()));
+        let Value::Map(pairs) = Value::from_bytes(&data).map_err(|_| broken())? else {
+            return Err(broken());
+        };
+        pairs
+            .into_iter()
+            .map(|(k, v)| match k {
+                Value::String(k) => Ok((k, v)),
+                _ => Err(broken()),
+            })
+            .collect()
+    }
+
+    /// Writes the entries, or fails if they do not fit the quota.
+    fn write(&mut self) -> mlua::Result<()> {
+        let quota = self.quota;
+        let file = self.file.clone();
+        let entries = self.entries()?;
+        let map = Value::Map(
+            entries
+                .iter()
+                .map(|(k, v)| (Value::String(k.clone()), v.clone()))
+                .collect(),
+        );
+        let data = map.to_bytes().map_err(Error::runtime)?;
+        if data.len() > quota {
+            return Err(Error::runtime(format!(
+                "storage would take {} bytes, the quota is {}",
+                data.len(),
+                quota
+            )));
+        }
+
+        let Some(file) = file else {
+            return Ok(());
+        };
+        write_atomically(&file, &data)
+            .map_err(|e| Error::runtime(format!("could not write {}: {}", file.display(), e)))
+    }
+
+    /// Sets `key` to `value` or removes it with `None`. Nothing changes if
+    /// the result can not be written.
+    fn change(&mut self, key: String, value: Option<Value>) -> mlua::Result<()> {
+        let entries = self.entries()?;
+        let old = match value {
+            Some(value) => entries.insert(key.clone(), value),
+            None => entries.remove(&key),
+        };
+
+        let written = self.write();
+        if written.is_err() {
+            let entries = self.entries()?;
+            match old {
+                Some(old) => entries.insert(key, old),
+                None => entries.remove(&key),
+            };
+        }
+        written
+    }
+}
+
+/// A reader never sees a half written file, the new one replaces the old
+/// one only once it is complete.
+fn write_atomically(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = file.with_extension("partial");
+    let mut f = File::create(&partial)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    std::fs::rename(&partial, file)
+}
+
+fn with_store<R>(lua: &Lua, f: impl FnOnce(&mut Store) -> mlua::Result<R>) -> mlua::Result<R> {
+    let mut store = lua
+        .app_data_mut::<Store>()
+        .ok_or_else(|| Error::runtime("storage is not set up"))?;
+    f(&mut store)
+}
+
+/// Sets up the `Storage` global, what it uses comes with `open`.
+pub(crate) fn install(lua: &Lua) -> mlua::Result<()> {
+    let storage = lua.create_table()?;
+
+    storage.set(
+        "get",
+        lua.create_function(|lua, key: String| {
+            let value = with_store(lua, |s| Ok(s.entries()?.get(&key).cloned()))?;
+            match value {
+                Some(value) => from_value(lua, &value),
+                None => Ok(mlua::Value::Nil),
+            }
+        })?,
+    )?;
+
+    storage.set(
+        "set",
+        lua.create_function(|lua, (key, value): (String, mlua::Value)| {
+            let value = match to_value(value)? {
+                Value::Nil => None,
+                value => Some(value),
+            };
+            with_store(lua, |s| s.change(key, value))
+        })?,
+    )?;
+
+    storage.set(
+        "delete",
+        lua.create_function(|lua, key: String| with_store(lua, |s| s.change(key, None)))?,
+    )?;
+
+    storage.set(
+        "list",
+        lua.create_function(|lua, ()| {
+            let keys: Vec<String> =
+                with_store(lua, |s| Ok(s.entries()?.keys().cloned().collect()))?;
+            lua.create_sequence_from(keys)
+        })?,
+    )?;
+
+    lua.globals().set("Storage", storage)
+}
+
+/// The file the package of `manifest` stores in, `None` if the host did
+/// not give a directory. The manifest made sure the name is a file name.
+pub(crate) fn file(manifest: &Manifest) -> Option<PathBuf> {
+    let dir = manifest.storage.as_ref()?;
+    Some(dir.join(format!("{}.storage", manifest.name)))
+}
+
+/// Gives `Storage` the entries in `file`, or entries that only live as
+/// long as the package without one.
+pub(crate) fn open(lua: &Lua, file: Option<PathBuf>, quota: usize) {
+    lua.set_app_data(Store {
+        file,
+        quota,
+        entries: None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+
+    /// A state with `Storage` on `file`.
+    fn storage(file: &Path, quota: usize) -> Lua {
+        let lua = Lua::new();
+        install(&lua).unwrap();
+        open(&lua, Some(file.to_path_buf()), quota);
+        lua
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let scratch = Scratch::new("storage-round-trip");
+        let lua = storage(&scratch.path().join("a.storage"), DEFAULT_QUOTA);
+        lua.load(
+            r#"
+            Storage.set("best", { name = "bed", scores = { 1, 2, 3 } })
+            Storage.set("count", 3)
+            Storage.set("gone", true)
+            Storage.delete("gone")
+            local best = Storage.get("best")
+            assert(best.name == "bed" and best.scores[3] == 3)
+            assert(Storage.get("count") == 3)
+            assert(Storage.get("gone") == nil)
+            local keys = Storage.list()
+            table.sort(keys)
+            assert(#keys == 2 and keys[1] == "best" and keys[2] == "count")
+            "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn entries_persist_across_reopen() {
+        let scratch = Scratch::new("storage-reopen");
+        let file = scratch.path().join("a.storage");
+        storage(&file, DEFAULT_QUOTA)
+            .load(r#"Storage.set("visits", 41)"#)
+            .exec()
+            .unwrap();
+
+        let lua = storage(&file, DEFAULT_QUOTA);
+        lua.load(r#"Storage.set("visits", Storage.get("visits") + 1)"#)
+            .exec()
+            .unwrap();
+        let visits: f64 = storage(&file, DEFAULT_QUOTA)
+            .load(r#"return Storage.get("visits")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(visits, 42.0);
+    }
+
+    #[test]
+    fn quota_keeps_the_previous_entries() {
+        let scratch = Scratch::new("storage-quota");
+        let file = scratch.path().join("a.storage");
+        let lua = storage(&file, 64);
+        lua.load(r#"Storage.set("small", "fits")"#).exec().unwrap();
+        let written = std::fs::read(&file).unwrap();
+
+        let e = lua
+            .load(r#"Storage.set("big", string.rep("x", 100))"#)
+            .exec()
+            .unwrap_err();
+        assert!(e.to_string().contains("the quota is 64"), "{}", e);
+        lua.load(r#"assert(Storage.get("big") == nil and Storage.get("small") == "fits")"#)
+            .exec()
+            .unwrap();
+        // replacing an entry that is there already puts the old one back
+        lua.load(
+            r#"
+            assert(not pcall(Storage.set, "small", string.rep("y", 100)))
+            assert(Storage.get("small") == "fits")
+            "#,
+        )
+        .exec()
+        .unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), written);
+    }
+
+    #[test]
+    fn files_are_replaced_whole() {
+        let scratch = Scratch::new("storage-atomic");
+        let file = scratch.path().join("data").join("a.storage");
+        write_atomically(&file, b"first").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"first");
+        assert!(!file.with_extension("partial").exists());
+
+        // a write that died halfway leaves the last complete file in place
+        std::fs::write(file.with_extension("partial"), b"hal").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"first");
+        write_atomically(&file, b"second").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"second");
+        assert!(!file.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn broken_files_are_reported() {
+        let scratch = Scratch::new("storage-broken");
+        scratch.write("a.storage", "not borsh");
+        let lua = storage(&scratch.path().join("a.storage"), DEFAULT_QUOTA);
+        let e = lua.load(r#"Storage.get("x")"#).exec().unwrap_err();
+        assert!(e.to_string().contains("is broken"), "{}", e);
+    }
+}
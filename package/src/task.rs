@@ -0,0 +1,426 @@
+use std::time::{Duration, Instant};
+
+use mlua::{FromLuaMulti, Function, Lua, MultiValue, Table, Thread, ThreadStatus};
+
+/// Registry key of the value a host function returns when the coroutine
+/// that called it has to wait for the answer.
+const PENDING: &str = "Pending";
+
+/// Wraps a host function, Rust callbacks can not yield by themselves.
+const AWAIT: &str = r#"
+local f, pending = ...
+local yield, isyieldable = coroutine.yield, coroutine.isyieldable
+local pack, unpack = table.pack, table.unpack
+
+local function finish(ok, ...)
+    if not ok then
+        error(..., 0)
+    end
+    return ...
+end
+
+return function(...)
+    local results = pack(f(...))
+    if results.n == 1 and results[1] == pending then
+        if not isyieldable() then
+            error("this only works inside a coroutine", 2)
+        end
+        return finish(yield())
+    end
+    return unpack(results, 1, results.n)
+end
+"#;
+
+const WAIT: &str = r#"
+local schedule = ...
+local yield, isyieldable = coroutine.yield, coroutine.isyieldable
+
+return function(seconds)
+    if not isyieldable() then
+        error("wait only works inside a coroutine", 2)
+    end
+    schedule(seconds or 0)
+    return yield()
+end
+"#;
+
+/// What a timer does when it is due.
+enum Target {
+    /// Runs a callback of `Game.after` or `Game.every` in a new coroutine.
+    Call(Function),
+    /// Resumes a coroutine that called `wait`.
+    Resume(Thread),
+}
+
+struct Timer {
+    id: u32,
+    /// Both in package time, see `Tasks::now`.
+    due: Duration,
+    set: Duration,
+    every: Option<Duration>,
+    target: Target,
+}
+
+/// The timers of a package and the thread it started on.
+struct Tasks {
+    main: Thread,
+    timers: Vec<Timer>,
+    next_id: u32,
+    /// Timers cancelled by their own callback while they ran.
+    cancelled: Vec<u32>,
+    started: Instant,
+    /// Time that only moves with `advance`, for tests.
+    fake: Option<Duration>,
+}
+
+impl Tasks {
+    /// Time since the package started.
+    fn now(&self) -> Duration {
+        self.fake.unwrap_or_else(|| self.started.elapsed())
+    }
+
+    fn add(&mut self, after: Duration, every: Option<Duration>, target: Target) -> u32 {
+        self.next_id += 1;
+        let now = self.now();
+        self.timers.push(Timer {
+            id: self.next_id,
+            due: now + after,
+            set: now,
+            every,
+            target,
+        });
+        self.next_id
+    }
+}
+
+fn duration(seconds: f64) -> mlua::Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| mlua::Error::runtime("seconds must be a positive number"))
+}
+
+/// Sets up `wait`, it has to run on the main thread of the package.
+pub(crate) fn install(lua: &Lua) -> mlua::Result<()> {
+    lua.set_app_data(Tasks {
+        main: lua.current_thread(),
+        timers: Vec::new(),
+        next_id: 0,
+        cancelled: Vec::new(),
+        started: Instant::now(),
+        fake: None,
+    });
+    lua.set_named_registry_value(PENDING, lua.create_table()?)?;
+
+    let schedule = lua.create_function(|lua, seconds: f64| {
+        let after = duration(seconds)?;
+        let thread = lua.current_thread();
+        let mut tasks = lua
+            .app_data_mut::<Tasks>()
+            .ok_or_else(|| mlua::Error::runtime("timers are not set up"))?;
+        tasks.add(after, None, Target::Resume(thread));
+        Ok(())
+    })?;
+    let wait: Function = lua.load(WAIT).set_name("=wait").call(schedule)?;
+    lua.globals().set("wait", wait)
+}
+
+/// Calls `f` in a coroutine once `seconds` have passed, for `Game.after`.
+/// Returns an id for `cancel`.
+pub fn after(lua: &Lua, seconds: f64, f: Function) -> mlua::Result<u32> {
+    let after = duration(seconds)?;
+    let mut tasks = lua
+        .app_data_mut::<Tasks>()
+        .ok_or_else(|| mlua::Error::runtime("timers are not set up"))?;
+    Ok(tasks.add(after, None, Target::Call(f)))
+}
+
+/// Calls `f` in a coroutine every `seconds` until it is cancelled, for
+/// `Game.every`. Returns an id for `cancel`.
+pub fn every(lua: &Lua, seconds: f64, f: Function) -> mlua::Result<u32> {
+    let period = duration(seconds)?;
+    if period.is_zero() {
+        return Err(mlua::Error::runtime("seconds must be more than 0"));
+    }
+    let mut tasks = lua
+        .app_data_mut::<Tasks>()
+        .ok_or_else(|| mlua::Error::runtime("timers are not set up"))?;
+    Ok(tasks.add(period, Some(period), Target::Call(f)))
+}
+
+/// Drops the timer `id`, whether it was still due does not matter.
+pub fn cancel(lua: &Lua, id: u32) -> mlua::Result<()> {
+    if let Some(mut tasks) = lua.app_data_mut::<Tasks>() {
+        let before = tasks.timers.len();
+        tasks.timers.retain(|t| t.id != id);
+        if tasks.timers.len() == before {
+            tasks.cancelled.push(id);
+        }
+    }
+    Ok(())
+}
+
+/// Whether Lua code running right now can wait for something.
+pub(crate) fn in_coroutine(lua: &Lua) -> bool {
+    lua.app_data_ref::<Tasks>()
+        .is_some_and(|t| t.main != lua.current_thread())
+}
+
+/// What a host function returns to make the caller yield.
+pub(crate) fn pending(lua: &Lua) -> mlua::Result<MultiValue> {
+    let pending: Table = lua.named_registry_value(PENDING)?;
+    Ok(MultiValue::from_iter([mlua::Value::Table(pending)]))
+}
+
+/// Wraps a host function that asks the host through `request`, so that
+/// inside a coroutine it yields instead of blocking the package.
+pub fn awaitable(lua: &Lua, f: Function) -> mlua::Result<Function> {
+    let pending: Table = lua.named_registry_value(PENDING)?;
+    lua.load(AWAIT).set_name("=awaitable").call((f, pending))
+}
+
+/// `awaitable` for a method of a userdata, which can not yield as a method.
+/// Offer it as a field instead, the function is made once per package and
+/// kept in the registry under `key`.
+pub fn awaitable_method<A: FromLuaMulti + 'static>(
+    lua: &Lua,
+    key: &str,
+    f: fn(&Lua, A) -> mlua::Result<MultiValue>,
+) -> mlua::Result<Function> {
+    if let Some(method) = lua.named_registry_value::<Option<Function>>(key)? {
+        return Ok(method);
+    }
+    let method = awaitable(lua, lua.create_function(f)?)?;
+    lua.set_named_registry_value(key, &method)?;
+    Ok(method)
+}
+
+/// Hands a coroutine that waited in an awaitable host function what it
+/// waited for.
+pub(crate) fn answer(thread: Thread, result: mlua::Result<MultiValue>) -> mlua::Result<()> {
+    if thread.status() != ThreadStatus::Resumable {
+        return Ok(());
+    }
+    let args = match result {
+        Ok(mut values) => {
+            values.push_front(mlua::Value::Boolean(true));
+            values
+        }
+        Err(e) => {
+            MultiValue::from_iter([mlua::Value::Boolean(false), mlua::Value::Error(Box::new(e))])
+        }
+    };
+    thread.resume(args)
+}
+
+/// Moves the clock of the timers on by `by`. From the first call on they
+/// no longer follow the real time.
+pub(crate) fn advance(lua: &Lua, by: Duration) {
+    if let Some(mut tasks) = lua.app_data_mut::<Tasks>() {
+        tasks.fake = Some(tasks.fake.unwrap_or_default() + by);
+    }
+}
+
+/// Runs the timers that are due. They are looked at once per round, so
+/// they are only as precise as the update rate of the package.
+pub(crate) fn run(lua: &Lua) -> mlua::Result<()> {
+    let (now, due) = {
+        let Some(mut tasks) = lua.app_data_mut::<Tasks>() else {
+            return Ok(());
+        };
+        let now = tasks.now();
+        let (mut due, rest): (Vec<Timer>, Vec<Timer>) =
+            tasks.timers.drain(..).partition(|t| t.due <= now);
+        tasks.timers = rest;
+        due.sort_by_key(|t| t.due);
+        (now, due)
+    };
+
+    for timer in due {
+        match &timer.target {
+            Target::Call(f) => lua.create_thread(f.clone())?.resume::<()>(())?,
+            Target::Resume(thread) => {
+                if thread.status() == ThreadStatus::Resumable {
+                    thread.resume::<()>((now - timer.set).as_secs_f64())?;
+                }
+            }
+        }
+
+        if let Some(period) = timer.every {
+            let Some(mut tasks) = lua.app_data_mut::<Tasks>() else {
+                continue;
+            };
+            if tasks.cancelled.contains(&timer.id) {
+                continue;
+            }
+            // a late timer does not fire several times in a row to catch up
+            let due = (timer.due + period).max(now);
+            tasks.timers.push(Timer {
+                due,
+                set: now,
+                ..timer
+            });
+        }
+    }
+
+    if let Some(mut tasks) = lua.app_data_mut::<Tasks>() {
+        tasks.cancelled.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare Lua with timers on a clock that only moves with `advance`.
+    fn timers() -> Lua {
+        let lua = Lua::new();
+        install(&lua).unwrap();
+        advance(&lua, Duration::ZERO);
+        let every = lua
+            .create_function(|lua, (seconds, f): (f64, Function)| every(lua, seconds, f))
+            .unwrap();
+        let cancel = lua.create_function(|lua, id: u32| cancel(lua, id)).unwrap();
+        lua.globals().set("every", every).unwrap();
+        lua.globals().set("cancel", cancel).unwrap();
+        lua.globals()
+            .set("asking", lua.create_table().unwrap())
+            .unwrap();
+        lua
+    }
+
+    fn tick(lua: &Lua, seconds: f64) {
+        advance(lua, Duration::from_secs_f64(seconds));
+        run(lua).unwrap();
+    }
+
+    fn global<T: mlua::FromLua>(lua: &Lua, name: &str) -> T {
+        lua.globals().get(name).unwrap()
+    }
+
+    #[test]
+    fn waiting_coroutines_resume_after_their_delay() {
+        let lua = timers();
+        lua.load("coroutine.resume(coroutine.create(function() waited = wait(0.5) end))")
+            .exec()
+            .unwrap();
+
+        tick(&lua, 0.4);
+        assert_eq!(global::<Option<f64>>(&lua, "waited"), None);
+        tick(&lua, 0.1);
+        let waited: f64 = global(&lua, "waited");
+        assert!((waited - 0.5).abs() < 1e-9, "waited {waited}");
+    }
+
+    #[test]
+    fn wait_outside_a_coroutine_is_an_error() {
+        let lua = timers();
+        let err = lua.load("wait(1)").exec().unwrap_err();
+        assert!(err.to_string().contains("inside a coroutine"), "{err}");
+    }
+
+    #[test]
+    fn cancel_stops_an_every() {
+        let lua = timers();
+        lua.load(
+            r#"
+            count = 0
+            id = every(1, function() count += 1 end)
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        tick(&lua, 1.0);
+        tick(&lua, 1.0);
+        assert_eq!(global::<u32>(&lua, "count"), 2);
+        lua.load("cancel(id)").exec().unwrap();
+        tick(&lua, 1.0);
+        tick(&lua, 1.0);
+        assert_eq!(global::<u32>(&lua, "count"), 2);
+    }
+
+    #[test]
+    fn an_every_can_cancel_itself() {
+        let lua = timers();
+        lua.load(
+            r#"
+            count = 0
+            id = every(1, function()
+                count += 1
+                if count == 2 then cancel(id) end
+            end)
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        for _ in 0..4 {
+            tick(&lua, 1.0);
+        }
+        assert_eq!(global::<u32>(&lua, "count"), 2);
+    }
+
+    #[test]
+    fn late_timers_run_in_order_and_do_not_catch_up() {
+        let lua = timers();
+        lua.load(
+            r#"
+            order = {}
+            every(1, function() table.insert(order, "every") end)
+            coroutine.resume(coroutine.create(function()
+                wait(0.5)
+                table.insert(order, "wait")
+            end))
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        tick(&lua, 3.5);
+        let order: Vec<String> = global(&lua, "order");
+        assert_eq!(order, ["wait", "every"]);
+    }
+
+    /// Never answers by itself, the test hands the answer to the thread.
+    fn ask(lua: &Lua, _: ()) -> mlua::Result<MultiValue> {
+        let asking: Table = lua.globals().get("asking")?;
+        asking.push(lua.current_thread())?;
+        pending(lua)
+    }
+
+    #[test]
+    fn awaitable_methods_yield_until_answered() {
+        let lua = timers();
+        let ask = awaitable_method(&lua, "Ask", ask).unwrap();
+        assert_eq!(awaitable_method(&lua, "Ask", self::ask).unwrap(), ask);
+        lua.globals().set("ask", ask).unwrap();
+        lua.load(
+            r#"
+            coroutine.resume(coroutine.create(function() answer = ask() end))
+            coroutine.resume(coroutine.create(function()
+                local ok, err = pcall(ask)
+                failed = tostring(err)
+            end))
+            "#,
+        )
+        .exec()
+        .unwrap();
+        assert_eq!(global::<Option<u32>>(&lua, "answer"), None);
+
+        let asking: Vec<Thread> = global(&lua, "asking");
+        let reply = MultiValue::from_iter([mlua::Value::Integer(42)]);
+        answer(asking[0].clone(), Ok(reply)).unwrap();
+        answer(asking[1].clone(), Err(mlua::Error::runtime("no"))).unwrap();
+        assert_eq!(global::<u32>(&lua, "answer"), 42);
+        assert!(global::<String>(&lua, "failed").contains("no"));
+    }
+
+    #[test]
+    fn awaitable_methods_outside_a_coroutine_are_an_error() {
+        let lua = timers();
+        let ask = awaitable_method(&lua, "Ask", ask).unwrap();
+        lua.globals().set("ask", ask).unwrap();
+        let err = lua.load("ask()").exec().unwrap_err();
+        assert!(err.to_string().contains("inside a coroutine"), "{err}");
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    cell::RefCell,
+    fmt::{Display, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use common::value::Value;
+use mlua::{AnyUserData, Error, Function, Lua, Table, ThreadStatus};
+
+use crate::{
+    error::PackageError, load_package, manifest::Manifest, on_reload, on_unload, sandbox::Budget,
+    task, value, App, Event, Loaded, Reply, Request, Service, Setup,
+};
+
+/// Updates a package gets before its tests run unless told otherwise.
+pub const DEFAULT_TICKS: u32 = 10;
+
+/// Updates a test may wait for timers and replies before it fails.
+pub const DEFAULT_TIMEOUT_TICKS: u32 = 1000;
+
+/// The directory of a package with its tests. Every `.luau` file in it
+/// returns a table of test functions by name.
+const TESTS: &str = "tests";
+
+/// The `dt` of a frame synced package, as if the host drew 60 frames a second.
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// The `Test` global, its assertions fail where they are called.
+/// `Test.send` and `Test.reload` stand in for the host.
+const ASSERT: &str = r#"
+local same, show, send, reload = ...
+local format = string.format
+
+local function fail(message, text, ...)
+    text = format(text, ...)
+    error(if message then message .. ": " .. text else text, 3)
+end
+
+local Test = {}
+
+function Test.equal(actual, expected, message)
+    if not same(actual, expected) then
+        fail(message, "expected %s, got %s", show(expected), show(actual))
+    end
+end
+
+function Test.near(actual, expected, tolerance, message)
+    tolerance = tolerance or 1e-6
+    if type(actual) ~= "number" or math.abs(actual - expected) > tolerance then
+        fail(message, "expected %s within %s, got %s", show(expected), show(tolerance), show(actual))
+    end
+end
+
+function Test.fails(f, text, ...)
+    local ok, err = pcall(f, ...)
+    if ok then
+        fail(nil, "expected an error")
+    end
+    err = tostring(err)
+    if text and not string.find(err, text, 1, true) then
+        fail(nil, "expected an error with %q, got %s", text, err)
+    end
+    return err
+end
+
+Test.send = send
+Test.reload = reload
+
+return table.freeze(Test)
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TestOptions {
+    /// Updates before the tests run.
+    pub ticks: u32,
+    /// The `dt` of every update, the period of the schedule if not given.
+    pub dt: Option<Duration>,
+    /// Updates a test may wait before it fails.
+    pub timeout: u32,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        Self {
+            ticks: DEFAULT_TICKS,
+            dt: None,
+            timeout: DEFAULT_TIMEOUT_TICKS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    /// Relative to the package root.
+    pub file: String,
+    /// Empty if the file itself could not be loaded.
+    pub name: String,
+    /// Why the test failed, `None` if it passed.
+    pub error: Option<String>,
+    /// Updates the test waited for.
+    pub ticks: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TestReport {
+    pub package: String,
+    /// Why the package did not get to its tests, like a crash in `OnStart`.
+    pub error: Option<String>,
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.failed() == 0
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|r| r.error.is_some()).count()
+    }
+
+    /// The report on one line for tools like a CI.
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"package\":{},\"passed\":{},\"failed\":{},\"error\":{},\"tests\":[",
+            json(&self.package),
+            self.results.len() - self.failed(),
+            self.failed(),
+            self.error.as_deref().map_or("null".to_string(), json)
+        );
+        for (i, r) in self.results.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}{{\"file\":{},\"name\":{},\"passed\":{},\"ticks\":{},\"error\":{}}}",
+                if i == 0 { "" } else { "," },
+                json(&r.file),
+                json(&r.name),
+                r.error.is_none(),
+                r.ticks,
+                r.error.as_deref().map_or("null".to_string(), json)
+            );
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl Display for TestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for r in self.results.iter() {
+            let test = match r.name.as_str() {
+                "" => r.file.clone(),
+                name => format!("{} {}", r.file, name),
+            };
+            match &r.error {
+                None => writeln!(f, "TEST {} {}: ok", self.package, test)?,
+                Some(e) => writeln!(f, "TEST {} {}: FAILED\n{}", self.package, test, e)?,
+            }
+        }
+        if let Some(e) = &self.error {
+            writeln!(f, "TEST {}: crashed\n{}", self.package, e)?;
+        }
+        write!(
+            f,
+            "TEST {}: {} passed, {} failed",
+            self.package,
+            self.results.len() - self.failed(),
+            self.failed()
+        )
+    }
+}
+
+fn json(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Whether two values are equal, tables by their contents.
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same(a, b))
+        }
+        // the entries come in the order Luau had them in
+        (Value::Map(a), Value::Map(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(k, v)| b.iter().any(|(l, w)| same(k, l) && same(v, w)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Messages a test sent the package, `OnMessage` gets them on the next
+/// update like the ones of the host.
+#[derive(Default)]
+struct Sent(Vec<Value>);
+
+fn install_assertions(rt: &Lua) -> mlua::Result<()> {
+    let same = rt.create_function(|_, (a, b): (mlua::Value, mlua::Value)| {
+        Ok(
+            match (value::to_value(a.clone()), value::to_value(b.clone())) {
+                (Ok(x), Ok(y)) => same(&x, &y),
+                // functions and userdata are only equal to themselves
+                _ => a == b,
+            },
+        )
+    })?;
+    let show = rt.create_function(|_, v: mlua::Value| Ok(value::display(v)))?;
+
+    rt.set_app_data(Sent::default());
+    let send = rt.create_function(|rt, msg: mlua::Value| {
+        // copied like on the way from the host
+        let msg = value::to_value(msg)?;
+        if let Some(mut sent) = rt.app_data_mut::<Sent>() {
+            sent.0.push(msg);
+        }
+        Ok(())
+    })?;
+    // the package keeps its state, unlike on a real reload only what
+    // `OnUnload` hands over is new to it
+    let reload = rt.create_function(|rt, ()| on_reload(rt, &on_unload(rt)?))?;
+
+    let test: Table = rt
+        .load(ASSERT)
+        .set_name("=Test")
+        .call((same, show, send, reload))?;
+    rt.globals().set("Test", test)
+}
+
+/// The test files of the package at `root`, in the order they run.
+fn files(root: &Path) -> Result<Vec<PathBuf>, PackageError> {
+    let dir = root.join(TESTS);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "luau"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Moves the host on by `dt`, what it returns is done on the package.
+type Advance<'a> = &'a mut dyn FnMut(Duration) -> Vec<Event>;
+
+/// A package on a fake clock that only moves when it is updated.
+struct Runner<'a> {
+    rt: &'a Lua,
+    app: AnyUserData,
+    budget: Budget,
+    on_message: Option<Function>,
+    on_update: Option<Function>,
+    advance: RefCell<Advance<'a>>,
+    dt: Duration,
+}
+
+impl Runner<'_> {
+    /// One round like on the package thread, once every coroutine that
+    /// asked the host got its reply. The host moves on first.
+    fn tick<M: Service>(&self) -> mlua::Result<()> {
+        self.budget.start();
+        self.app.borrow_scoped(|app: &App<M>| app.settle())??;
+        let sent = self
+            .rt
+            .app_data_mut::<Sent>()
+            .map(|mut sent| std::mem::take(&mut sent.0))
+            .unwrap_or_default();
+        for msg in sent {
+            if let Some(on_message) = &self.on_message {
+                let _: () = on_message.call(value::from_value(self.rt, &msg)?)?;
+            }
+        }
+        let events = (self.advance.borrow_mut())(self.dt);
+        for event in events {
+            event(self.rt)?;
+        }
+
+        let answered = self.app.borrow_scoped(|app: &App<M>| app.answered())?;
+        for (thread, then, reply) in answered {
+            let result = reply.map_err(Error::runtime).and_then(|r| then(self.rt, r));
+            task::answer(thread, result)?;
+        }
+
+        task::advance(self.rt, self.dt);
+        task::run(self.rt)?;
+        match &self.on_update {
+            Some(on_update) => on_update.call(self.dt.as_secs_f64()),
+            None => Ok(()),
+        }
+    }
+
+    /// The tests in `path` by name.
+    fn load(&self, path: &Path, file: &str) -> Result<Vec<(String, Function)>, PackageError> {
+        let data = std::fs::read_to_string(path)?;
+        self.budget.start();
+        let tests: Table = self
+            .rt
+            .load(&data)
+            .set_name(format!("@{}", file))
+            .call(())?;
+
+        let mut found = Vec::new();
+        for pair in tests.pairs::<String, Function>() {
+            found.push(pair?);
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+
+    /// Runs `test` in a coroutine and updates the package while it waits.
+    /// Returns how many updates it waited for.
+    fn call<M: Service>(&self, test: Function, timeout: u32) -> (u32, Result<(), String>) {
+        let thread = match self.rt.create_thread(test) {
+            Ok(thread) => thread,
+            Err(e) => return (0, Err(e.to_string())),
+        };
+        self.budget.start();
+        if let Err(e) = thread.resume::<()>(()) {
+            return (0, Err(e.to_string()));
+        }
+
+        let mut ticks = 0;
+        while thread.status() == ThreadStatus::Resumable {
+            if ticks == timeout {
+                return (ticks, Err(format!("still waiting after {} updates", ticks)));
+            }
+            ticks += 1;
+            // whatever goes wrong while the test waits is its fault
+            if let Err(e) = self.tick::<M>() {
+                return (ticks, Err(e.to_string()));
+            }
+        }
+        (ticks, Ok(()))
+    }
+}
+
+fn run<M: Service>(
+    manifest: &Manifest,
+    setup: &Setup,
+    options: &TestOptions,
+    app: App<M>,
+    advance: Advance,
+    report: &mut TestReport,
+) -> Result<(), PackageError> {
+    let root = &manifest.root;
+    // nobody else is on the bus, and tests do not touch what the package
+    // stored for real
+    let Loaded { rt, app, budget } =
+        load_package(setup, manifest, mpsc::channel().0, app, None, None, |rt| {
+            install_assertions(rt)?;
+            task::advance(rt, Duration::ZERO);
+            Ok(())
+        })?;
+
+    let runner = Runner {
+        rt: &rt,
+        app,
+        budget,
+        on_message: rt.globals().get("OnMessage")?,
+        on_update: rt.globals().get("OnUpdate")?,
+        advance: RefCell::new(advance),
+        dt: options.dt.or(manifest.schedule.period()).unwrap_or(FRAME),
+    };
+    for _ in 0..options.ticks {
+        runner.tick::<M>()?;
+    }
+
+    for path in files(root)? {
+        let file = format!("{}/{}", TESTS, path.file_name().unwrap().to_string_lossy());
+        let tests = match runner.load(&path, &file) {
+            Ok(tests) => tests,
+            Err(e) => {
+                report.results.push(TestResult {
+                    file,
+                    name: String::new(),
+                    error: Some(e.msg),
+                    ticks: 0,
+                });
+                continue;
+            }
+        };
+
+        for (name, test) in tests {
+            let (ticks, result) = runner.call::<M>(test, options.timeout);
+            report.results.push(TestResult {
+                file: file.clone(),
+                name,
+                error: result.err(),
+                ticks,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the package of `manifest` without its host: `OnStart`, then
+/// `options.ticks` updates, then the tests in its `tests` directory one
+/// after another. Time only passes with updates, so `wait` and the timers
+/// of a test make the package update until they are due. `host` answers
+/// the requests of the package in place of the real host, `advance` moves
+/// it on before every update and returns events for the package, like
+/// the callbacks of a world that ticked. Like on the real host `OnStart`
+/// and `OnUpdate` are called only if the package defines them, and so
+/// are `OnMessage` for `Test.send` and `OnUnload` and `OnReload` for
+/// `Test.reload`.
+pub fn test<M, H, A>(
+    manifest: &Manifest,
+    setup: &Setup,
+    options: &TestOptions,
+    host: H,
+    mut advance: A,
+) -> TestReport
+where
+    M: Service,
+    H: FnMut(M) -> Reply<M> + Send + 'static,
+    A: FnMut(Duration) -> Vec<Event>,
+{
+    let (service_tx, requests) = mpsc::channel::<Request<M>>();
+    let (reply_tx, service_rx) = mpsc::channel();
+    // ends with the package, once nobody can ask anymore
+    std::thread::spawn(move || {
+        let mut host = host;
+        for Request { id, msg } in requests {
+            if reply_tx.send((id, host(msg))).is_err() {
+                break;
+            }
+        }
+    });
+
+    let app = App::new(service_tx, service_rx);
+    let mut report = TestReport {
+        package: manifest.name.clone(),
+        ..Default::default()
+    };
+    if let Err(e) = run(manifest, setup, options, app, &mut advance, &mut report) {
+        report.error = Some(e.msg);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::{Nothing, Scratch};
+
+    fn report(scratch: &Scratch) -> TestReport {
+        test(
+            &scratch.manifest(),
+            &|_| Ok(()),
+            &TestOptions::default(),
+            |_: Nothing| Ok(()),
+            |_| Vec::new(),
+        )
+    }
+
+    #[test]
+    fn tests_run_after_the_package_started() {
+        let scratch = Scratch::package("runner", "");
+        scratch
+            .write(
+                "index.luau",
+                r#"
+                updates = 0
+                function OnUpdate() updates += 1 end
+                "#,
+            )
+            .write(
+                "tests/updates.luau",
+                r#"
+                return {
+                    started = function() Test.equal(updates, 10) end,
+                    waits = function()
+                        wait(1)
+                        Test.equal(updates > 10, true)
+                    end,
+                    fails = function() Test.equal(updates, 0, "updates") end,
+                }
+                "#,
+            );
+        let report = report(&scratch);
+        assert_eq!(report.error, None);
+        let failed: Vec<&str> = report
+            .results
+            .iter()
+            .filter(|r| r.error.is_some())
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(failed, ["fails"]);
+        assert!(report.results[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("updates: expected 0"));
+    }
+
+    #[test]
+    fn tests_can_send_messages_and_reload() {
+        let scratch = Scratch::package("runner-host", "");
+        scratch
+            .write(
+                "index.luau",
+                r#"
+                received = {}
+                generation = 1
+                function OnMessage(msg) table.insert(received, msg) end
+                function OnUnload() return { generation = generation, seen = #received } end
+                function OnReload(previous) generation = previous.generation + 1 end
+                "#,
+            )
+            .write(
+                "tests/host.luau",
+                r#"
+                return {
+                    messages = function()
+                        local msg = { kind = "hello" }
+                        Test.send(msg)
+                        Test.equal(#received, 0, "before the update")
+                        wait(0)
+                        Test.equal(received, { { kind = "hello" } })
+                        Test.equal(rawequal(received[1], msg), false, "copied")
+                        Test.fails(function() Test.send(print) end, "can not be sent")
+                    end,
+                    reload = function()
+                        Test.reload()
+                        Test.equal(generation, 2)
+                    end,
+                }
+                "#,
+            );
+        let report = report(&scratch);
+        assert!(report.passed(), "{report}");
+        assert_eq!(report.results.len(), 2);
+    }
+
+    #[test]
+    fn a_crash_while_starting_is_reported() {
+        let scratch = Scratch::package("runner-crash", "");
+        scratch
+            .write("index.luau", "function OnStart() error('no start') end")
+            .write("tests/never.luau", "return { never = function() end }");
+        let report = report(&scratch);
+        assert!(report.error.unwrap().contains("no start"));
+        assert!(report.results.is_empty());
+    }
+}
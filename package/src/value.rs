@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+
+use common::value::Value;
+pub use common::value::MAX_DEPTH;
+use mlua::{Lua, Table};
+
+/// Copies `value` out of Lua so it can leave the package. Functions,
+/// userdata, threads, NaN and tables that contain themselves can not be copied.
+pub fn to_value(value: mlua::Value) -> mlua::Result<Value> {
+    copy(value, &mut HashSet::new(), 0)
+}
+
+pub fn from_value(lua: &Lua, value: &Value) -> mlua::Result<mlua::Value> {
+    Ok(match value {
+        Value::Nil => mlua::Value::Nil,
+        Value::Boolean(b) => mlua::Value::Boolean(*b),
+        Value::Number(n) => mlua::Value::Number(*n),
+        Value::String(s) => mlua::Value::String(lua.create_string(s)?),
+        Value::Array(items) => {
+            let t = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                t.raw_push(from_value(lua, item)?)?;
+            }
+            mlua::Value::Table(t)
+        }
+        Value::Map(entries) => {
+            let t = lua.create_table_with_capacity(0, entries.len())?;
+            for (k, v) in entries {
+                t.raw_set(from_value(lua, k)?, from_value(lua, v)?)?;
+            }
+            mlua::Value::Table(t)
+        }
+    })
+}
+
+fn copy(value: mlua::Value, seen: &mut HashSet<usize>, depth: usize) -> mlua::Result<Value> {
+    Ok(match value {
+        mlua::Value::Nil => Value::Nil,
+        mlua::Value::Boolean(b) => Value::Boolean(b),
+        mlua::Value::Integer(i) => Value::Number(i as f64),
+        mlua::Value::Number(n) if n.is_nan() => {
+            return Err(mlua::Error::runtime("NaN can not be sent"))
+        }
+        mlua::Value::Number(n) => Value::Number(n),
+        mlua::Value::String(s) => Value::String(s.to_str()?.to_string()),
+        mlua::Value::Table(t) => copy_table(t, seen, depth)?,
+        v => {
+            return Err(mlua::Error::runtime(format!(
+                "a {} can not be sent",
+                v.type_name()
+            )))
+        }
+    })
+}
+
+fn copy_table(t: Table, seen: &mut HashSet<usize>, depth: usize) -> mlua::Result<Value> {
+    if depth >= MAX_DEPTH {
+        return Err(mlua::Error::runtime("table is nested too deep to be sent"));
+    }
+    let ptr = t.to_pointer() as usize;
+    if !seen.insert(ptr) {
+        return Err(mlua::Error::runtime(
+            "table contains itself and can not be sent",
+        ));
+    }
+
+    let len = t.raw_len();
+    let mut entries = Vec::new();
+    for pair in t.pairs::<mlua::Value, mlua::Value>() {
+        let (k, v) = pair?;
+        if !matches!(
+            k,
+            mlua::Value::Boolean(_)
+                | mlua::Value::Integer(_)
+                | mlua::Value::Number(_)
+                | mlua::Value::String(_)
+        ) {
+            return Err(mlua::Error::runtime(format!(
+                "a {} key can not be sent",
+                k.type_name()
+            )));
+        }
+        entries.push((copy(k, seen, depth + 1)?, copy(v, seen, depth + 1)?));
+    }
+    seen.remove(&ptr);
+
+    // a plain sequence stays one, so it keeps its order on the other side
+    if entries.len() == len && len > 0 {
+        let mut items = vec![Value::Nil; len];
+        let mut sequence = true;
+        for (k, v) in entries.iter() {
+            match k {
+                Value::Number(n) if n.fract() == 0.0 && *n >= 1.0 && *n <= len as f64 => {
+                    items[*n as usize - 1] = v.clone();
+                }
+                _ => {
+                    sequence = false;
+                    break;
+                }
+            }
+        }
+        if sequence {
+            return Ok(Value::Array(items));
+        }
+    }
+
+    Ok(Value::Map(entries))
+}
+
+/// What `print` shows for `value`, anything that can not be copied is
+/// shown by its type and address like Luau's `tostring` does.
+pub fn display(value: mlua::Value) -> String {
+    let fallback = format!("{}: {:?}", value.type_name(), value.to_pointer());
+    match to_value(value.clone()) {
+        Ok(v) => v.to_string(),
+        // errors raised by the host say what went wrong through `__tostring`
+        Err(_) => value.to_string().unwrap_or(fallback),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copied(lua: &Lua, code: &str) -> mlua::Result<Value> {
+        to_value(lua.load(code).eval()?)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn sequences_become_arrays_and_the_rest_maps() {
+        let lua = Lua::new();
+        let numbers = Value::Array(vec![Value::Number(1.0), Value::Number(2.5)]);
+        assert_eq!(copied(&lua, "return { 1, 2.5 }").unwrap(), numbers);
+        assert_eq!(
+            copied(&lua, "return { name = 'bed' }").unwrap(),
+            Value::Map(vec![(string("name"), string("bed"))])
+        );
+        assert_eq!(
+            copied(&lua, "return { [2] = true }").unwrap(),
+            Value::Map(vec![(Value::Number(2.0), Value::Boolean(true))])
+        );
+        assert_eq!(copied(&lua, "return {}").unwrap(), Value::Map(vec![]));
+        let Value::Map(mixed) = copied(&lua, "return { 1, 2, x = 3 }").unwrap() else {
+            panic!("a table with a sequence and more is a map");
+        };
+        assert_eq!(mixed.len(), 3);
+    }
+
+    #[test]
+    fn nested_values_round_trip() {
+        let lua = Lua::new();
+        let value = Value::Map(vec![(
+            string("rooms"),
+            Value::Array(vec![
+                Value::Map(vec![(string("beds"), Value::Number(2.0))]),
+                Value::Array(vec![string("a"), Value::Boolean(false)]),
+                string("hall"),
+            ]),
+        )]);
+        let table = from_value(&lua, &value).unwrap();
+        assert_eq!(to_value(table.clone()).unwrap(), value);
+
+        let check = lua
+            .load("local t = ... return t.rooms[1].beds == 2 and t.rooms[2][1] == 'a' and #t.rooms == 3")
+            .into_function()
+            .unwrap();
+        assert!(check.call::<bool>(table).unwrap());
+    }
+
+    #[test]
+    fn what_can_not_be_copied_is_refused() {
+        let lua = Lua::new();
+        lua.globals()
+            .set("data", lua.create_any_userdata(0u8).unwrap())
+            .unwrap();
+        for (code, error) in [
+            ("return print", "a function can not be sent"),
+            ("return data", "a userdata can not be sent"),
+            ("return { inside = { data } }", "a userdata can not be sent"),
+            ("return coroutine.create(print)", "a thread can not be sent"),
+            ("return 0 / 0", "NaN can not be sent"),
+            ("return { [{}] = 1 }", "a table key can not be sent"),
+            ("local t = {} t.t = t return t", "contains itself"),
+        ] {
+            let e = copied(&lua, code).unwrap_err();
+            assert!(e.to_string().contains(error), "{code}: {e}");
+        }
+        // the same table twice is fine, as long as it is not inside itself
+        assert!(copied(&lua, "local t = {} return { t, t }").is_ok());
+    }
+
+    #[test]
+    fn nesting_is_limited_like_on_the_wire() {
+        let lua = Lua::new();
+        let nested = |depth: usize| {
+            copied(
+                &lua,
+                &format!("local t = 0 for i = 1, {depth} do t = {{ t }} end return t"),
+            )
+        };
+
+        let deepest = nested(MAX_DEPTH).unwrap();
+        assert_eq!(
+            Value::from_bytes(&deepest.to_bytes().unwrap()).unwrap(),
+            deepest
+        );
+        let e = nested(MAX_DEPTH + 1).unwrap_err();
+        assert!(e.to_string().contains("nested too deep"), "{e}");
+    }
+}
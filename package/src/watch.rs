@@ -0,0 +1,130 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often the files of a watched package are looked at.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Notices when a file below a package root was added, removed or written.
+pub struct Watcher {
+    root: PathBuf,
+    stamp: (usize, Option<SystemTime>),
+    polled: Instant,
+}
+
+impl Watcher {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            stamp: stamp(root),
+            polled: Instant::now(),
+        }
+    }
+
+    /// Whether anything changed since the last time this returned true.
+    pub fn changed(&mut self) -> bool {
+        if self.polled.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.polled = Instant::now();
+
+        let stamp = stamp(&self.root);
+        if stamp == self.stamp {
+            return false;
+        }
+        self.stamp = stamp;
+        true
+    }
+}
+
+/// Number of files below `root` and the newest modification time among them.
+fn stamp(root: &Path) -> (usize, Option<SystemTime>) {
+    let mut count = 0;
+    let mut newest = None;
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            count += 1;
+            if let Ok(modified) = meta.modified() {
+                newest = newest.max(Some(modified));
+            }
+        }
+    }
+
+    (count, newest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::Scratch;
+
+    /// Looks at the files right away instead of after `POLL_INTERVAL`.
+    fn poll(watcher: &mut Watcher) -> bool {
+        watcher.polled = Instant::now() - POLL_INTERVAL;
+        watcher.changed()
+    }
+
+    /// Writes `file` with a modification time later than any before, file
+    /// systems with a coarse clock would miss a quick write otherwise.
+    fn touch(scratch: &Scratch, file: &str, data: &str, later: u64) {
+        scratch.write(file, data);
+        std::fs::File::options()
+            .write(true)
+            .open(scratch.path().join(file))
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(later))
+            .unwrap();
+    }
+
+    #[test]
+    fn unchanged_trees_are_not_changes() {
+        let scratch = Scratch::new("watch-unchanged");
+        scratch.write("index.luau", "").write("lib/util.luau", "");
+        let mut watcher = Watcher::new(scratch.path());
+        assert!(!poll(&mut watcher));
+        assert!(!poll(&mut watcher));
+    }
+
+    #[test]
+    fn changes_are_noticed_once() {
+        let scratch = Scratch::new("watch-changes");
+        scratch.write("index.luau", "");
+        let mut watcher = Watcher::new(scratch.path());
+
+        touch(&scratch, "index.luau", "print(1)", 10);
+        assert!(poll(&mut watcher));
+        assert!(!poll(&mut watcher));
+
+        touch(&scratch, "lib/util.luau", "", 20);
+        assert!(poll(&mut watcher));
+        assert!(!poll(&mut watcher));
+
+        std::fs::remove_file(scratch.path().join("lib/util.luau")).unwrap();
+        assert!(poll(&mut watcher));
+        assert!(!poll(&mut watcher));
+    }
+
+    #[test]
+    fn files_are_only_looked_at_now_and_then() {
+        let scratch = Scratch::new("watch-interval");
+        scratch.write("index.luau", "");
+        let mut watcher = Watcher::new(scratch.path());
+
+        touch(&scratch, "index.luau", "print(1)", 10);
+        assert!(!watcher.changed());
+        assert!(poll(&mut watcher));
+    }
+}
@@ -1,3 +1,338 @@
+use std::collections::BTreeMap;
+
+use common::vector;
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, Table, UserData, UserDataRef};
+
+use crate::{
+    message::{AgentState, ServiceMessage, ServiceReply},
+    navigation::{self, NavGrid},
+    object::SmartObjects,
+    social::{Episode, Invitation, Memory, Peer, Relationship, Social},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Need {
+    Food,
+    Pleasure,
+    Intimacy,
+    Acceptance,
+    Belonging,
+    Security,
+    Purpose,
+    Rest,
+    Beauty,
+    Awe,
+    Spirituality,
+    Contentment,
+}
+
+impl Need {
+    pub const ALL: [Need; 12] = [
+        Need::Food,
+        Need::Pleasure,
+        Need::Intimacy,
+        Need::Acceptance,
+        Need::Belonging,
+        Need::Security,
+        Need::Purpose,
+        Need::Rest,
+        Need::Beauty,
+        Need::Awe,
+        Need::Spirituality,
+        Need::Contentment,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Need::Food => "Food",
+            Need::Pleasure => "Pleasure",
+            Need::Intimacy => "Intimacy",
+            Need::Acceptance => "Acceptance",
+            Need::Belonging => "Belonging",
+            Need::Security => "Security",
+            Need::Purpose => "Purpose",
+            Need::Rest => "Rest",
+            Need::Beauty => "Beauty",
+            Need::Awe => "Awe",
+            Need::Spirituality => "Spirituality",
+            Need::Contentment => "Contentment",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Need> {
+        Need::ALL.into_iter().find(|n| n.name() == name)
+    }
+
+    /// How much of the need is lost per simulated hour.
+    pub fn decay(self) -> f32 {
+        match self {
+            Need::Food => 1.0 / 8.0,
+            Need::Pleasure => 1.0 / 12.0,
+            Need::Intimacy => 1.0 / 48.0,
+            Need::Acceptance => 1.0 / 24.0,
+            Need::Belonging => 1.0 / 24.0,
+            Need::Security => 1.0 / 36.0,
+            Need::Purpose => 1.0 / 16.0,
+            Need::Rest => 1.0 / 18.0,
+            Need::Beauty => 1.0 / 24.0,
+            Need::Awe => 1.0 / 72.0,
+            Need::Spirituality => 1.0 / 72.0,
+            Need::Contentment => 1.0 / 24.0,
+        }
+    }
+
+    /// Importance when several needs compete, the basic ones come first.
+    pub fn weight(self) -> f32 {
+        match self {
+            Need::Food | Need::Rest => 3.0,
+            Need::Security => 2.0,
+            Need::Intimacy | Need::Acceptance | Need::Belonging => 1.5,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Needs below this value are critical and interrupt whatever the agent does.
+pub const CRITICAL: f32 = 0.15;
+
+/// Agents rather idle than do something with less utility.
+pub const MIN_UTILITY: f32 = 0.01;
+
+/// How far away agents notice smart objects, in metres.
+pub const PERCEPTION_RADIUS: f32 = 30.0;
+
+/// Metres an agent covers per simulated hour.
+pub const WALK_SPEED: f32 = 5000.0;
+
+/// Agents this close to the target of their activity have arrived.
+pub const ARRIVE_RADIUS: f32 = 0.5;
+
+/// Agents that can not get closer to their target still reach it from
+/// this far away, past that it is out of reach.
+pub const REACH: f32 = 2.0;
+
+/// How far apart two agents stand while they interact, in metres.
+pub const SOCIAL_DISTANCE: f32 = 1.0;
+
+/// Affinity lost towards an agent that turned an invitation down.
+pub const REJECTION: f32 = 0.05;
+
+/// Satisfaction of every need, from 0 (deprived) to 1 (fulfilled).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Needs {
+    values: [f32; 12],
+}
+
+impl Needs {
+    pub fn get(&self, need: Need) -> f32 {
+        self.values[need.index()]
+    }
+
+    pub fn set(&mut self, need: Need, value: f32) {
+        self.values[need.index()] = value.clamp(0.0, 1.0);
+    }
+
+    pub fn add(&mut self, need: Need, amount: f32) {
+        self.set(need, self.get(need) + amount);
+    }
+
+    /// How bad the agent feels about this need, grows fast when it runs low.
+    pub fn discomfort(need: Need, value: f32) -> f32 {
+        need.weight() * (1.0 - value).powi(2)
+    }
+
+    pub fn critical(&self) -> impl Iterator<Item = Need> + '_ {
+        Need::ALL.into_iter().filter(|n| self.get(*n) < CRITICAL)
+    }
+
+    fn decay(&mut self, hours: f32) {
+        for n in Need::ALL {
+            self.add(n, -n.decay() * hours);
+        }
+    }
+}
+
+impl Default for Needs {
+    fn default() -> Self {
+        Self { values: [1.0; 12] }
+    }
+}
+
+/// Something an agent can do, the effects are spread over the duration.
+#[derive(Debug, Clone)]
+pub struct Action {
+    pub name: String,
+    /// Simulated hours.
+    pub duration: f32,
+    pub effects: Vec<(Need, f32)>,
+    /// Set when it takes a partner, who gets the same effects.
+    pub social: Option<Social>,
+}
+
+impl Action {
+    pub fn new(name: &str, duration: f32, effects: &[(Need, f32)]) -> Self {
+        Self {
+            name: name.into(),
+            duration,
+            effects: effects.to_vec(),
+            social: None,
+        }
+    }
+
+    pub fn social(name: &str, duration: f32, effects: &[(Need, f32)], social: Social) -> Self {
+        Self {
+            social: Some(social),
+            ..Self::new(name, duration, effects)
+        }
+    }
+
+    /// Discomfort the action would take away from an agent with these
+    /// needs, per simulated hour it takes including `travel` hours to get there.
+    pub fn utility(&self, needs: &Needs, travel: f32) -> f32 {
+        let relief: f32 = self
+            .effects
+            .iter()
+            .map(|(n, amount)| {
+                let v = needs.get(*n);
+                Needs::discomfort(*n, v) - Needs::discomfort(*n, (v + amount).min(1.0))
+            })
+            .sum();
+
+        relief / (self.duration + travel).max(f32::EPSILON)
+    }
+
+    fn satisfies(&self, need: Need) -> bool {
+        self.effects.iter().any(|(n, a)| *n == need && *a > 0.0)
+    }
+}
+
+/// Actions every agent can do on its own, without anything around it.
+pub fn innate_actions() -> Vec<Action> {
+    vec![
+        Action::new("sleep", 8.0, &[(Need::Rest, 0.9), (Need::Contentment, 0.1)]),
+        Action::new("nap", 1.0, &[(Need::Rest, 0.15)]),
+        Action::new(
+            "daydream",
+            0.5,
+            &[(Need::Pleasure, 0.1), (Need::Contentment, 0.1)],
+        ),
+        Action::new(
+            "meditate",
+            1.0,
+            &[(Need::Spirituality, 0.3), (Need::Contentment, 0.15)],
+        ),
+        Action::new("stargaze", 1.0, &[(Need::Awe, 0.3), (Need::Beauty, 0.15)]),
+        Action::new("work", 4.0, &[(Need::Purpose, 0.5), (Need::Rest, -0.1)]),
+    ]
+}
+
+/// Actions two agents do together, the only way to meet the social needs.
+pub fn social_actions() -> Vec<Action> {
+    vec![
+        Action::social(
+            "chat",
+            0.5,
+            &[
+                (Need::Belonging, 0.15),
+                (Need::Acceptance, 0.05),
+                (Need::Pleasure, 0.05),
+            ],
+            Social {
+                affinity: 0.05,
+                familiarity: 0.1,
+                min_affinity: -0.3,
+                min_familiarity: 0.0,
+            },
+        ),
+        Action::social(
+            "comfort",
+            0.5,
+            &[(Need::Acceptance, 0.25), (Need::Security, 0.05)],
+            Social {
+                affinity: 0.1,
+                familiarity: 0.05,
+                min_affinity: 0.1,
+                min_familiarity: 0.1,
+            },
+        ),
+        Action::social(
+            "embrace",
+            0.25,
+            &[(Need::Intimacy, 0.3), (Need::Acceptance, 0.1)],
+            Social {
+                affinity: 0.1,
+                familiarity: 0.05,
+                min_affinity: 0.4,
+                min_familiarity: 0.3,
+            },
+        ),
+    ]
+}
+
+/// What an agent sees of the world while it decides.
+pub struct Surroundings<'a> {
+    /// Simulated hours since the world started.
+    pub now: f32,
+    pub actions: &'a [Action],
+    pub peers: &'a [Peer],
+}
+
+/// Where the action of an activity comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Index into the actions of the world.
+    Innate(usize),
+    /// An action advertised by a smart object the agent has reserved.
+    Object { object: u32, action: usize },
+    /// A social action of the world done together with `partner`.
+    Social { partner: u32, action: usize },
+}
+
+impl Source {
+    pub fn action<'a>(
+        &self,
+        actions: &'a [Action],
+        objects: &'a SmartObjects,
+    ) -> Option<&'a Action> {
+        match *self {
+            Source::Innate(i) | Source::Social { action: i, .. } => actions.get(i),
+            Source::Object { object, action } => objects.get(object)?.actions.get(action),
+        }
+    }
+}
+
+/// Something about an agent that packages get told about.
+#[derive(Debug, Clone)]
+pub enum AgentEvent {
+    /// The agent started a new activity.
+    Decided { agent: u32, action: String },
+    /// The need just dropped below `CRITICAL`.
+    NeedCritical { agent: u32, need: Need },
+}
+
+impl AgentEvent {
+    /// The global function of a package that is called for the event.
+    pub fn callback(&self) -> &'static str {
+        match self {
+            AgentEvent::Decided { .. } => "OnAgentDecide",
+            AgentEvent::NeedCritical { .. } => "OnNeedCritical",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Activity {
+    pub source: Source,
+    /// Where the agent still has to go before it can start.
+    pub target: Option<[f32; 3]>,
+    /// Simulated hours left.
+    pub remaining: f32,
+}
+
 /// The 12 Basic Needs:
 /// 1. Food/Water,
 /// 2. Pleasure,
@@ -8,7 +343,670 @@
 /// 7. Purpose,
 /// 8. Rest,
 /// 9. Beauty,
-/// 10. Awe     
+/// 10. Awe
 /// 11. Spirituality,
 /// 12. Contentment
-pub struct Agent {}
+pub struct Agent {
+    pub id: u32,
+    /// Scene node that shows the agent, if any.
+    pub node: Option<u32>,
+    pub position: [f32; 3],
+    pub needs: Needs,
+    pub activity: Option<Activity>,
+    /// Waypoints left to the target of the activity.
+    pub path: Vec<[f32; 3]>,
+    pub relationships: BTreeMap<u32, Relationship>,
+    pub memory: Memory,
+}
+
+impl Agent {
+    pub fn new(id: u32, position: [f32; 3]) -> Self {
+        Self {
+            id,
+            node: None,
+            position,
+            needs: Needs::default(),
+            activity: None,
+            path: Vec::new(),
+            relationships: BTreeMap::new(),
+            memory: Memory::default(),
+        }
+    }
+
+    pub fn relationship(&self, other: u32) -> Relationship {
+        self.relationships.get(&other).copied().unwrap_or_default()
+    }
+
+    /// Who the agent is busy with right now.
+    pub fn partner(&self) -> Option<u32> {
+        match self.activity.as_ref()?.source {
+            Source::Social { partner, .. } => Some(partner),
+            _ => None,
+        }
+    }
+
+    pub fn peer(&self) -> Peer {
+        Peer {
+            id: self.id,
+            position: self.position,
+            partner: self.partner(),
+            ready: self.activity.as_ref().is_none_or(|a| a.target.is_none()),
+        }
+    }
+
+    /// How much the agent would rather do `action` with `other`, from
+    /// what it thinks of them and remembers about them.
+    pub fn preference(&self, other: u32, action: &str, now: f32) -> f32 {
+        let affinity = self.relationship(other).affinity;
+        let mood = self.memory.mood_towards(other, action, now);
+        (1.0 + affinity + mood).clamp(0.1, 2.0)
+    }
+
+    /// Every action the agent could start right now together with the
+    /// hours it takes to get there: its innate ones and those of nearby
+    /// smart objects that still have room.
+    pub fn candidates<'a>(
+        &'a self,
+        surroundings: &'a Surroundings<'a>,
+        objects: &'a SmartObjects,
+    ) -> impl Iterator<Item = (Source, &'a Action, f32)> + 'a {
+        let actions = surroundings.actions.iter().enumerate();
+
+        let innate = actions
+            .clone()
+            .filter(|(_, a)| a.social.is_none())
+            .map(|(i, a)| (Source::Innate(i), a, 0.0));
+
+        let social = actions
+            .filter_map(|(i, a)| Some((i, a, a.social?)))
+            .flat_map(move |(i, a, social)| {
+                surroundings
+                    .peers
+                    .iter()
+                    .filter(move |p| {
+                        p.id != self.id
+                            && p.partner.is_none()
+                            && vector::distance(&self.position, &p.position) <= PERCEPTION_RADIUS
+                            && self.relationship(p.id).allows(&social)
+                    })
+                    .map(move |p| {
+                        let travel = vector::distance(&self.position, &p.position) / WALK_SPEED;
+                        (
+                            Source::Social {
+                                partner: p.id,
+                                action: i,
+                            },
+                            a,
+                            travel,
+                        )
+                    })
+            });
+
+        let offered = objects
+            .nearby(&self.position, PERCEPTION_RADIUS)
+            .filter(|o| o.is_available(self.id))
+            .flat_map(move |o| {
+                let travel = vector::distance(&self.position, &o.position) / WALK_SPEED;
+                o.actions.iter().enumerate().map(move |(i, a)| {
+                    (
+                        Source::Object {
+                            object: o.id,
+                            action: i,
+                        },
+                        a,
+                        travel,
+                    )
+                })
+            });
+
+        innate.chain(offered).chain(social)
+    }
+
+    /// The candidate with the highest utility. Ties go to the first one
+    /// so the outcome only depends on the inputs.
+    pub fn choose(&self, surroundings: &Surroundings, objects: &SmartObjects) -> Option<Source> {
+        let mut best = None;
+        let mut best_utility = MIN_UTILITY;
+
+        for (source, a, travel) in self.candidates(surroundings, objects) {
+            let mut u = a.utility(&self.needs, travel);
+            if let Source::Social { partner, .. } = source {
+                u *= self.preference(partner, &a.name, surroundings.now);
+            }
+            if u > best_utility {
+                best = Some(source);
+                best_utility = u;
+            }
+        }
+
+        best
+    }
+
+    /// Ends the current activity and gives back its reservation.
+    pub fn stop(&mut self, objects: &mut SmartObjects) {
+        self.path.clear();
+        if let Some(Activity {
+            source: Source::Object { object, .. },
+            ..
+        }) = self.activity.take()
+        {
+            if let Some(o) = objects.get_mut(object) {
+                o.release(self.id);
+            }
+        }
+    }
+
+    /// Advances the agent by `hours` of simulated time. Starting a social
+    /// action returns the invitation its partner has to answer.
+    pub fn tick(
+        &mut self,
+        hours: f32,
+        surroundings: &Surroundings,
+        objects: &mut SmartObjects,
+    ) -> Option<Invitation> {
+        let actions = surroundings.actions;
+        self.needs.decay(hours);
+
+        // drop the activity if its object or partner is gone, or if
+        // something critical comes up that another action would help with
+        // more, carrying on must win otherwise or the agent starts over
+        // and over again
+        if let Some(act) = &self.activity {
+            let interrupt = match act.source.action(actions, objects) {
+                None => true,
+                Some(action) => {
+                    let left = match act.source {
+                        Source::Social { partner, .. } => !surroundings
+                            .peers
+                            .iter()
+                            .any(|p| p.id == partner && p.partner == Some(self.id)),
+                        _ => false,
+                    };
+                    if left {
+                        self.memory.remember(Episode {
+                            time: surroundings.now,
+                            action: action.name.clone(),
+                            with: self.partner(),
+                            valence: -0.5,
+                        });
+                    }
+
+                    let carry_on = action.utility(&self.needs, 0.0);
+                    left || self.needs.critical().any(|n| {
+                        !action.satisfies(n)
+                            && self
+                                .candidates(surroundings, objects)
+                                .any(|(_, a, travel)| {
+                                    a.satisfies(n) && a.utility(&self.needs, travel) > carry_on
+                                })
+                    })
+                }
+            };
+            if interrupt {
+                self.stop(objects);
+            }
+        }
+
+        let mut invitation = None;
+        if self.activity.is_none() {
+            if let Some(source) = self.choose(surroundings, objects) {
+                let reserved = match source {
+                    Source::Object { object, .. } => {
+                        objects.get_mut(object).is_some_and(|o| o.reserve(self.id))
+                    }
+                    _ => true,
+                };
+                if let (true, Some(action)) = (reserved, source.action(actions, objects)) {
+                    let target = match source {
+                        Source::Innate(_) => None,
+                        Source::Object { object, .. } => objects.get(object).map(|o| o.position),
+                        Source::Social { partner, action } => {
+                            invitation = Some(Invitation {
+                                from: self.id,
+                                to: partner,
+                                action,
+                            });
+                            surroundings
+                                .peers
+                                .iter()
+                                .find(|p| p.id == partner)
+                                .map(|p| self.meeting_point(&p.position))
+                        }
+                    };
+                    self.activity = Some(Activity {
+                        source,
+                        target,
+                        remaining: action.duration,
+                    });
+                }
+            }
+        }
+
+        let Some(act) = self.activity.as_mut().filter(|a| a.target.is_none()) else {
+            return invitation;
+        };
+        let Some(action) = act.source.action(actions, objects) else {
+            return invitation;
+        };
+        // a social action only starts once the inviter arrived, both do it
+        // at the same pace then and end it together
+        if let Source::Social { partner, .. } = act.source {
+            if !surroundings
+                .peers
+                .iter()
+                .any(|p| p.id == partner && p.ready)
+            {
+                return invitation;
+            }
+        }
+
+        let step = hours.min(act.remaining);
+        for (n, amount) in action.effects.iter() {
+            self.needs.add(*n, amount * step / action.duration);
+        }
+        act.remaining -= step;
+        if act.remaining <= 0.0 {
+            if let (Source::Social { partner, .. }, Some(social)) = (act.source, action.social) {
+                self.relationships
+                    .entry(partner)
+                    .or_default()
+                    .interact(&social, surroundings.now);
+                self.memory.remember(Episode {
+                    time: surroundings.now,
+                    action: action.name.clone(),
+                    with: Some(partner),
+                    valence: 1.0,
+                });
+            }
+            self.stop(objects);
+        }
+
+        invitation
+    }
+
+    /// Drops whatever the agent does for the action called `name`, one it
+    /// can do on its own. Returns false if there is no such action.
+    pub fn perform(&mut self, name: &str, actions: &[Action], objects: &mut SmartObjects) -> bool {
+        let Some((i, action)) = actions
+            .iter()
+            .enumerate()
+            .find(|(_, a)| a.name == name && a.social.is_none())
+        else {
+            return false;
+        };
+
+        self.stop(objects);
+        self.activity = Some(Activity {
+            source: Source::Innate(i),
+            target: None,
+            remaining: action.duration,
+        });
+        true
+    }
+
+    /// Name of the action the agent is busy with.
+    pub fn doing<'a>(&self, actions: &'a [Action], objects: &'a SmartObjects) -> Option<&'a str> {
+        let act = self.activity.as_ref()?;
+        act.source.action(actions, objects).map(|a| a.name.as_str())
+    }
+
+    /// Answers an invitation, joining in drops whatever the agent did.
+    pub fn invited(
+        &mut self,
+        invitation: &Invitation,
+        actions: &[Action],
+        objects: &mut SmartObjects,
+    ) -> bool {
+        // both asked each other at once, the inviter comes over
+        if self.partner() == Some(invitation.from) {
+            self.arrive();
+            return true;
+        }
+
+        let Some(action) = actions.get(invitation.action) else {
+            return false;
+        };
+        let Some(social) = action.social else {
+            return false;
+        };
+        if self.partner().is_some()
+            || self.needs.critical().next().is_some()
+            || !self.relationship(invitation.from).allows(&social)
+        {
+            return false;
+        }
+
+        self.stop(objects);
+        self.activity = Some(Activity {
+            source: Source::Social {
+                partner: invitation.from,
+                action: invitation.action,
+            },
+            target: None,
+            remaining: action.duration,
+        });
+        true
+    }
+
+    /// The partner turned the invitation down.
+    pub fn rejected(
+        &mut self,
+        invitation: &Invitation,
+        actions: &[Action],
+        now: f32,
+        objects: &mut SmartObjects,
+    ) {
+        let relationship = self.relationships.entry(invitation.to).or_default();
+        relationship.affinity = (relationship.affinity - REJECTION).max(-1.0);
+
+        if let Some(action) = actions.get(invitation.action) {
+            self.memory.remember(Episode {
+                time: now,
+                action: action.name.clone(),
+                with: Some(invitation.to),
+                valence: -1.0,
+            });
+        }
+        self.stop(objects);
+    }
+
+    /// Where to stand to interact with an agent at `other`.
+    fn meeting_point(&self, other: &[f32; 3]) -> [f32; 3] {
+        let mut away = vector::sub(&self.position, other);
+        if vector::length(&away) <= SOCIAL_DISTANCE {
+            return self.position;
+        }
+        vector::normalize(&mut away);
+        vector::add(other, &vector::scale(&away, SOCIAL_DISTANCE))
+    }
+
+    /// Walks towards the target of the activity for `hours`, steering
+    /// around the agents at `others`. Returns false when the target can
+    /// not be reached.
+    pub fn walk(&mut self, hours: f32, grid: &NavGrid, others: &[[f32; 3]]) -> bool {
+        let Some(target) = self.activity.as_ref().and_then(|a| a.target) else {
+            return true;
+        };
+
+        if vector::distance(&self.position, &target) <= ARRIVE_RADIUS {
+            self.arrive();
+            return true;
+        }
+
+        if self.path.is_empty() {
+            match grid.find_path(&self.position, &target) {
+                Some(path) => self.path = path,
+                None => return false,
+            }
+            if self.path.is_empty() {
+                return self.reach(&target);
+            }
+        }
+
+        let mut budget = WALK_SPEED * hours;
+        while let Some(next) = self.path.first().copied() {
+            let d = vector::distance(&self.position, &next);
+            if d <= budget {
+                self.position = next;
+                self.path.remove(0);
+                budget -= d;
+                // the last waypoint is as close as the grid gets
+                if self.path.is_empty() {
+                    return self.reach(&target);
+                }
+                continue;
+            }
+
+            let mut direction = vector::sub(&next, &self.position);
+            vector::normalize(&mut direction);
+            let steered = navigation::avoid(&self.position, &direction, others);
+
+            // avoidance must not push the agent into a wall
+            let step = vector::add(&self.position, &vector::scale(&steered, budget));
+            self.position = if grid.is_walkable(&step) {
+                step
+            } else {
+                vector::add(&self.position, &vector::scale(&direction, budget))
+            };
+            // only a detour that lost sight of the next waypoint needs a new path
+            if steered != direction && !grid.line_of_sight(&self.position, &next) {
+                self.path.clear();
+            }
+            break;
+        }
+
+        true
+    }
+
+    /// Arrives if `target` is within reach from where the agent stands.
+    fn reach(&mut self, target: &[f32; 3]) -> bool {
+        if vector::distance(&self.position, target) > REACH {
+            return false;
+        }
+        self.arrive();
+        true
+    }
+
+    fn arrive(&mut self) {
+        self.path.clear();
+        if let Some(act) = &mut self.activity {
+            act.target = None;
+        }
+    }
+}
+
+fn need_arg(name: &str) -> mlua::Result<Need> {
+    Need::from_name(name).ok_or_else(|| mlua::Error::runtime(format!("unknown need {}", name)))
+}
+
+/// Reads an action like `{ name = "eat", duration = 0.5, effects = { Food = 0.4 } }`.
+pub fn lua_action(table: Table) -> mlua::Result<Action> {
+    let name: String = table.get("name")?;
+    let duration: f32 = table.get("duration")?;
+    // NaN fails both, an agent would never finish the action
+    if !duration.is_finite() || duration <= 0.0 {
+        return Err(mlua::Error::runtime("action duration must be positive"));
+    }
+
+    let mut effects = Vec::new();
+    for pair in table.get::<Table>("effects")?.pairs::<String, f32>() {
+        let (need, amount) = pair?;
+        if !amount.is_finite() {
+            return Err(mlua::Error::runtime(format!(
+                "effect on {} must be a number",
+                need
+            )));
+        }
+        effects.push((need_arg(&need)?, amount));
+    }
+    effects.sort_by_key(|(n, _)| n.index());
+
+    Ok(Action::new(&name, duration, &effects))
+}
+
+pub fn lua_action_define(lua: &Lua, table: Table) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let msg = ServiceMessage::DefineAction(lua_action(table)?);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::Done(true) => Ok(()),
+        _ => Err(mlua::Error::runtime("could not define action")),
+    })
+}
+
+pub fn lua_agent_spawn(lua: &Lua, (x, y, z): (f32, f32, f32)) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let msg = ServiceMessage::SpawnAgent([x, y, z]);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::SpawnedAgent(id) => Ok(LuaAgent { id }),
+        _ => Err(mlua::Error::runtime("could not spawn agent")),
+    })
+}
+
+/// Runs `OnAgentDecide` in a coroutine of its own, so that replacing the
+/// decision waits for the server without blocking the package.
+const DECIDE: &str = r#"
+local decide, perform, agent, action = ...
+local other = decide(agent, action)
+if other ~= nil and other ~= action then
+    perform(agent, other)
+end
+"#;
+
+/// Calls `OnAgentDecide` or `OnNeedCritical` of a package, if it has them.
+/// An action name returned from `OnAgentDecide` replaces the decision.
+pub fn lua_agent_event(event: AgentEvent) -> package::Event {
+    Box::new(move |lua| {
+        let Some(cb) = lua.globals().get::<Option<Function>>(event.callback())? else {
+            return Ok(());
+        };
+        match event {
+            AgentEvent::Decided { agent, action } => {
+                let decide = match lua.named_registry_value::<Option<Function>>("AgentDecide")? {
+                    Some(decide) => decide,
+                    None => {
+                        let decide = lua.load(DECIDE).set_name("=decide").into_function()?;
+                        lua.set_named_registry_value("AgentDecide", &decide)?;
+                        decide
+                    }
+                };
+                let perform = package::awaitable_method(lua, "AgentPerform", lua_agent_perform)?;
+                let thread = lua.create_thread(decide)?;
+                thread.resume::<()>((cb, perform, LuaAgent { id: agent }, action))?;
+            }
+            AgentEvent::NeedCritical { agent, need } => {
+                cb.call::<()>((LuaAgent { id: agent }, need.name()))?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Asks for the state of agent `id` and hands it to `then`.
+fn lua_agent_state<R, F>(lua: &Lua, id: u32, then: F) -> mlua::Result<MultiValue>
+where
+    R: IntoLuaMulti,
+    F: FnOnce(&Lua, AgentState) -> mlua::Result<R> + 'static,
+{
+    package::request(
+        lua,
+        ServiceMessage::GetAgent(id),
+        move |lua, answer| match answer {
+            ServiceReply::GotAgent(Some(state)) => then(lua, state),
+            _ => Err(mlua::Error::runtime(format!("agent {} is gone", id))),
+        },
+    )
+}
+
+fn lua_agent_needs(lua: &Lua, me: UserDataRef<LuaAgent>) -> mlua::Result<MultiValue> {
+    lua_agent_state(lua, me.id, |lua, state| {
+        let needs = lua.create_table()?;
+        for n in Need::ALL {
+            needs.set(n.name(), state.needs.get(n))?;
+        }
+        Ok(needs)
+    })
+}
+
+fn lua_agent_need(
+    lua: &Lua,
+    (me, name): (UserDataRef<LuaAgent>, String),
+) -> mlua::Result<MultiValue> {
+    let need = need_arg(&name)?;
+    lua_agent_state(lua, me.id, move |_lua, state| Ok(state.needs.get(need)))
+}
+
+fn lua_agent_set_need(
+    lua: &Lua,
+    (me, name, value): (UserDataRef<LuaAgent>, String, f32),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let id = me.id;
+    let msg = ServiceMessage::SetNeed(id, need_arg(&name)?, value);
+    package::request(lua, msg, move |_lua, answer| match answer {
+        ServiceReply::Done(true) => Ok(()),
+        _ => Err(mlua::Error::runtime(format!("agent {} is gone", id))),
+    })
+}
+
+fn lua_agent_activity(lua: &Lua, me: UserDataRef<LuaAgent>) -> mlua::Result<MultiValue> {
+    lua_agent_state(lua, me.id, |_lua, state| Ok(state.activity))
+}
+
+fn lua_agent_position(lua: &Lua, me: UserDataRef<LuaAgent>) -> mlua::Result<MultiValue> {
+    lua_agent_state(lua, me.id, |_lua, state| {
+        let [x, y, z] = state.position;
+        Ok((x, y, z))
+    })
+}
+
+fn lua_agent_perform(
+    lua: &Lua,
+    (me, name): (UserDataRef<LuaAgent>, String),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let msg = ServiceMessage::Perform(me.id, name);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::Done(done) => Ok(done),
+        _ => Err(mlua::Error::runtime("could not perform action")),
+    })
+}
+
+#[derive(Clone, Copy)]
+pub struct LuaAgent {
+    pub id: u32,
+}
+
+impl UserData for LuaAgent {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, me| Ok(me.id));
+
+        // the methods ask the server, so they are awaitable functions
+        fields.add_field_function_get("needs", |lua, _| {
+            package::awaitable_method(lua, "AgentNeeds", lua_agent_needs)
+        });
+        fields.add_field_function_get("need", |lua, _| {
+            package::awaitable_method(lua, "AgentNeed", lua_agent_need)
+        });
+        fields.add_field_function_get("setNeed", |lua, _| {
+            package::awaitable_method(lua, "AgentSetNeed", lua_agent_set_need)
+        });
+        fields.add_field_function_get("activity", |lua, _| {
+            package::awaitable_method(lua, "AgentActivity", lua_agent_activity)
+        });
+        fields.add_field_function_get("position", |lua, _| {
+            package::awaitable_method(lua, "AgentPosition", lua_agent_position)
+        });
+        fields.add_field_function_get("perform", |lua, _| {
+            package::awaitable_method(lua, "AgentPerform", lua_agent_perform)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(lua: &Lua, code: &str) -> mlua::Result<Action> {
+        lua_action(lua.load(code).eval()?)
+    }
+
+    #[test]
+    fn actions_need_a_real_duration() {
+        let lua = Lua::new();
+        let nap = action(
+            &lua,
+            "return { name = 'nap', duration = 0.5, effects = { Rest = 0.3 } }",
+        );
+        assert_eq!(nap.unwrap().name, "nap");
+
+        for duration in ["0", "-1", "0/0", "math.huge"] {
+            let code = format!(
+                "return {{ name = 'x', duration = {}, effects = {{}} }}",
+                duration
+            );
+            assert!(action(&lua, &code).is_err(), "{}", duration);
+        }
+        let code = "return { name = 'x', duration = 1, effects = { Rest = 0/0 } }";
+        assert!(action(&lua, code).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+
+use common::{
+    message::{self, Message},
+    transport::Transport,
+    version::Version,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{self, UnboundedSender},
+};
+
+use crate::error::{ServerError, ServerErrorKind};
+
+pub enum ClientEvent {
+    Received(u32, Message),
+    Disconnected(u32, ServerError),
+}
+
+pub struct Client {
+    pub addr: SocketAddr,
+    /// Set once the version handshake succeeded.
+    pub version: Option<Version>,
+    /// Capabilities negotiated during the handshake.
+    pub capabilities: Vec<String>,
+    /// Last snapshot tick the client confirmed.
+    pub acked: Option<u32>,
+    tx: UnboundedSender<Message>,
+}
+
+impl Client {
+    /// Splits the socket into a reader and a writer task. Everything the
+    /// reader gets is forwarded to `events`, tagged with the client id.
+    pub fn spawn(
+        id: u32,
+        addr: SocketAddr,
+        socket: TcpStream,
+        events: UnboundedSender<ClientEvent>,
+    ) -> Self {
+        let (mut reader, mut writer) = socket.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                if let Err(e) = write_message(&mut writer, &msg).await {
+                    println!("SERVER: client {} write: {}", id, e);
+                    break;
+                }
+            }
+            // the client got dropped, everything queued is written out
+            let _ = writer.shutdown().await;
+        });
+
+        tokio::spawn(async move {
+            loop {
+                match read_message(&mut reader).await {
+                    Ok(msg) => {
+                        if events.send(ClientEvent::Received(id, msg)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = events.send(ClientEvent::Disconnected(id, e));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            version: None,
+            capabilities: Vec::new(),
+            acked: None,
+            tx,
+        }
+    }
+
+    pub fn send(&self, msg: Message) -> Result<(), ServerError> {
+        self.tx.send(msg)?;
+        Ok(())
+    }
+
+    pub fn has_capability(&self, cap: &str) -> bool {
+        self.capabilities.iter().any(|c| c == cap)
+    }
+}
+
+impl Transport for Client {
+    fn send(&mut self, msg: Message) -> std::io::Result<()> {
+        self.tx
+            .send(msg)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(socket: &mut R) -> Result<Message, ServerError> {
+    let mut prefix = [0u8; 4];
+    socket.read_exact(&mut prefix).await?;
+
+    let mut data = vec![0u8; message::frame_len(prefix)?];
+    socket.read_exact(&mut data).await.map_err(|e| {
+        // the peer went away in the middle of a frame, that is not a clean disconnect
+        let mut e = ServerError::from(e);
+        if let ServerErrorKind::Disconnected = e.kind {
+            e.kind = ServerErrorKind::Io;
+            e.msg = "connection closed inside a frame".into();
+        }
+        e
+    })?;
+
+    Ok(message::decode(&data)?)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    socket: &mut W,
+    msg: &Message,
+) -> Result<(), ServerError> {
+    socket.write_all(&message::encode(msg)?).await?;
+    socket.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::snapshot::{SnapshotHistory, SnapshotReceiver};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::scene::Scene;
+
+    #[tokio::test]
+    async fn snapshots_reach_the_peer_and_acks_come_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let mut client = Client::spawn(7, addr, socket, events_tx);
+
+        let mut scene = Scene::new();
+        let node = scene.create(None);
+        let mut history = SnapshotHistory::new(4);
+        let mut receiver = SnapshotReceiver::new(4);
+
+        for tick in 1..=2 {
+            history.push(tick, scene.state());
+            history.replicate(client.acked, &mut client).unwrap();
+
+            let Message::Snapshot(snapshot) = read_message(&mut peer).await.unwrap() else {
+                panic!("no snapshot");
+            };
+            assert_eq!(snapshot.baseline, client.acked);
+            let acked = receiver.receive(&snapshot).unwrap();
+            write_message(&mut peer, &Message::Ack { tick: acked })
+                .await
+                .unwrap();
+            let Some(ClientEvent::Received(7, Message::Ack { tick })) = events.recv().await else {
+                panic!("no ack");
+            };
+            assert_eq!(tick, acked);
+            client.acked = Some(tick);
+            assert_eq!(receiver.latest(), history.latest());
+
+            let mut moved = scene.get(node).unwrap().transform;
+            moved[12] += 1.0;
+            scene.set_transform(node, moved);
+        }
+
+        // what was queued is written out before the connection closes
+        client.send(Message::Ack { tick: 3 }).unwrap();
+        drop(client);
+        assert!(matches!(
+            read_message(&mut peer).await,
+            Ok(Message::Ack { tick: 3 })
+        ));
+        let Err(e) = read_message(&mut peer).await else {
+            panic!("still connected");
+        };
+        assert!(matches!(e.kind, ServerErrorKind::Disconnected), "{:?}", e);
+    }
+}
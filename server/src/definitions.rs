@@ -0,0 +1,157 @@
+use std::{error::Error, path::Path};
+
+use mlua::IntoLua;
+use package::Definitions;
+
+use crate::{agent::LuaAgent, runtime};
+
+/// How the file is written, it goes at its top.
+const TOOL: &str = "server --definitions";
+
+const ACTION: &str = "{ name: string, duration: number, effects: { [string]: number } }";
+
+/// Everything a server package sees, next to what every package gets.
+pub fn definitions() -> Definitions {
+    let mut defs = Definitions::new();
+
+    defs.class("Agent")
+        .field("id", "number")
+        .method("needs", "", "{ [string]: number }")
+        .method("need", "name: string", "number")
+        .method("setNeed", "name: string, value: number", "()")
+        .method("activity", "", "string?")
+        .method("position", "", "(number, number, number)")
+        .method("perform", "name: string", "boolean");
+
+    defs.class("Game")
+        .field("isServer", "boolean")
+        .field("after", "(seconds: number, f: () -> ()) -> number")
+        .field("every", "(seconds: number, f: () -> ()) -> number")
+        .field("cancel", "(id: number) -> ()")
+        .method("clients", "", "{ { id: number, address: string } }")
+        .method("send", "client: number, data: any", "boolean")
+        .method("broadcast", "data: any", "boolean");
+
+    defs.value("Game", "Game");
+    defs.library("Agent")
+        .function("spawn", "x: number, y: number, z: number", "Agent");
+    defs.library("Action")
+        .function("define", &format!("action: {}", ACTION), "()");
+    defs.library("Object")
+        .function(
+            "spawn",
+            &format!(
+                "x: number, y: number, z: number, capacity: number, actions: {{ {} }}",
+                ACTION
+            ),
+            "number",
+        )
+        .function("remove", "id: number", "boolean");
+    defs.library("Node")
+        .function("create", "parent: number?", "number")
+        .function(
+            "get",
+            "id: number",
+            "{ parent: number?, children: { number } }?",
+        )
+        .function("reparent", "id: number, parent: number?", "boolean")
+        .function("destroy", "id: number", "boolean");
+    defs.library("Obstacle").function(
+        "add",
+        "x: number, y: number, z: number, radius: number",
+        "number",
+    );
+
+    defs.callback("OnMessage", "(data: any, from: number) -> ()")
+        .callback("OnClientConnected", "(client: number) -> ()")
+        .callback("OnClientDisconnected", "(client: number) -> ()")
+        .callback("OnAgentDecide", "(agent: Agent, action: string) -> string?")
+        .callback("OnNeedCritical", "(agent: Agent, need: string) -> ()");
+
+    defs
+}
+
+pub fn write(file: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(file, definitions().to_luau(TOOL))?;
+    println!("SERVER: wrote definitions to {}", file.display());
+    Ok(())
+}
+
+/// Whether `file` is up to date and the definitions still match the
+/// globals packages really get. Tells what differs.
+pub fn check(file: &Path) -> Result<bool, Box<dyn Error>> {
+    let drift = drift(file)?;
+    for d in drift.iter() {
+        println!("SERVER: {}", d);
+    }
+    Ok(drift.is_empty())
+}
+
+/// What differs between the definitions, `file` and the globals.
+fn drift(file: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let defs = definitions();
+    let lua = package::api(&runtime::setup)?;
+    let samples = vec![("Agent", LuaAgent { id: 0 }.into_lua(&lua)?)];
+    let mut drift = defs.check(&lua, samples)?;
+    drift.extend(defs.check_class::<LuaAgent>("Agent"));
+    drift.extend(defs.check_class::<runtime::Game>("Game"));
+
+    let written = std::fs::read_to_string(file).unwrap_or_default();
+    if written != defs.to_luau(TOOL) {
+        drift.push(format!(
+            "{} is out of date, run {} {}",
+            file.display(),
+            TOOL,
+            file.display()
+        ));
+    }
+    Ok(drift)
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::UserData;
+    use package::Definitions;
+
+    use super::*;
+
+    #[test]
+    fn definitions_match_the_api() {
+        let file = Path::new(env!("CARGO_MANIFEST_DIR")).join("api.d.luau");
+        assert_eq!(drift(&file).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn undeclared_globals_are_found() {
+        let lua = package::api(&runtime::setup).unwrap();
+        lua.globals().set("Extra", 1).unwrap();
+        let drift = definitions().check(&lua, Vec::new()).unwrap();
+        assert_eq!(drift, vec!["global Extra is not declared".to_string()]);
+    }
+
+    #[test]
+    fn undeclared_members_are_found() {
+        struct Thing;
+        impl UserData for Thing {
+            fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+                fields.add_field_method_get("size", |_lua, _me| Ok(1));
+            }
+
+            fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+                methods.add_method("grow", |_lua, _me, ()| Ok(()));
+                methods.add_meta_method("__tostring", |_lua, _me, ()| Ok("thing"));
+            }
+        }
+
+        let mut defs = Definitions::new();
+        defs.class("Thing").field("size", "number");
+        assert_eq!(
+            defs.check_class::<Thing>("Thing"),
+            vec!["Thing.grow is not declared".to_string()]
+        );
+        assert_eq!(
+            defs.check_class::<Thing>("Other"),
+            vec!["class Other is not declared".to_string()]
+        );
+    }
+}
@@ -1,8 +1,43 @@
 use std::{error::Error, fmt::Display};
 
+use common::message::{FrameTooLarge, Reject};
+
+#[derive(Debug)]
+pub enum ServerErrorKind {
+    Io,
+    Disconnected,
+    FrameTooLarge,
+    Rejected,
+    Sync,
+}
+
 #[derive(Debug)]
 pub struct ServerError {
-    msg: String,
+    pub kind: ServerErrorKind,
+    pub msg: String,
+}
+
+impl ServerError {
+    pub fn disconnected() -> Self {
+        Self {
+            kind: ServerErrorKind::Disconnected,
+            msg: String::new(),
+        }
+    }
+
+    pub fn frame_too_large(size: u32) -> Self {
+        Self {
+            kind: ServerErrorKind::FrameTooLarge,
+            msg: format!("frame of {} bytes exceeds the limit", size),
+        }
+    }
+
+    pub fn rejected(reason: &Reject) -> Self {
+        Self {
+            kind: ServerErrorKind::Rejected,
+            msg: format!("{:?}", reason),
+        }
+    }
 }
 
 impl Error for ServerError {}
@@ -15,15 +50,24 @@ impl Display for ServerError {
 
 impl From<std::io::Error> for ServerError {
     fn from(value: std::io::Error) -> Self {
-        ServerError {
+        if value.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Self::disconnected();
+        }
+        if let Some(FrameTooLarge(size)) = value.get_ref().and_then(|e| e.downcast_ref()) {
+            return Self::frame_too_large(*size);
+        }
+
+        Self {
+            kind: ServerErrorKind::Io,
             msg: value.to_string(),
         }
     }
 }
 
-impl From<bincode::Error> for ServerError {
-    fn from(value: bincode::Error) -> Self {
-        ServerError {
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ServerError {
+    fn from(value: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Self {
+            kind: ServerErrorKind::Sync,
             msg: value.to_string(),
         }
     }
@@ -1,36 +1,305 @@
-use std::{error::Error, io::Read};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
 
-use common::message::Message;
-use error::ServerError;
+use agent::lua_agent_event;
+use client::{Client, ClientEvent};
+use common::{
+    message::{Message, Reject},
+    snapshot::{self, SnapshotHistory},
+    version::{negotiate, Compatibility, Version},
+};
+use error::{ServerError, ServerErrorKind};
 use hashbrown::HashMap;
-use tokio::net::TcpListener;
+use message::ServiceMessage;
+use package::{Bus, Package, TestOptions};
+use scene::Scene;
+use tokio::{net::TcpListener, sync::mpsc};
+use world::World;
 
 mod agent;
+mod client;
+mod definitions;
 mod error;
+mod message;
+mod navigation;
+mod object;
+mod runtime;
+mod scene;
+mod social;
+mod testing;
+mod world;
 
-pub struct Client {
-    socket: TcpStream,
+/// Capabilities this server can offer to clients.
+const CAPABILITIES: &[&str] = &[snapshot::CAPABILITY];
+
+/// Number of ticks a client may lag behind before it gets a full snapshot.
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// Scene events for clients that did not negotiate snapshots.
+fn broadcast(clients: &HashMap<u32, Client>, msgs: &[Message]) {
+    for (id, c) in clients
+        .iter()
+        .filter(|(_, c)| c.version.is_some() && !c.has_capability(snapshot::CAPABILITY))
+    {
+        for msg in msgs {
+            if let Err(e) = c.send(msg.clone()) {
+                println!("SERVER: client {}: {}", id, e);
+                break;
+            }
+        }
+    }
 }
 
-impl Client {
-    fn read(&mut self) -> Result<Message, ServerError> {
-        let mut size_bytes = [0u8; 4];
-        self.socket.read_exact(&mut size_bytes)?;
-        let size = u32::from_le_bytes(size_bytes);
-        let mut data = vec![0u8; size as _];
-        self.socket.read_exact(&mut data)?;
+fn greet(
+    client: &mut Client,
+    version: Version,
+    capabilities: Vec<String>,
+) -> Result<(), ServerError> {
+    let ours = Version::default();
+    let compatibility = ours.compatibility(&version);
 
-        Ok(bincode::deserialize(&data)?)
+    if !compatibility.is_compatible() {
+        let reason = Reject::VersionMismatch {
+            server: ours.to_string(),
+            client: version.to_string(),
+        };
+        client.send(Message::Reject(reason.clone()))?;
+        return Err(ServerError::rejected(&reason));
     }
+
+    if compatibility == Compatibility::PatchMismatch {
+        println!(
+            "SERVER: patch level differs, server {} client {}",
+            ours, version
+        );
+    }
+
+    let offered: Vec<String> = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    client.capabilities = negotiate(&offered, &capabilities);
+    client.version = Some(version);
+    client.send(Message::Greeting {
+        version: ours,
+        capabilities: client.capabilities.clone(),
+    })
+}
+
+fn handle_message(
+    clients: &mut HashMap<u32, Client>,
+    packages: &[Package<ServiceMessage>],
+    scene: &Scene,
+    id: u32,
+    msg: Message,
+) -> Result<(), ServerError> {
+    let Some(client) = clients.get_mut(&id) else {
+        return Ok(());
+    };
+
+    if client.version.is_none() {
+        return match msg {
+            Message::Greeting {
+                version,
+                capabilities,
+            } => {
+                println!("SERVER: client {} greets with {}", id, version);
+                greet(client, version, capabilities)?;
+                if !client.has_capability(snapshot::CAPABILITY) {
+                    for msg in scene.snapshot() {
+                        client.send(msg)?;
+                    }
+                }
+                for pk in packages {
+                    let _ = pk.event_tx.send(runtime::callback("OnClientConnected", id));
+                }
+                Ok(())
+            }
+            _ => {
+                client.send(Message::Reject(Reject::NotGreeted))?;
+                Err(ServerError::rejected(&Reject::NotGreeted))
+            }
+        };
+    }
+
+    match msg {
+        Message::Greeting { .. }
+        | Message::Reject(_)
+        | Message::CreateNode { .. }
+        | Message::ReparentNode { .. }
+        | Message::TransformNode { .. }
+        | Message::DestroyNode { .. }
+        | Message::Snapshot(_) => {
+            println!("SERVER: client {} sent an unexpected message", id);
+        }
+        Message::Ack { tick } => {
+            if let Some(client) = clients.get_mut(&id) {
+                if client.acked.is_none_or(|a| a < tick) {
+                    client.acked = Some(tick);
+                }
+            }
+        }
+        Message::Package { name, data } => {
+            if let Some(pk) = packages.iter().find(|p| p.name == name) {
+                let _ = pk.event_tx.send(runtime::on_message(data, id));
+                return Ok(());
+            }
+
+            // no server half for this package, relay to everybody else
+            let msg = Message::Package { name, data };
+            for (cid, c) in clients.iter() {
+                if *cid != id && c.version.is_some() {
+                    c.send(msg.clone())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let mut listener = TcpListener::bind(([127, 0, 0, 1], 39093));
-    let mut clients = HashMap::new();
+    if let Some(file) = std::env::args().skip_while(|a| a != "--definitions").nth(1) {
+        return definitions::write(file.as_ref());
+    }
+    if let Some(file) = std::env::args()
+        .skip_while(|a| a != "--check-definitions")
+        .nth(1)
+    {
+        if !definitions::check(file.as_ref())? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let sandbox = std::env::args().any(|a| a == "--sandbox");
+    if let Some(dir) = std::env::args().skip_while(|a| a != "--test").nth(1) {
+        let mut options = TestOptions::default();
+        if let Some(ticks) = std::env::args().skip_while(|a| a != "--ticks").nth(1) {
+            options.ticks = ticks.parse()?;
+        }
+        let report = std::env::args().skip_while(|a| a != "--report").nth(1);
+        if !testing::run(
+            dir.as_ref(),
+            &options,
+            sandbox,
+            report.as_deref().map(Path::new),
+        )? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", 39093)).await?;
+    let (etx, mut erx) = mpsc::unbounded_channel();
+    let mut clients: HashMap<u32, Client> = HashMap::new();
     let mut id_pool = 1;
+    let mut world = World::new();
+    let mut tick = tokio::time::interval(snapshot::TICK);
+    let mut tick_count: u32 = 0;
+    let mut history = SnapshotHistory::new(SNAPSHOT_HISTORY);
+    let data: PathBuf = std::env::args()
+        .skip_while(|a| a != "--data")
+        .nth(1)
+        .unwrap_or("data".into())
+        .into();
+    let packages = runtime::load(&data, sandbox);
+    let mut bus = Bus::new();
+
+    println!("SERVER: listen on {}", listener.local_addr()?);
 
     loop {
-        // let (sck, addr) = listener.ac
+        tokio::select! {
+            _ = tick.tick() => {
+                for pk in packages.iter() {
+                    while let Ok(request) = pk.service_rx.try_recv() {
+                        pk.reply(request.id, runtime::serve(&mut world, &clients, request.msg));
+                    }
+                }
+                bus.route(&packages);
+                for pk in packages.iter() {
+                    pk.frame(snapshot::TICK.as_secs_f64());
+                }
+
+                world.tick(snapshot::TICK.as_secs_f32());
+
+                for event in world.drain_events() {
+                    for pk in packages.iter().filter(|p| p.defines(event.callback())) {
+                        let _ = pk.event_tx.send(lua_agent_event(event.clone()));
+                    }
+                }
+
+                let events = world.scene.drain_events();
+                if !events.is_empty() {
+                    broadcast(&clients, &events);
+                }
+
+                tick_count = tick_count.wrapping_add(1);
+                history.push(tick_count, world.scene.state());
+                let mut stuck = Vec::new();
+                for (id, c) in clients
+                    .iter_mut()
+                    .filter(|(_, c)| c.has_capability(snapshot::CAPABILITY))
+                {
+                    if let Err(e) = history.replicate(c.acked, c) {
+                        println!("SERVER: client {}: {}", id, e);
+                        stuck.push(*id);
+                    }
+                }
+                // they would get the same snapshot again every tick
+                for id in stuck {
+                    if clients.remove(&id).is_some_and(|c| c.version.is_some()) {
+                        for pk in packages.iter() {
+                            let _ = pk
+                                .event_tx
+                                .send(runtime::callback("OnClientDisconnected", id));
+                        }
+                    }
+                }
+            }
+            accepted = listener.accept() => {
+                let (sck, addr) = match accepted {
+                    Ok(a) => a,
+                    Err(e) => {
+                        println!("SERVER: accept: {}", e);
+                        continue;
+                    }
+                };
+                let id = id_pool;
+                id_pool += 1;
+                println!("SERVER: client {} connected from {}", id, addr);
+                clients.insert(id, Client::spawn(id, addr, sck, etx.clone()));
+            }
+            Some(event) = erx.recv() => {
+                match event {
+                    ClientEvent::Received(id, msg) => {
+                        if let Err(e) = handle_message(&mut clients, &packages, &world.scene, id, msg) {
+                            println!("SERVER: client {}: {}", id, e);
+                            if let ServerErrorKind::Rejected = e.kind {
+                                clients.remove(&id);
+                            }
+                        }
+                    }
+                    ClientEvent::Disconnected(id, e) => {
+                        if let Some(c) = clients.remove(&id) {
+                            if c.version.is_some() {
+                                for pk in packages.iter() {
+                                    let _ = pk
+                                        .event_tx
+                                        .send(runtime::callback("OnClientDisconnected", id));
+                                }
+                            }
+                            match e.kind {
+                                ServerErrorKind::Disconnected => {
+                                    println!("SERVER: client {} ({}) disconnected", id, c.addr);
+                                }
+                                _ => {
+                                    println!("SERVER: client {} ({}) dropped: {}", id, c.addr, e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,53 @@
+use common::message::Message;
+use package::Service;
+
+use crate::agent::{Action, Need, Needs};
+
+#[derive(Clone)]
+pub struct AgentState {
+    pub position: [f32; 3],
+    pub needs: Needs,
+    pub activity: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct SceneNode {
+    pub parent: Option<u32>,
+    pub children: Vec<u32>,
+}
+
+/// What a package asks the server loop.
+#[derive(Clone)]
+pub enum ServiceMessage {
+    Clients,
+    /// A message for one client, or for all of them.
+    Send(Option<u32>, Message),
+    SpawnAgent([f32; 3]),
+    GetAgent(u32),
+    SetNeed(u32, Need, f32),
+    Perform(u32, String),
+    DefineAction(Action),
+    SpawnObject([f32; 3], usize, Vec<Action>),
+    RemoveObject(u32),
+    CreateNode(Option<u32>),
+    GetNode(u32),
+    ReparentNode(u32, Option<u32>),
+    DestroyNode(u32),
+    /// A node at the position agents keep `radius` metres away from.
+    AddObstacle([f32; 3], f32),
+}
+
+#[derive(Clone)]
+pub enum ServiceReply {
+    ClientList(Vec<(u32, String)>),
+    SpawnedAgent(u32),
+    GotAgent(Option<AgentState>),
+    SpawnedObject(u32),
+    CreatedNode(u32),
+    GotNode(Option<SceneNode>),
+    Done(bool),
+}
+
+impl Service for ServiceMessage {
+    type Reply = ServiceReply;
+}
@@ -0,0 +1,423 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use common::vector;
+
+use crate::scene::Scene;
+
+/// Room agents keep from obstacles, in metres.
+pub const AGENT_RADIUS: f32 = 0.4;
+
+/// Agents closer than this push each other apart while walking.
+pub const AVOID_RADIUS: f32 = 1.5;
+
+const STRAIGHT: u32 = 10;
+const DIAGONAL: u32 = 14;
+
+/// A scene node agents have to walk around.
+pub struct Obstacle {
+    pub node: u32,
+    pub radius: f32,
+}
+
+/// Walkable cells on the ground plane, the grid spans x and z with y up.
+pub struct NavGrid {
+    /// Corner of the first cell.
+    pub origin: [f32; 3],
+    pub cell_size: f32,
+    pub width: usize,
+    pub height: usize,
+    walkable: Vec<bool>,
+}
+
+impl NavGrid {
+    pub fn new(origin: [f32; 3], cell_size: f32, width: usize, height: usize) -> Self {
+        Self {
+            origin,
+            cell_size,
+            width,
+            height,
+            walkable: vec![true; width * height],
+        }
+    }
+
+    /// Same layout as `self` with every obstacle of the scene blocked out.
+    pub fn rebuild(&self, scene: &Scene, obstacles: &[Obstacle]) -> Self {
+        let mut grid = Self::new(self.origin, self.cell_size, self.width, self.height);
+        for o in obstacles {
+            if let Some(node) = scene.get(o.node) {
+                let t = &node.transform;
+                grid.block(&[t[12], t[13], t[14]], o.radius);
+            }
+        }
+        grid
+    }
+
+    /// Marks every cell whose center an agent could not stand on.
+    pub fn block(&mut self, center: &[f32; 3], radius: f32) {
+        let reach = radius + AGENT_RADIUS;
+        let (x0, z0) = self.cell(&[center[0] - reach, 0.0, center[2] - reach]);
+        let (x1, z1) = self.cell(&[center[0] + reach, 0.0, center[2] + reach]);
+
+        for z in z0..=z1 {
+            for x in x0..=x1 {
+                let c = self.center((x, z));
+                if (c[0] - center[0]).hypot(c[2] - center[2]) < reach {
+                    self.walkable[z * self.width + x] = false;
+                }
+            }
+        }
+    }
+
+    pub fn is_walkable(&self, position: &[f32; 3]) -> bool {
+        let (x, z) = self.cell(position);
+        self.walkable[z * self.width + x]
+    }
+
+    /// Waypoints from `from` to `to`, not including `from`. When `to` is
+    /// blocked the path ends at the closest walkable cell instead.
+    pub fn find_path(&self, from: &[f32; 3], to: &[f32; 3]) -> Option<Vec<[f32; 3]>> {
+        let start = self.nearest_walkable(self.cell(from))?;
+        let goal_cell = self.cell(to);
+        let goal = self.nearest_walkable(goal_cell)?;
+
+        let index = |(x, z): (usize, usize)| z * self.width + x;
+        let heuristic = |(x, z): (usize, usize)| {
+            let dx = x.abs_diff(goal.0) as u32;
+            let dz = z.abs_diff(goal.1) as u32;
+            STRAIGHT * dx.max(dz) + (DIAGONAL - STRAIGHT) * dx.min(dz)
+        };
+
+        let mut cost = vec![u32::MAX; self.walkable.len()];
+        let mut came_from = vec![usize::MAX; self.walkable.len()];
+        let mut open = BinaryHeap::new();
+
+        cost[index(start)] = 0;
+        open.push(Reverse((heuristic(start), index(start))));
+
+        while let Some(Reverse((_, i))) = open.pop() {
+            let cell = (i % self.width, i / self.width);
+            if cell == goal {
+                break;
+            }
+
+            for (next, step) in self.neighbours(cell) {
+                let n = index(next);
+                let c = cost[i] + step;
+                if c < cost[n] {
+                    cost[n] = c;
+                    came_from[n] = i;
+                    open.push(Reverse((c + heuristic(next), n)));
+                }
+            }
+        }
+
+        if cost[index(goal)] == u32::MAX {
+            return None;
+        }
+
+        let mut cells = Vec::new();
+        let mut i = index(goal);
+        while i != index(start) {
+            cells.push(self.center((i % self.width, i / self.width)));
+            i = came_from[i];
+        }
+        cells.reverse();
+
+        // the exact spot beats the center of its cell
+        if goal == goal_cell && self.contains(to) {
+            cells.pop();
+            cells.push(*to);
+        }
+
+        Some(self.smooth(from, cells))
+    }
+
+    /// Skips every waypoint that can be seen from an earlier one.
+    fn smooth(&self, from: &[f32; 3], points: Vec<[f32; 3]>) -> Vec<[f32; 3]> {
+        let mut smoothed = Vec::new();
+        let mut current = *from;
+        let mut i = 0;
+
+        while i < points.len() {
+            let mut furthest = i;
+            for j in (i + 1..points.len()).rev() {
+                if self.line_of_sight(&current, &points[j]) {
+                    furthest = j;
+                    break;
+                }
+            }
+            current = points[furthest];
+            smoothed.push(current);
+            i = furthest + 1;
+        }
+
+        smoothed
+    }
+
+    /// Whether the straight line from `a` to `b` only crosses walkable cells.
+    pub fn line_of_sight(&self, a: &[f32; 3], b: &[f32; 3]) -> bool {
+        let d = vector::distance(a, b);
+        let steps = (d / (self.cell_size * 0.25)).ceil() as usize;
+        let delta = vector::sub(b, a);
+
+        (0..=steps).all(|s| {
+            let t = if steps == 0 {
+                0.0
+            } else {
+                s as f32 / steps as f32
+            };
+            self.is_walkable(&vector::add(a, &vector::scale(&delta, t)))
+        })
+    }
+
+    /// Walkable neighbours and the cost to get there. Diagonal moves
+    /// must not cut the corner of a blocked cell.
+    fn neighbours(
+        &self,
+        (x, z): (usize, usize),
+    ) -> impl Iterator<Item = ((usize, usize), u32)> + '_ {
+        let walkable = move |x: isize, z: isize| {
+            x >= 0
+                && z >= 0
+                && (x as usize) < self.width
+                && (z as usize) < self.height
+                && self.walkable[z as usize * self.width + x as usize]
+        };
+        let (x, z) = (x as isize, z as isize);
+
+        [
+            (-1, 0),
+            (1, 0),
+            (0, -1),
+            (0, 1),
+            (-1, -1),
+            (1, -1),
+            (-1, 1),
+            (1, 1),
+        ]
+        .into_iter()
+        .filter(move |(dx, dz)| {
+            walkable(x + dx, z + dz) && walkable(x + dx, z) && walkable(x, z + dz)
+        })
+        .map(move |(dx, dz)| {
+            let step = if dx != 0 && dz != 0 {
+                DIAGONAL
+            } else {
+                STRAIGHT
+            };
+            (((x + dx) as usize, (z + dz) as usize), step)
+        })
+    }
+
+    /// Closest walkable cell, searching outwards ring by ring.
+    fn nearest_walkable(&self, (x, z): (usize, usize)) -> Option<(usize, usize)> {
+        for r in 0..self.width.max(self.height) as isize {
+            for dz in -r..=r {
+                for dx in -r..=r {
+                    if dx.abs() != r && dz.abs() != r {
+                        continue;
+                    }
+                    let (cx, cz) = (x as isize + dx, z as isize + dz);
+                    if cx < 0 || cz < 0 || cx as usize >= self.width || cz as usize >= self.height {
+                        continue;
+                    }
+                    if self.walkable[cz as usize * self.width + cx as usize] {
+                        return Some((cx as usize, cz as usize));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn contains(&self, p: &[f32; 3]) -> bool {
+        let x = (p[0] - self.origin[0]) / self.cell_size;
+        let z = (p[2] - self.origin[2]) / self.cell_size;
+        (0.0..self.width as f32).contains(&x) && (0.0..self.height as f32).contains(&z)
+    }
+
+    /// Cell under the position, positions off the grid snap to its border.
+    fn cell(&self, p: &[f32; 3]) -> (usize, usize) {
+        let x = ((p[0] - self.origin[0]) / self.cell_size).floor();
+        let z = ((p[2] - self.origin[2]) / self.cell_size).floor();
+        (
+            x.clamp(0.0, (self.width - 1) as f32) as usize,
+            z.clamp(0.0, (self.height - 1) as f32) as usize,
+        )
+    }
+
+    fn center(&self, (x, z): (usize, usize)) -> [f32; 3] {
+        [
+            self.origin[0] + (x as f32 + 0.5) * self.cell_size,
+            self.origin[1],
+            self.origin[2] + (z as f32 + 0.5) * self.cell_size,
+        ]
+    }
+}
+
+/// Bends `direction` away from nearby agents, closer ones push harder.
+pub fn avoid(position: &[f32; 3], direction: &[f32; 3], others: &[[f32; 3]]) -> [f32; 3] {
+    let mut push = [0.0; 3];
+    for o in others {
+        let away = vector::sub(position, o);
+        let d = vector::length(&away);
+        if d > f32::EPSILON && d < AVOID_RADIUS {
+            push = vector::add(
+                &push,
+                &vector::scale(&away, (AVOID_RADIUS - d) / (AVOID_RADIUS * d)),
+            );
+        }
+    }
+    push[1] = 0.0;
+
+    let mut steered = vector::add(direction, &push);
+    if vector::length(&steered) <= f32::EPSILON {
+        return *direction;
+    }
+    vector::normalize(&mut steered);
+    steered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> NavGrid {
+        NavGrid::new([0.0; 3], 1.0, 20, 20)
+    }
+
+    /// Blocks the cells of column `x` from row `z0` to `z1`.
+    fn wall(grid: &mut NavGrid, x: usize, z0: usize, z1: usize) {
+        for z in z0..=z1 {
+            grid.walkable[z * grid.width + x] = false;
+        }
+    }
+
+    fn length(from: &[f32; 3], path: &[[f32; 3]]) -> f32 {
+        let mut last = *from;
+        path.iter()
+            .map(|p| {
+                let d = vector::distance(&last, p);
+                last = *p;
+                d
+            })
+            .sum()
+    }
+
+    /// Every leg of the path stays on walkable cells.
+    fn assert_walkable(grid: &NavGrid, from: &[f32; 3], path: &[[f32; 3]]) {
+        let mut last = *from;
+        for p in path {
+            assert!(
+                grid.line_of_sight(&last, p),
+                "{:?} to {:?} is blocked",
+                last,
+                p
+            );
+            last = *p;
+        }
+    }
+
+    #[test]
+    fn open_ground_is_crossed_in_a_straight_line() {
+        let grid = grid();
+        let (from, to) = ([1.5, 0.0, 1.5], [17.2, 0.0, 12.7]);
+        assert_eq!(grid.find_path(&from, &to), Some(vec![to]));
+    }
+
+    #[test]
+    fn walls_are_walked_around() {
+        let mut grid = grid();
+        wall(&mut grid, 10, 0, 15);
+        let (from, to) = ([5.5, 0.0, 5.5], [15.5, 0.0, 5.5]);
+
+        let path = grid.find_path(&from, &to).unwrap();
+        assert_eq!(path.last(), Some(&to));
+        assert_walkable(&grid, &from, &path);
+        // over the end of the wall at row 15 and back down
+        assert!(path.iter().any(|p| p[2] > 15.0));
+        assert!(length(&from, &path) > 20.0);
+    }
+
+    #[test]
+    fn smoothing_keeps_only_the_corners() {
+        let mut grid = grid();
+        wall(&mut grid, 10, 0, 15);
+        let from = [5.5, 0.0, 5.5];
+
+        let path = grid.find_path(&from, &[15.5, 0.0, 5.5]).unwrap();
+        // a cell by cell path would take more than 20 waypoints
+        assert!(path.len() <= 4, "{:?}", path);
+        assert_walkable(&grid, &from, &path);
+    }
+
+    #[test]
+    fn diagonals_do_not_cut_corners() {
+        let mut grid = grid();
+        grid.walkable[5 * grid.width + 6] = false;
+        grid.walkable[6 * grid.width + 5] = false;
+
+        // the only gap between the two cells is a corner
+        let cells: Vec<_> = grid.neighbours((5, 5)).map(|(c, _)| c).collect();
+        assert!(!cells.contains(&(6, 6)));
+        assert!(cells.contains(&(4, 4)));
+    }
+
+    #[test]
+    fn blocked_targets_end_at_the_closest_cell() {
+        let mut grid = grid();
+        grid.block(&[10.0, 0.0, 10.0], 1.0);
+        let target = [10.0, 0.0, 10.0];
+        assert!(!grid.is_walkable(&target));
+
+        let path = grid.find_path(&[2.5, 0.0, 2.5], &target).unwrap();
+        let end = path.last().unwrap();
+        assert!(grid.is_walkable(end));
+        assert!(vector::distance(end, &target) < 1.0 + AGENT_RADIUS + 1.0);
+    }
+
+    #[test]
+    fn enclosed_targets_have_no_path() {
+        let mut grid = grid();
+        // a box around the cells 14..=16
+        wall(&mut grid, 13, 13, 17);
+        wall(&mut grid, 17, 13, 17);
+        for x in 13..=17 {
+            grid.walkable[13 * grid.width + x] = false;
+            grid.walkable[17 * grid.width + x] = false;
+        }
+
+        assert_eq!(grid.find_path(&[2.5, 0.0, 2.5], &[15.5, 0.0, 15.5]), None);
+    }
+
+    #[test]
+    fn rebuild_blocks_the_obstacles_of_the_scene() {
+        let mut scene = Scene::new();
+        let node = scene.create(None);
+        let mut transform = [0.0; 16];
+        common::matrix::identity(&mut transform);
+        transform[12] = 10.0;
+        transform[14] = 10.0;
+        scene.set_transform(node, transform);
+
+        let grid = grid().rebuild(&scene, &[Obstacle { node, radius: 1.0 }]);
+        assert!(!grid.is_walkable(&[10.0, 0.0, 10.0]));
+        assert!(!grid.is_walkable(&[9.2, 0.0, 10.2]));
+        assert!(grid.is_walkable(&[13.0, 0.0, 10.0]));
+
+        scene.destroy(node);
+        let grid = grid.rebuild(&scene, &[Obstacle { node, radius: 1.0 }]);
+        assert!(grid.is_walkable(&[10.0, 0.0, 10.0]));
+    }
+
+    #[test]
+    fn avoidance_turns_away_from_close_agents() {
+        let direction = [1.0, 0.0, 0.0];
+        let steered = avoid(&[0.0; 3], &direction, &[[0.5, 0.0, 0.5]]);
+        assert!(steered[2] < 0.0);
+        assert!((vector::length(&steered) - 1.0).abs() < 1e-4);
+
+        let far = avoid(&[0.0; 3], &direction, &[[0.0, 0.0, AVOID_RADIUS + 1.0]]);
+        assert_eq!(far, direction);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use mlua::{Lua, MultiValue, Table};
+
+use crate::{
+    agent::{lua_action, Action},
+    message::{ServiceMessage, ServiceReply},
+};
+
+/// A world entity that advertises what it is good for, like a bed or a
+/// fountain. Agents reserve it while they use it.
+pub struct SmartObject {
+    pub id: u32,
+    /// Scene node that represents the object, if any.
+    pub node: Option<u32>,
+    pub position: [f32; 3],
+    /// How many agents can use it at the same time.
+    pub capacity: usize,
+    pub actions: Vec<Action>,
+    users: Vec<u32>,
+}
+
+impl SmartObject {
+    pub fn users(&self) -> &[u32] {
+        &self.users
+    }
+
+    /// Whether `agent` could start using the object right now.
+    pub fn is_available(&self, agent: u32) -> bool {
+        self.users.contains(&agent) || self.users.len() < self.capacity
+    }
+
+    pub fn reserve(&mut self, agent: u32) -> bool {
+        if self.users.contains(&agent) {
+            return true;
+        }
+        if self.users.len() >= self.capacity {
+            return false;
+        }
+        self.users.push(agent);
+        true
+    }
+
+    pub fn release(&mut self, agent: u32) {
+        self.users.retain(|a| *a != agent);
+    }
+}
+
+/// All smart objects of a world, ordered by id so queries are deterministic.
+pub struct SmartObjects {
+    id_pool: u32,
+    objects: BTreeMap<u32, SmartObject>,
+}
+
+impl SmartObjects {
+    pub fn new() -> Self {
+        Self {
+            id_pool: 1,
+            objects: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        node: Option<u32>,
+        position: [f32; 3],
+        capacity: usize,
+        actions: Vec<Action>,
+    ) -> u32 {
+        let id = self.id_pool;
+        self.id_pool += 1;
+
+        self.objects.insert(
+            id,
+            SmartObject {
+                id,
+                node,
+                position,
+                capacity,
+                actions,
+                users: Vec::new(),
+            },
+        );
+
+        id
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<SmartObject> {
+        self.objects.remove(&id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&SmartObject> {
+        self.objects.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut SmartObject> {
+        self.objects.get_mut(&id)
+    }
+
+    /// Objects within `radius` of `position`.
+    pub fn nearby(
+        &self,
+        position: &[f32; 3],
+        radius: f32,
+    ) -> impl Iterator<Item = &SmartObject> + '_ {
+        let position = *position;
+        self.objects
+            .values()
+            .filter(move |o| common::vector::distance(&o.position, &position) <= radius)
+    }
+}
+
+pub fn lua_object_spawn(
+    lua: &Lua,
+    (x, y, z, capacity, actions): (f32, f32, f32, usize, Table),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let actions = actions
+        .sequence_values::<Table>()
+        .map(|t| lua_action(t?))
+        .collect::<mlua::Result<Vec<_>>>()?;
+
+    let msg = ServiceMessage::SpawnObject([x, y, z], capacity, actions);
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::SpawnedObject(id) => Ok(id),
+        _ => Err(mlua::Error::runtime("could not spawn object")),
+    })
+}
+
+pub fn lua_object_remove(lua: &Lua, id: u32) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    package::request(
+        lua,
+        ServiceMessage::RemoveObject(id),
+        |_lua, answer| match answer {
+            ServiceReply::Done(done) => Ok(done),
+            _ => Err(mlua::Error::runtime("could not remove object")),
+        },
+    )
+}
@@ -0,0 +1,268 @@
+use std::{error::Error, path::Path};
+
+use common::{message::Message, value::Value};
+use hashbrown::HashMap;
+use mlua::{Function, Lua, MultiValue, UserData, UserDataRef};
+use package::{Capability, Event, Package, Reply, Sandbox};
+
+use crate::{
+    agent::{lua_action_define, lua_agent_spawn},
+    client::Client,
+    message::{AgentState, SceneNode, ServiceMessage, ServiceReply},
+    object::{lua_object_remove, lua_object_spawn},
+    scene::{lua_node_create, lua_node_destroy, lua_node_get, lua_node_reparent, lua_obstacle_add},
+    world::World,
+};
+
+/// The `Game` global of server packages.
+pub struct Game;
+
+fn lua_game_clients(lua: &Lua, _game: UserDataRef<Game>) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Network)?;
+    package::request(lua, ServiceMessage::Clients, |lua, answer| {
+        let ServiceReply::ClientList(list) = answer else {
+            return Err(mlua::Error::runtime("could not list clients"));
+        };
+
+        let clients = lua.create_table()?;
+        for (id, address) in list {
+            let c = lua.create_table()?;
+            c.set("id", id)?;
+            c.set("address", address)?;
+            clients.push(c)?;
+        }
+        Ok(clients)
+    })
+}
+
+fn lua_game_send(
+    lua: &Lua,
+    (_game, client, data): (UserDataRef<Game>, u32, mlua::Value),
+) -> mlua::Result<MultiValue> {
+    send(lua, Some(client), data)
+}
+
+fn lua_game_broadcast(
+    lua: &Lua,
+    (_game, data): (UserDataRef<Game>, mlua::Value),
+) -> mlua::Result<MultiValue> {
+    send(lua, None, data)
+}
+
+fn send(lua: &Lua, client: Option<u32>, data: mlua::Value) -> mlua::Result<MultiValue> {
+    package::allow(lua, Capability::Network)?;
+    let name = package::name(lua)?;
+    let data = package::to_value(data)?;
+    let msg = ServiceMessage::Send(client, Message::Package { name, data });
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::Done(done) => Ok(done),
+        _ => Err(mlua::Error::runtime("could not send")),
+    })
+}
+
+impl UserData for Game {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("isServer", |_lua, _me| Ok(true));
+
+        // these ask the server loop, so they are awaitable functions
+        fields.add_field_function_get("clients", |lua, _| {
+            package::awaitable_method(lua, "GameClients", lua_game_clients)
+        });
+        fields.add_field_function_get("send", |lua, _| {
+            package::awaitable_method(lua, "GameSend", lua_game_send)
+        });
+        fields.add_field_function_get("broadcast", |lua, _| {
+            package::awaitable_method(lua, "GameBroadcast", lua_game_broadcast)
+        });
+    }
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("after", |lua, (seconds, f): (f64, Function)| {
+            package::after(lua, seconds, f)
+        });
+
+        methods.add_function("every", |lua, (seconds, f): (f64, Function)| {
+            package::every(lua, seconds, f)
+        });
+
+        methods.add_function("cancel", |lua, id: u32| package::cancel(lua, id));
+    }
+}
+
+/// Sets up the globals of a server package, `definitions` describes them.
+pub fn setup(lua: &Lua) -> Result<(), Box<dyn Error>> {
+    let globals = lua.globals();
+
+    globals.set("Game", Game)?;
+
+    let agent = lua.create_table()?;
+    let spawn = package::awaitable(lua, lua.create_function(lua_agent_spawn)?)?;
+    agent.set("spawn", spawn)?;
+    globals.set("Agent", agent)?;
+
+    let action = lua.create_table()?;
+    let define = package::awaitable(lua, lua.create_function(lua_action_define)?)?;
+    action.set("define", define)?;
+    globals.set("Action", action)?;
+
+    let object = lua.create_table()?;
+    let spawn = package::awaitable(lua, lua.create_function(lua_object_spawn)?)?;
+    object.set("spawn", spawn)?;
+    let remove = package::awaitable(lua, lua.create_function(lua_object_remove)?)?;
+    object.set("remove", remove)?;
+    globals.set("Object", object)?;
+
+    let node = lua.create_table()?;
+    let create = package::awaitable(lua, lua.create_function(lua_node_create)?)?;
+    node.set("create", create)?;
+    let get = package::awaitable(lua, lua.create_function(lua_node_get)?)?;
+    node.set("get", get)?;
+    let reparent = package::awaitable(lua, lua.create_function(lua_node_reparent)?)?;
+    node.set("reparent", reparent)?;
+    let destroy = package::awaitable(lua, lua.create_function(lua_node_destroy)?)?;
+    node.set("destroy", destroy)?;
+    globals.set("Node", node)?;
+
+    let obstacle = lua.create_table()?;
+    let add = package::awaitable(lua, lua.create_function(lua_obstacle_add)?)?;
+    obstacle.set("add", add)?;
+    globals.set("Obstacle", obstacle)?;
+
+    Ok(())
+}
+
+/// Loads every package found in `data` in dependency order, they run
+/// without a window. Broken packages are reported and left out. With
+/// `sandbox` every package runs sandboxed, even if its manifest trusts it.
+pub fn load(data: &Path, sandbox: bool) -> Vec<Package<ServiceMessage>> {
+    let mut packages = Vec::new();
+
+    let manifests = match package::load_order(data) {
+        Ok((manifests, errors)) => {
+            for e in errors {
+                println!("SERVER: {}", e);
+            }
+            manifests
+        }
+        Err(e) => {
+            println!("SERVER: {}: {}", data.display(), e);
+            return packages;
+        }
+    };
+
+    let storage = package::data_dir("server").map(|d| d.join("storage"));
+    for mut manifest in manifests {
+        if sandbox {
+            manifest.sandbox.get_or_insert_with(Sandbox::default);
+        }
+        manifest.storage = storage.clone();
+        match Package::<ServiceMessage>::load(manifest, setup) {
+            Ok(pk) => packages.push(pk),
+            Err(e) => println!("SERVER: {}", e),
+        }
+    }
+
+    packages
+}
+
+/// Calls the global callback `name` of a package with `args`, if it has one.
+pub fn callback<A>(name: &'static str, args: A) -> Event
+where
+    A: mlua::IntoLuaMulti + Send + 'static,
+{
+    Box::new(
+        move |lua| match lua.globals().get::<Option<Function>>(name)? {
+            Some(cb) => cb.call(args),
+            None => Ok(()),
+        },
+    )
+}
+
+/// Calls `OnMessage` of a package with what client `from` sent it.
+pub fn on_message(data: Value, from: u32) -> Event {
+    Box::new(
+        move |lua| match lua.globals().get::<Option<Function>>("OnMessage")? {
+            Some(cb) => cb.call((package::from_value(lua, &data)?, from)),
+            None => Ok(()),
+        },
+    )
+}
+
+/// Answers a request of a package.
+pub fn serve(
+    world: &mut World,
+    clients: &HashMap<u32, Client>,
+    msg: ServiceMessage,
+) -> Reply<ServiceMessage> {
+    let reply = match msg {
+        ServiceMessage::Clients => {
+            let mut list: Vec<(u32, String)> = clients
+                .iter()
+                .filter(|(_, c)| c.version.is_some())
+                .map(|(id, c)| (*id, c.addr.to_string()))
+                .collect();
+            list.sort();
+            ServiceReply::ClientList(list)
+        }
+        ServiceMessage::Send(Some(id), msg) => ServiceReply::Done(
+            clients
+                .get(&id)
+                .filter(|c| c.version.is_some())
+                .is_some_and(|c| c.send(msg).is_ok()),
+        ),
+        ServiceMessage::Send(None, msg) => {
+            for c in clients.values().filter(|c| c.version.is_some()) {
+                let _ = c.send(msg.clone());
+            }
+            ServiceReply::Done(true)
+        }
+        ServiceMessage::SpawnAgent(position) => {
+            ServiceReply::SpawnedAgent(world.spawn_agent(position))
+        }
+        ServiceMessage::GetAgent(id) => {
+            let state = world
+                .agents
+                .iter()
+                .find(|a| a.id == id)
+                .map(|a| AgentState {
+                    position: a.position,
+                    needs: a.needs,
+                    activity: a.doing(&world.actions, &world.objects).map(String::from),
+                });
+            ServiceReply::GotAgent(state)
+        }
+        ServiceMessage::SetNeed(id, need, value) => match world.agent_mut(id) {
+            Some(agent) => {
+                agent.needs.set(need, value);
+                ServiceReply::Done(true)
+            }
+            None => ServiceReply::Done(false),
+        },
+        ServiceMessage::Perform(id, action) => ServiceReply::Done(world.perform(id, &action)),
+        ServiceMessage::DefineAction(action) => {
+            // a package may tune an action it defined before
+            world.define_action(action);
+            ServiceReply::Done(true)
+        }
+        ServiceMessage::SpawnObject(position, capacity, actions) => {
+            ServiceReply::SpawnedObject(world.spawn_object(position, capacity, actions))
+        }
+        ServiceMessage::RemoveObject(id) => ServiceReply::Done(world.remove_object(id)),
+        ServiceMessage::CreateNode(parent) => ServiceReply::CreatedNode(world.scene.create(parent)),
+        ServiceMessage::GetNode(id) => {
+            ServiceReply::GotNode(world.scene.get(id).map(|n| SceneNode {
+                parent: n.parent,
+                children: n.children.clone(),
+            }))
+        }
+        ServiceMessage::ReparentNode(id, parent) => {
+            ServiceReply::Done(world.scene.reparent(id, parent))
+        }
+        ServiceMessage::DestroyNode(id) => ServiceReply::Done(world.destroy_node(id)),
+        ServiceMessage::AddObstacle(position, radius) => {
+            ServiceReply::CreatedNode(world.add_obstacle(position, radius))
+        }
+    };
+
+    Ok(reply)
+}
@@ -1,4 +1,334 @@
-pub struct Scene {}
+use common::{
+    message::Message,
+    snapshot::{NodeState, WorldState},
+};
+use hashbrown::HashMap;
+use mlua::{Lua, MultiValue};
 
-#[derive(Trace)]
-pub struct JsScene {}
+use crate::message::{ServiceMessage, ServiceReply};
+
+pub struct Node {
+    pub id: u32,
+    pub parent: Option<u32>,
+    pub children: Vec<u32>,
+    pub transform: [f32; 16],
+}
+
+/// Headless scene graph owned by the server. Every change is recorded as a
+/// `Message` which the main loop hands out to the connected clients.
+pub struct Scene {
+    id_pool: u32,
+    nodes: HashMap<u32, Node>,
+    events: Vec<Message>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self {
+            id_pool: 1,
+            nodes: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Node> {
+        self.nodes.get(&id)
+    }
+
+    pub fn create(&mut self, parent: Option<u32>) -> u32 {
+        let id = self.id_pool;
+        self.id_pool += 1;
+
+        let parent = parent.filter(|p| self.nodes.contains_key(p));
+        if let Some(p) = parent.and_then(|p| self.nodes.get_mut(&p)) {
+            p.children.push(id);
+        }
+
+        let mut transform = [0.0; 16];
+        common::matrix::identity(&mut transform);
+
+        self.nodes.insert(
+            id,
+            Node {
+                id,
+                parent,
+                children: Vec::new(),
+                transform,
+            },
+        );
+        self.events.push(Message::CreateNode {
+            id,
+            parent,
+            transform,
+        });
+
+        id
+    }
+
+    /// Moves `id` below `parent`, false if either is missing or the node
+    /// would end up below itself.
+    pub fn reparent(&mut self, id: u32, parent: Option<u32>) -> bool {
+        if !self.nodes.contains_key(&id) {
+            return false;
+        }
+
+        if let Some(p) = parent {
+            // a node can not become its own ancestor
+            let mut cursor = Some(p);
+            while let Some(c) = cursor {
+                if c == id {
+                    return false;
+                }
+                cursor = self.nodes.get(&c).and_then(|n| n.parent);
+            }
+            if !self.nodes.contains_key(&p) {
+                return false;
+            }
+        }
+
+        let old = self.nodes[&id].parent;
+        if let Some(o) = old.and_then(|o| self.nodes.get_mut(&o)) {
+            o.children.retain(|c| *c != id);
+        }
+        if let Some(p) = parent.and_then(|p| self.nodes.get_mut(&p)) {
+            p.children.push(id);
+        }
+        self.nodes.get_mut(&id).unwrap().parent = parent;
+
+        self.events.push(Message::ReparentNode { id, parent });
+        true
+    }
+
+    pub fn set_transform(&mut self, id: u32, transform: [f32; 16]) {
+        if let Some(n) = self.nodes.get_mut(&id) {
+            n.transform = transform;
+            self.events.push(Message::TransformNode { id, transform });
+        }
+    }
+
+    /// Removes the node together with all of its descendants.
+    pub fn destroy(&mut self, id: u32) -> bool {
+        let Some(node) = self.nodes.remove(&id) else {
+            return false;
+        };
+
+        if let Some(p) = node.parent.and_then(|p| self.nodes.get_mut(&p)) {
+            p.children.retain(|c| *c != id);
+        }
+
+        let mut stack = node.children;
+        while let Some(c) = stack.pop() {
+            if let Some(n) = self.nodes.remove(&c) {
+                stack.extend(n.children);
+            }
+        }
+
+        self.events.push(Message::DestroyNode { id });
+        true
+    }
+
+    /// Changes since the last call.
+    pub fn drain_events(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn state(&self) -> WorldState {
+        self.nodes
+            .values()
+            .map(|n| {
+                (
+                    n.id,
+                    NodeState {
+                        parent: n.parent,
+                        transform: n.transform,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The whole graph as create messages, parents always before their children.
+    pub fn snapshot(&self) -> Vec<Message> {
+        let mut msgs = Vec::with_capacity(self.nodes.len());
+        let mut stack: Vec<u32> = self
+            .nodes
+            .values()
+            .filter(|n| n.parent.is_none())
+            .map(|n| n.id)
+            .collect();
+
+        while let Some(id) = stack.pop() {
+            let n = &self.nodes[&id];
+            msgs.push(Message::CreateNode {
+                id,
+                parent: n.parent,
+                transform: n.transform,
+            });
+            stack.extend(n.children.iter());
+        }
+
+        msgs
+    }
+}
+
+pub fn lua_node_create(lua: &Lua, parent: Option<u32>) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    package::request(
+        lua,
+        ServiceMessage::CreateNode(parent),
+        |_lua, answer| match answer {
+            ServiceReply::CreatedNode(id) => Ok(id),
+            _ => Err(mlua::Error::runtime("could not create node")),
+        },
+    )
+}
+
+/// The parent and children of a node, nil if there is no such node.
+pub fn lua_node_get(lua: &Lua, id: u32) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    package::request(lua, ServiceMessage::GetNode(id), |lua, answer| {
+        let ServiceReply::GotNode(node) = answer else {
+            return Err(mlua::Error::runtime("could not get node"));
+        };
+        let Some(node) = node else {
+            return Ok(None);
+        };
+        let t = lua.create_table()?;
+        t.set("parent", node.parent)?;
+        t.set("children", lua.create_sequence_from(node.children)?)?;
+        Ok(Some(t))
+    })
+}
+
+pub fn lua_node_reparent(lua: &Lua, (id, parent): (u32, Option<u32>)) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    package::request(
+        lua,
+        ServiceMessage::ReparentNode(id, parent),
+        |_lua, answer| match answer {
+            ServiceReply::Done(done) => Ok(done),
+            _ => Err(mlua::Error::runtime("could not reparent node")),
+        },
+    )
+}
+
+pub fn lua_node_destroy(lua: &Lua, id: u32) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    package::request(
+        lua,
+        ServiceMessage::DestroyNode(id),
+        |_lua, answer| match answer {
+            ServiceReply::Done(done) => Ok(done),
+            _ => Err(mlua::Error::runtime("could not destroy node")),
+        },
+    )
+}
+
+/// Blocks a circle of the ground for walking agents, returns its node.
+pub fn lua_obstacle_add(
+    lua: &Lua,
+    (x, y, z, radius): (f32, f32, f32, f32),
+) -> mlua::Result<MultiValue> {
+    package::allow(lua, package::Capability::Scene)?;
+    let msg = ServiceMessage::AddObstacle([x, y, z], radius.max(0.0));
+    package::request(lua, msg, |_lua, answer| match answer {
+        ServiceReply::CreatedNode(id) => Ok(id),
+        _ => Err(mlua::Error::runtime("could not add obstacle")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{
+        snapshot::{Snapshot, SnapshotHistory, SnapshotReceiver},
+        transport::{loopback, Loopback},
+    };
+
+    use super::*;
+
+    /// The scene of the server replicated to a client, like the main loop
+    /// does it every tick for clients with the snapshot capability.
+    struct Replication {
+        server: Loopback,
+        client: Loopback,
+        history: SnapshotHistory,
+        receiver: SnapshotReceiver,
+        acked: Option<u32>,
+        tick: u32,
+    }
+
+    impl Replication {
+        fn new() -> Self {
+            let (server, client) = loopback();
+            Self {
+                server,
+                client,
+                history: SnapshotHistory::new(8),
+                receiver: SnapshotReceiver::new(8),
+                acked: None,
+                tick: 0,
+            }
+        }
+
+        /// One tick of the server, returns the snapshot the client got and
+        /// the state it has now.
+        fn tick(&mut self, scene: &Scene) -> (Snapshot, WorldState) {
+            self.tick += 1;
+            self.history.push(self.tick, scene.state());
+            self.history
+                .replicate(self.acked, &mut self.server)
+                .unwrap();
+
+            let Some(Message::Snapshot(snapshot)) = self.client.recv() else {
+                panic!("no snapshot in tick {}", self.tick);
+            };
+            assert!(self.receiver.handle(&snapshot, &mut self.client).unwrap());
+            while let Some(msg) = self.server.recv() {
+                if let Message::Ack { tick } = msg {
+                    self.acked = Some(tick);
+                }
+            }
+            (snapshot, self.receiver.latest().unwrap().1.clone())
+        }
+    }
+
+    #[test]
+    fn changes_reach_the_client_as_snapshots() {
+        let mut scene = Scene::new();
+        let mut replication = Replication::new();
+
+        let room = scene.create(None);
+        let bed = scene.create(Some(room));
+        let (snapshot, state) = replication.tick(&scene);
+        assert_eq!(snapshot.baseline, None);
+        assert_eq!(state, scene.state());
+        assert_eq!(state[&bed].parent, Some(room));
+
+        // only the moved node is sent, based on what the client has
+        let mut moved = scene.get(bed).unwrap().transform;
+        moved[12] = 2.0;
+        scene.set_transform(bed, moved);
+        let (snapshot, state) = replication.tick(&scene);
+        assert_eq!(snapshot.baseline, Some(1));
+        assert_eq!(snapshot.changed.len(), 1);
+        assert_eq!(snapshot.changed[0].id, bed);
+        assert_eq!(state[&bed].transform, moved);
+
+        let hall = scene.create(None);
+        assert!(scene.reparent(bed, Some(hall)));
+        let (_, state) = replication.tick(&scene);
+        assert_eq!(state, scene.state());
+        assert_eq!(state[&bed].parent, Some(hall));
+
+        // removing a node takes what is below it along
+        assert!(scene.destroy(hall));
+        let (snapshot, state) = replication.tick(&scene);
+        assert_eq!(snapshot.removed, [bed, hall]);
+        assert_eq!(state.keys().copied().collect::<Vec<_>>(), [room]);
+
+        // nothing changed, nothing is sent but the tick
+        let (snapshot, state) = replication.tick(&scene);
+        assert!(snapshot.changed.is_empty() && snapshot.removed.is_empty());
+        assert_eq!(state, scene.state());
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+
+/// How many episodes an agent remembers before the oldest fade.
+pub const MEMORY_SIZE: usize = 32;
+
+/// Simulated hours after which an episode only counts a third as much.
+pub const MEMORY_FADE: f32 = 24.0;
+
+/// Makes an action something two agents do together.
+#[derive(Debug, Clone, Copy)]
+pub struct Social {
+    /// Change of affinity for both sides once it is done.
+    pub affinity: f32,
+    /// Change of familiarity for both sides once it is done.
+    pub familiarity: f32,
+    /// Neither side goes along with it below this affinity.
+    pub min_affinity: f32,
+    pub min_familiarity: f32,
+}
+
+/// What an agent thinks of another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Relationship {
+    /// From -1 (hostile) to 1 (devoted).
+    pub affinity: f32,
+    /// From 0 (stranger) to 1 (knows them well).
+    pub familiarity: f32,
+    /// Simulated hour of the last interaction.
+    pub last_interaction: f32,
+}
+
+impl Default for Relationship {
+    fn default() -> Self {
+        Self {
+            affinity: 0.0,
+            familiarity: 0.0,
+            last_interaction: f32::NEG_INFINITY,
+        }
+    }
+}
+
+impl Relationship {
+    pub fn allows(&self, social: &Social) -> bool {
+        self.affinity >= social.min_affinity && self.familiarity >= social.min_familiarity
+    }
+
+    pub fn interact(&mut self, social: &Social, now: f32) {
+        self.affinity = (self.affinity + social.affinity).clamp(-1.0, 1.0);
+        self.familiarity = (self.familiarity + social.familiarity).clamp(0.0, 1.0);
+        self.last_interaction = now;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Episode {
+    /// Simulated hour it happened.
+    pub time: f32,
+    /// Name of the action it was about.
+    pub action: String,
+    pub with: Option<u32>,
+    /// How it felt, from -1 to 1.
+    pub valence: f32,
+}
+
+/// The most recent things that happened to an agent.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    episodes: VecDeque<Episode>,
+}
+
+impl Memory {
+    pub fn remember(&mut self, episode: Episode) {
+        if self.episodes.len() == MEMORY_SIZE {
+            self.episodes.pop_front();
+        }
+        self.episodes.push_back(episode);
+    }
+
+    /// How the remembered episodes with `other` feel at `now` when it
+    /// comes to `action`. Newer ones count more, those about the same
+    /// action twice as much.
+    pub fn mood_towards(&self, other: u32, action: &str, now: f32) -> f32 {
+        self.episodes
+            .iter()
+            .filter(|e| e.with == Some(other))
+            .map(|e| {
+                let weight = if e.action == action { 2.0 } else { 1.0 };
+                weight * e.valence * (-(now - e.time).max(0.0) / MEMORY_FADE).exp()
+            })
+            .sum()
+    }
+}
+
+/// What an agent can see of another one while it decides.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub id: u32,
+    pub position: [f32; 3],
+    /// Who it is busy with right now.
+    pub partner: Option<u32>,
+    /// Whether it is where its activity takes place.
+    pub ready: bool,
+}
+
+/// Asks `to` to do the social action at `action` together with `from`.
+#[derive(Debug, Clone, Copy)]
+pub struct Invitation {
+    pub from: u32,
+    pub to: u32,
+    pub action: usize,
+}
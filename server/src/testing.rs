@@ -0,0 +1,137 @@
+use std::{
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use hashbrown::HashMap;
+use package::{Manifest, Sandbox, TestOptions};
+
+use crate::{agent::lua_agent_event, message::ServiceMessage, runtime, world::World};
+
+/// Runs the tests of the package in `dir` against a world of its own and
+/// no clients. The world ticks once per update of the package, which gets
+/// its agent events like on the server. The JSON report goes to `report`
+/// if given. Whether all tests passed.
+pub fn run(
+    dir: &Path,
+    options: &TestOptions,
+    sandbox: bool,
+    report: Option<&Path>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut manifest = Manifest::read(dir)?;
+    if sandbox {
+        manifest.sandbox.get_or_insert_with(Sandbox::default);
+    }
+
+    let world = Arc::new(Mutex::new(World::new()));
+    let served = world.clone();
+    let clients = HashMap::new();
+    let result = package::test::<ServiceMessage, _, _>(
+        &manifest,
+        &runtime::setup,
+        options,
+        move |msg| runtime::serve(&mut served.lock().unwrap(), &clients, msg),
+        |dt| {
+            let mut world = world.lock().unwrap();
+            world.tick(dt.as_secs_f32());
+            // nobody watches the scene
+            world.scene.drain_events();
+            world
+                .drain_events()
+                .into_iter()
+                .map(lua_agent_event)
+                .collect()
+        },
+    );
+
+    println!("{}", result);
+    if let Some(file) = report {
+        std::fs::write(file, result.to_json() + "\n")?;
+    }
+    Ok(result.passed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A package in a directory of its own, without `OnStart` or `OnUpdate`.
+    fn package(test: &str, entry: &str, tests: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("einkrad-testing-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        std::fs::write(
+            dir.join("package.toml"),
+            format!("name = \"{}\"\nversion = \"0.1.0\"\n", test),
+        )
+        .unwrap();
+        std::fs::write(dir.join("index.luau"), entry).unwrap();
+        std::fs::write(dir.join("tests").join("world.luau"), tests).unwrap();
+        dir
+    }
+
+    #[test]
+    fn agent_events_reach_the_package() {
+        let dir = package(
+            "events",
+            r#"
+            decided, critical = {}, {}
+            replaced = {}
+            function OnAgentDecide(agent, action)
+                decided[agent.id] = action
+                if replace and action ~= replace then
+                    replaced[agent.id] = action
+                end
+                return replace
+            end
+            function OnNeedCritical(agent, need)
+                critical[agent.id] = need
+            end
+            "#,
+            r#"
+            return {
+                decide = function()
+                    local agent = Agent.spawn(0, 0, 0)
+                    while not decided[agent.id] do
+                        wait()
+                    end
+                    Test.equal(type(decided[agent.id]), "string")
+                end,
+                override = function()
+                    replace = "nap"
+                    local agent = Agent.spawn(0, 0, 0)
+                    agent:setNeed("Food", 0.2)
+                    while agent:activity() ~= "nap" do
+                        wait()
+                    end
+                    replace = nil
+                    -- what it chose itself was replaced
+                    Test.equal(type(replaced[agent.id]), "string")
+                end,
+                critical = function()
+                    local agent = Agent.spawn(0, 0, 0)
+                    for _, need in { "Food", "Rest", "Acceptance" } do
+                        agent:setNeed(need, 1)
+                    end
+                    agent:setNeed("Food", 0.1501)
+                    while not critical[agent.id] do
+                        wait()
+                    end
+                    Test.equal(critical[agent.id], "Food")
+                end,
+            }
+            "#,
+        );
+        let report = dir.join("report.json");
+
+        let passed = run(&dir, &TestOptions::default(), false, Some(&report)).unwrap();
+        let json = std::fs::read_to_string(&report).unwrap();
+        assert!(passed, "{}", json);
+        assert!(json.contains(r#""passed":3"#), "{}", json);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,581 @@
+use crate::{
+    agent::{
+        innate_actions, social_actions, Action, Agent, AgentEvent, Need, Source, Surroundings,
+        CRITICAL,
+    },
+    navigation::{NavGrid, Obstacle},
+    object::SmartObjects,
+    scene::Scene,
+    social::Peer,
+};
+
+/// Simulated seconds per real second.
+pub const SIM_SPEED: f32 = 60.0;
+
+/// Metres on each side of the navigation grid, centered on the origin.
+pub const WORLD_SIZE: usize = 128;
+
+fn translation(position: &[f32; 3]) -> [f32; 16] {
+    let mut transform = [0.0; 16];
+    common::matrix::identity(&mut transform);
+    transform[12..15].copy_from_slice(position);
+    transform
+}
+
+/// Everything the server simulates.
+pub struct World {
+    /// Simulated hours since the world started.
+    pub time: f32,
+    pub scene: Scene,
+    pub agents: Vec<Agent>,
+    pub actions: Vec<Action>,
+    pub objects: SmartObjects,
+    pub obstacles: Vec<Obstacle>,
+    pub navigation: NavGrid,
+    agent_pool: u32,
+    events: Vec<AgentEvent>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self {
+            time: 0.0,
+            scene: Scene::new(),
+            agents: Vec::new(),
+            actions: innate_actions()
+                .into_iter()
+                .chain(social_actions())
+                .collect(),
+            objects: SmartObjects::new(),
+            obstacles: Vec::new(),
+            navigation: NavGrid::new(
+                [-(WORLD_SIZE as f32) / 2.0, 0.0, -(WORLD_SIZE as f32) / 2.0],
+                1.0,
+                WORLD_SIZE,
+                WORLD_SIZE,
+            ),
+            agent_pool: 1,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn spawn_agent(&mut self, position: [f32; 3]) -> u32 {
+        let id = self.agent_pool;
+        self.agent_pool += 1;
+
+        let node = self.scene.create(None);
+        self.scene.set_transform(node, translation(&position));
+
+        let mut agent = Agent::new(id, position);
+        agent.node = Some(node);
+        self.agents.push(agent);
+
+        id
+    }
+
+    /// Places something agents walk around, with a scene node so clients
+    /// see it. Destroying the node removes the obstacle.
+    pub fn add_obstacle(&mut self, position: [f32; 3], radius: f32) -> u32 {
+        let node = self.scene.create(None);
+        self.scene.set_transform(node, translation(&position));
+
+        self.obstacles.push(Obstacle { node, radius });
+        self.rebuild_navigation();
+        node
+    }
+
+    /// Destroys the node with its descendants, the obstacles among them
+    /// stop blocking.
+    pub fn destroy_node(&mut self, id: u32) -> bool {
+        if !self.scene.destroy(id) {
+            return false;
+        }
+        let count = self.obstacles.len();
+        self.obstacles.retain(|o| self.scene.get(o.node).is_some());
+        if self.obstacles.len() != count {
+            self.rebuild_navigation();
+        }
+        true
+    }
+
+    /// Blocks out the obstacles again, call it after moving one of them.
+    pub fn rebuild_navigation(&mut self) {
+        self.navigation = self.navigation.rebuild(&self.scene, &self.obstacles);
+        for agent in self.agents.iter_mut() {
+            agent.path.clear();
+        }
+    }
+
+    /// Adds the action, or replaces the one with the same name. Agents
+    /// busy with a replaced action drop it, it may no longer be what they
+    /// started, or no longer something to do together.
+    pub fn define_action(&mut self, action: Action) {
+        let Some(i) = self.actions.iter().position(|a| a.name == action.name) else {
+            self.actions.push(action);
+            return;
+        };
+        self.actions[i] = action;
+
+        for agent in self.agents.iter_mut() {
+            let source = agent.activity.as_ref().map(|a| a.source);
+            if let Some(Source::Innate(j) | Source::Social { action: j, .. }) = source {
+                if i == j {
+                    agent.stop(&mut self.objects);
+                }
+            }
+        }
+    }
+
+    /// Places a smart object in the world, with a scene node so clients see it.
+    pub fn spawn_object(
+        &mut self,
+        position: [f32; 3],
+        capacity: usize,
+        actions: Vec<Action>,
+    ) -> u32 {
+        let node = self.scene.create(None);
+        self.scene.set_transform(node, translation(&position));
+
+        self.objects.add(Some(node), position, capacity, actions)
+    }
+
+    /// Removes the object and its node, agents using it stop right away.
+    /// Returns false if there is no such object.
+    pub fn remove_object(&mut self, id: u32) -> bool {
+        let Some(object) = self.objects.remove(id) else {
+            return false;
+        };
+        for agent in self
+            .agents
+            .iter_mut()
+            .filter(|a| object.users().contains(&a.id))
+        {
+            agent.stop(&mut self.objects);
+        }
+        if let Some(node) = object.node {
+            self.destroy_node(node);
+        }
+        true
+    }
+
+    /// Advances the simulation by `seconds` of real time.
+    pub fn tick(&mut self, seconds: f32) {
+        let hours = seconds * SIM_SPEED / 3600.0;
+
+        self.time += hours;
+
+        let peers: Vec<Peer> = self.agents.iter().map(|a| a.peer()).collect();
+        let surroundings = Surroundings {
+            now: self.time,
+            actions: &self.actions,
+            peers: &peers,
+        };
+
+        let mut invitations = Vec::new();
+        for agent in self.agents.iter_mut() {
+            let needs = agent.needs;
+            let source = agent.activity.as_ref().map(|a| a.source);
+
+            invitations.extend(agent.tick(hours, &surroundings, &mut self.objects));
+
+            for need in Need::ALL {
+                if needs.get(need) >= CRITICAL && agent.needs.get(need) < CRITICAL {
+                    self.events.push(AgentEvent::NeedCritical {
+                        agent: agent.id,
+                        need,
+                    });
+                }
+            }
+            let started = agent.activity.as_ref().map(|a| a.source);
+            if started.is_some() && started != source {
+                if let Some(action) = agent.doing(&self.actions, &self.objects) {
+                    self.events.push(AgentEvent::Decided {
+                        agent: agent.id,
+                        action: action.to_string(),
+                    });
+                }
+            }
+        }
+
+        let mut answered = Vec::new();
+        for inv in invitations {
+            // an invitation answered earlier may have changed the plans
+            let Some(from) = self.agents.iter().position(|a| a.id == inv.from) else {
+                continue;
+            };
+            if self.agents[from].partner() != Some(inv.to) || answered.contains(&(inv.to, inv.from))
+            {
+                continue;
+            }
+            answered.push((inv.from, inv.to));
+
+            let accepted = self
+                .agents
+                .iter_mut()
+                .find(|a| a.id == inv.to)
+                .is_some_and(|a| a.invited(&inv, &self.actions, &mut self.objects));
+            if !accepted {
+                self.agents[from].rejected(&inv, &self.actions, self.time, &mut self.objects);
+            }
+        }
+
+        let positions: Vec<[f32; 3]> = self.agents.iter().map(|a| a.position).collect();
+        for agent in self.agents.iter_mut() {
+            let from = agent.position;
+            if !agent.walk(hours, &self.navigation, &positions) {
+                agent.stop(&mut self.objects);
+            }
+            if let Some(node) = agent.node.filter(|_| agent.position != from) {
+                self.scene.set_transform(node, translation(&agent.position));
+            }
+        }
+    }
+
+    pub fn agent_mut(&mut self, id: u32) -> Option<&mut Agent> {
+        self.agents.iter_mut().find(|a| a.id == id)
+    }
+
+    /// Makes the agent drop what it does for the named action.
+    pub fn perform(&mut self, id: u32, action: &str) -> bool {
+        match self.agents.iter_mut().find(|a| a.id == id) {
+            Some(agent) => agent.perform(action, &self.actions, &mut self.objects),
+            None => false,
+        }
+    }
+
+    /// Agent events since the last call.
+    pub fn drain_events(&mut self) -> Vec<AgentEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{ARRIVE_RADIUS, PERCEPTION_RADIUS},
+        social::Episode,
+    };
+
+    /// Real seconds that make one simulated minute.
+    const MINUTE: f32 = 60.0 / SIM_SPEED;
+
+    fn run(world: &mut World, hours: f32) {
+        for _ in 0..(hours * 60.0).round() as usize {
+            world.tick(MINUTE);
+        }
+    }
+
+    fn fridge(world: &mut World, position: [f32; 3]) -> u32 {
+        world.spawn_object(
+            position,
+            1,
+            vec![Action::new("eat", 0.5, &[(Need::Food, 0.6)])],
+        )
+    }
+
+    fn doing(world: &World, agent: u32) -> Option<&str> {
+        let agent = world.agents.iter().find(|a| a.id == agent)?;
+        agent.doing(&world.actions, &world.objects)
+    }
+
+    #[test]
+    fn needs_decay_at_their_own_rate() {
+        let mut world = World::new();
+        // nothing to do, the agent idles
+        world.actions.clear();
+        let id = world.spawn_agent([0.0; 3]);
+
+        run(&mut world, 6.0);
+
+        let needs = world.agent_mut(id).unwrap().needs;
+        for need in Need::ALL {
+            let expected = 1.0 - need.decay() * 6.0;
+            assert!(
+                (needs.get(need) - expected).abs() < 1e-3,
+                "{} is {}, not {}",
+                need.name(),
+                needs.get(need),
+                expected
+            );
+        }
+        assert!((world.time - 6.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn needs_never_leave_their_range() {
+        let mut world = World::new();
+        world.actions.clear();
+        let id = world.spawn_agent([0.0; 3]);
+
+        run(&mut world, 100.0);
+
+        let needs = world.agent_mut(id).unwrap().needs;
+        assert!(Need::ALL.iter().all(|n| needs.get(*n) == 0.0));
+    }
+
+    #[test]
+    fn the_most_pressing_need_is_met_first() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        let agent = world.agent_mut(id).unwrap();
+        agent.needs.set(Need::Rest, 0.2);
+        agent.needs.set(Need::Purpose, 0.6);
+
+        // a nap brings the most rest per hour
+        world.tick(MINUTE);
+        assert_eq!(doing(&world, id), Some("nap"));
+
+        run(&mut world, 4.0);
+        assert!(world.agent_mut(id).unwrap().needs.get(Need::Rest) > 0.5);
+    }
+
+    #[test]
+    fn hungry_agents_use_objects_nearby() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        fridge(&mut world, [5.0, 0.0, 0.0]);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+
+        world.tick(MINUTE);
+        assert_eq!(doing(&world, id), Some("eat"));
+
+        run(&mut world, 1.0);
+        assert!(world.agent_mut(id).unwrap().needs.get(Need::Food) > 0.8);
+    }
+
+    #[test]
+    fn objects_out_of_sight_are_ignored() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        fridge(&mut world, [PERCEPTION_RADIUS + 5.0, 0.0, 0.0]);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+
+        world.tick(MINUTE);
+        assert_ne!(doing(&world, id), Some("eat"));
+    }
+
+    #[test]
+    fn agents_walk_to_objects_before_using_them() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        let position = [20.0, 0.0, 0.0];
+        fridge(&mut world, position);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+
+        let mut walked = 0;
+        loop {
+            world.tick(0.05);
+            let agent = world.agent_mut(id).unwrap();
+            let Some(act) = &agent.activity else {
+                panic!("the agent gave up");
+            };
+            if act.target.is_none() {
+                break;
+            }
+            assert!(agent.needs.get(Need::Food) < 0.3);
+            walked += 1;
+        }
+
+        assert!(walked > 1);
+        assert_eq!(doing(&world, id), Some("eat"));
+        let agent = world.agent_mut(id).unwrap();
+        assert!(common::vector::distance(&agent.position, &position) <= ARRIVE_RADIUS);
+    }
+
+    #[test]
+    fn agents_walk_around_obstacles() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        let obstacle = world.add_obstacle([10.0, 0.0, 0.0], 2.0);
+        fridge(&mut world, [20.0, 0.0, 0.0]);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+
+        let mut detour = false;
+        while doing(&world, id) != Some("eat")
+            || world
+                .agent_mut(id)
+                .unwrap()
+                .activity
+                .as_ref()
+                .unwrap()
+                .target
+                .is_some()
+        {
+            world.tick(0.05);
+            let position = world.agent_mut(id).unwrap().position;
+            assert!(world.navigation.is_walkable(&position), "{:?}", position);
+            detour |= position[2].abs() > 2.0;
+            assert!(world.time < 0.1, "the agent never arrived");
+        }
+        assert!(detour);
+
+        assert!(world.destroy_node(obstacle));
+        assert!(world.obstacles.is_empty());
+        assert!(world.navigation.is_walkable(&[10.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn objects_out_of_reach_are_not_used() {
+        let mut world = World::new();
+        // the grid ends at 64
+        let id = world.spawn_agent([60.0, 0.0, 0.0]);
+        fridge(&mut world, [80.0, 0.0, 0.0]);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+
+        run(&mut world, 1.0);
+        assert!(world.agent_mut(id).unwrap().needs.get(Need::Food) < 0.3);
+    }
+
+    #[test]
+    fn removed_objects_stop_their_users() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        let object = fridge(&mut world, [1.0, 0.0, 0.0]);
+        world.agent_mut(id).unwrap().needs.set(Need::Food, 0.3);
+        world.tick(MINUTE);
+        assert_eq!(world.objects.get(object).unwrap().users(), &[id]);
+        let node = world.objects.get(object).unwrap().node.unwrap();
+
+        assert!(world.remove_object(object));
+        assert!(!world.remove_object(object));
+        assert!(world.agent_mut(id).unwrap().activity.is_none());
+        assert!(world.scene.get(node).is_none());
+    }
+
+    #[test]
+    fn partners_chat_together_once_both_are_there() {
+        let mut world = World::new();
+        let a = world.spawn_agent([0.0; 3]);
+        let b = world.spawn_agent([10.0, 0.0, 0.0]);
+        for id in [a, b] {
+            world.agent_mut(id).unwrap().needs.set(Need::Belonging, 0.2);
+        }
+
+        world.tick(0.05);
+        assert_eq!(doing(&world, a), Some("chat"));
+        assert_eq!(doing(&world, b), Some("chat"));
+        assert_eq!(world.agent_mut(a).unwrap().partner(), Some(b));
+        assert_eq!(world.agent_mut(b).unwrap().partner(), Some(a));
+
+        // nobody gets anything out of it while one of them still walks
+        let chat = world.actions.iter().find(|a| a.name == "chat").unwrap();
+        let duration = chat.duration;
+        while world
+            .agents
+            .iter()
+            .any(|a| a.activity.as_ref().unwrap().target.is_some())
+        {
+            for agent in world.agents.iter() {
+                assert_eq!(agent.activity.as_ref().unwrap().remaining, duration);
+            }
+            world.tick(0.05);
+        }
+
+        while world.agents.iter().any(|a| a.partner().is_some()) {
+            let remaining: Vec<f32> = world
+                .agents
+                .iter()
+                .map(|a| a.activity.as_ref().unwrap().remaining)
+                .collect();
+            assert_eq!(remaining[0], remaining[1]);
+            world.tick(MINUTE);
+        }
+
+        let now = world.time;
+        for (me, other) in [(a, b), (b, a)] {
+            let agent = world.agent_mut(me).unwrap();
+            let r = agent.relationship(other);
+            assert!(r.familiarity > 0.0 && r.affinity > 0.0);
+            assert!(agent.memory.mood_towards(other, "chat", now) > 0.0);
+            assert!(agent.needs.get(Need::Belonging) > 0.2);
+        }
+    }
+
+    #[test]
+    fn agents_approach_whom_they_remember_fondly() {
+        let mut world = World::new();
+        let a = world.spawn_agent([0.0; 3]);
+        let b = world.spawn_agent([5.0, 0.0, 0.0]);
+        let c = world.spawn_agent([-5.0, 0.0, 0.0]);
+        world.agent_mut(a).unwrap().needs.set(Need::Belonging, 0.2);
+
+        let agent = world.agent_mut(a).unwrap();
+        agent.memory.remember(Episode {
+            time: 0.0,
+            action: "chat".into(),
+            with: Some(b),
+            valence: -1.0,
+        });
+        agent.memory.remember(Episode {
+            time: 0.0,
+            action: "chat".into(),
+            with: Some(c),
+            valence: 1.0,
+        });
+
+        world.tick(0.05);
+        assert_eq!(world.agent_mut(a).unwrap().partner(), Some(c));
+    }
+
+    #[test]
+    fn redefined_actions_are_dropped() {
+        let mut world = World::new();
+        let a = world.spawn_agent([0.0; 3]);
+        let b = world.spawn_agent([0.5, 0.0, 0.0]);
+        for id in [a, b] {
+            world.agent_mut(id).unwrap().needs.set(Need::Belonging, 0.2);
+        }
+        world.tick(0.05);
+        assert_eq!(doing(&world, a), Some("chat"));
+
+        // the same name, but nothing to do together any more
+        world.define_action(Action::new("chat", 0.5, &[(Need::Pleasure, 0.1)]));
+        assert_eq!(world.actions.iter().filter(|a| a.name == "chat").count(), 1);
+        assert!(world.agents.iter().all(|a| a.activity.is_none()));
+
+        world.tick(0.05);
+        assert!(world.agents.iter().all(|a| a.partner().is_none()));
+    }
+
+    #[test]
+    fn basic_needs_hold_up_for_days() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        fridge(&mut world, [3.0, 0.0, 3.0]);
+
+        for _ in 0..72 {
+            run(&mut world, 1.0);
+            let needs = world.agent_mut(id).unwrap().needs;
+            assert!(needs.get(Need::Food) >= CRITICAL * 0.5);
+            assert!(needs.get(Need::Rest) >= CRITICAL * 0.5);
+        }
+    }
+
+    #[test]
+    fn critical_needs_interrupt_and_are_reported() {
+        let mut world = World::new();
+        let id = world.spawn_agent([0.0; 3]);
+        fridge(&mut world, [2.0, 0.0, 0.0]);
+        assert!(world.perform(id, "work"));
+        world
+            .agent_mut(id)
+            .unwrap()
+            .needs
+            .set(Need::Food, CRITICAL + 0.001);
+        world.drain_events();
+
+        run(&mut world, 0.25);
+
+        assert_eq!(doing(&world, id), Some("eat"));
+        let events = world.drain_events();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            AgentEvent::NeedCritical { agent, need: Need::Food } if *agent == id
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            AgentEvent::Decided { agent, action } if *agent == id && action == "eat"
+        )));
+    }
+}